@@ -1,13 +1,27 @@
-use once_cell::sync::OnceCell;
+use base64::prelude::*;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use futures_util::TryStreamExt;
+use once_cell::sync::{Lazy, OnceCell};
+use regex::Regex;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use tiberius::{Client, Config};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tiberius::numeric::Numeric;
+use tiberius::{
+    Client, ColumnData, Config, EncryptionLevel, IntoSql, QueryItem, Row, SqlBrowser, ToSql,
+    TokenRow, Uuid,
+};
 use tokio::net::TcpStream;
 use tokio::runtime::Runtime;
+use tokio::sync::{Notify, mpsc};
 use tokio_util::compat::TokioAsyncWriteCompatExt;
 
 type TibClient = Client<tokio_util::compat::Compat<TcpStream>>;
@@ -17,38 +31,524 @@ type TibClient = Client<tokio_util::compat::Compat<TcpStream>>;
 enum ColumnType {
     I64,
     I32,
+    I16,
+    U8,
     Str,
     F64,
+    F32,
     Bool,
+    DateTime,
+    Date,
+    Time,
+    Decimal,
+    Guid,
+    Binary,
     Other,
 }
 
-// Global trace flag
-static TRACE_ENABLED: AtomicBool = AtomicBool::new(false);
+// Trace verbosity: each level includes the ones below it
+const TRACE_OFF: u32 = 0;
+// Errors and warnings
+const TRACE_ERRORS: u32 = 1;
+// Plus the SQL text of each statement sent to the server
+const TRACE_STATEMENTS: u32 = 2;
+// Plus everything else: row counts, pooling, settings changes
+const TRACE_VERBOSE: u32 = 3;
+static TRACE_LEVEL: AtomicU32 = AtomicU32::new(TRACE_OFF);
 
-/// Log a message to stderr when trace mode is enabled
+// Host-supplied trace sink; when set it replaces stderr output
+type TraceCallback = extern "C" fn(*const c_char);
+static TRACE_CALLBACK: OnceCell<Mutex<Option<TraceCallback>>> = OnceCell::new();
+
+fn get_trace_callback() -> &'static Mutex<Option<TraceCallback>> {
+    TRACE_CALLBACK.get_or_init(|| Mutex::new(None))
+}
+
+/// Log a verbose-level trace message
 fn trace(msg: &str) {
-    if TRACE_ENABLED.load(Ordering::Relaxed) {
-        eprintln!("[SQLThinkRS] {}", msg);
+    trace_at(TRACE_VERBOSE, msg);
+}
+
+/// Log a message when the trace level is at least `level`, to the registered
+/// callback or stderr
+fn trace_at(level: u32, msg: &str) {
+    if TRACE_LEVEL.load(Ordering::Relaxed) >= level {
+        let redacted = redact_secrets(msg);
+        let msg = redacted.as_ref();
+        // Copy the pointer out so the callback runs without holding the lock
+        let callback = *get_trace_callback().lock().unwrap_or_else(PoisonError::into_inner);
+        match callback {
+            Some(cb) => {
+                // Interior NULs cannot cross the C boundary; replace them
+                let c_msg = CString::new(msg.replace('\0', " ")).unwrap_or_default();
+                cb(c_msg.as_ptr());
+            }
+            None => eprintln!("[SQLThinkRS] {}", msg),
+        }
     }
 }
 
-/// EnableTrace turns on SQL trace output to stderr.
+// password=..., pwd=... and access token=... in connection strings, plus
+// PASSWORD = '...' clauses in SQL such as CREATE LOGIN
+static SECRET_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\b(password|pwd|access\s*token)(\s*=\s*)(\{(?:[^}]|\}\})*\}|N?'(?:[^']|'')*'|[^;\s,)]*)")
+        .unwrap()
+});
+
+/// Replace secret values in a trace message with ***
+fn redact_secrets(msg: &str) -> Cow<'_, str> {
+    SECRET_RE.replace_all(msg, "${1}${2}***")
+}
+
+/// SetTraceCallback routes trace output to `cb` instead of stderr. The callback
+/// receives a null-terminated UTF-8 message that is only valid for the duration
+/// of the call, and may be invoked from any thread. Pass null to go back to
+/// stderr. Tracing must still be turned on with EnableTrace or SetTraceLevel.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetTraceCallback(cb: Option<TraceCallback>) {
+    *get_trace_callback().lock().unwrap_or_else(PoisonError::into_inner) = cb;
+}
+
+/// EnableTrace turns on SQL trace output (stderr, or the SetTraceCallback sink)
+/// at full verbosity; same as SetTraceLevel(3).
 #[unsafe(no_mangle)]
 pub extern "C" fn EnableTrace() {
-    TRACE_ENABLED.store(true, Ordering::Relaxed);
-    eprintln!("[SQLThinkRS] Trace enabled");
+    SetTraceLevel(TRACE_VERBOSE);
 }
 
-/// DisableTrace turns off SQL trace output.
+/// DisableTrace turns off SQL trace output; same as SetTraceLevel(0).
 #[unsafe(no_mangle)]
 pub extern "C" fn DisableTrace() {
-    eprintln!("[SQLThinkRS] Trace disabled");
-    TRACE_ENABLED.store(false, Ordering::Relaxed);
+    SetTraceLevel(TRACE_OFF);
+}
+
+/// SetTraceLevel sets how much trace output is produced: 0 = off, 1 = errors
+/// and warnings, 2 = also the SQL text of each statement, 3 = everything
+/// (row counts and timings, pooling, settings). Values above 3 are treated as 3.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetTraceLevel(level: u32) {
+    let level = level.min(TRACE_VERBOSE);
+    if level == TRACE_OFF {
+        trace("Trace disabled");
+    }
+    TRACE_LEVEL.store(level, Ordering::Relaxed);
+    trace(&format!("Trace level: {}", level));
+}
+
+// tiberius does not put INFO tokens (PRINT, RAISERROR with severity 10 or
+// less) on the query stream; it only reports each one as an INFO tracing event
+// whose only field is the message, from its token stream module.
+const TIBERIUS_TOKEN_TARGET: &str = "tiberius::tds::stream::token";
+
+// The other INFO events of that module have the same shape: ENVCHANGE notices
+// (USE, SET LANGUAGE, BEGIN TRAN, ...) and the LOGINACK. Their fixed wording
+// tells them apart from server messages.
+static TIBERIUS_NOTICE_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(concat!(
+        r"^(Database change from '.*' to '.*'|Packet size change from '.*' to '.*'",
+        r"|SQL collation change( from .* to .*)?|SQL collation changed to .*",
+        r"|(Begin|Commit|Rollback|Defect) transaction",
+        r"|Server requested routing to a new address: .*|Fallback mirror server: `.*`",
+        r"|Ignored env change: `.*`|Microsoft SQL Server version \d+)$",
+    ))
+    .unwrap()
+});
+
+/// tracing subscriber that only listens to tiberius' INFO message event and
+/// keeps the messages of the statement running on the current thread. It is
+/// only the thread's default while run_statement drives a statement.
+struct InfoMessageCapture;
+
+impl InfoMessageCapture {
+    fn wants(metadata: &tracing::Metadata<'_>) -> bool {
+        metadata.is_event()
+            && *metadata.level() == tracing::Level::INFO
+            && metadata.target() == TIBERIUS_TOKEN_TARGET
+            && metadata.fields().len() == 1
+            && metadata.fields().field("message").is_some()
+    }
+}
+
+impl tracing::Subscriber for InfoMessageCapture {
+    fn register_callsite(
+        &self,
+        metadata: &'static tracing::Metadata<'static>,
+    ) -> tracing::subscriber::Interest {
+        if Self::wants(metadata) {
+            tracing::subscriber::Interest::always()
+        } else {
+            tracing::subscriber::Interest::never()
+        }
+    }
+
+    fn enabled(&self, metadata: &tracing::Metadata<'_>) -> bool {
+        Self::wants(metadata)
+    }
+
+    fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        tracing::span::Id::from_u64(1)
+    }
+
+    fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+    fn event(&self, event: &tracing::Event<'_>) {
+        let mut visitor = MessageField(String::new());
+        event.record(&mut visitor);
+        if TIBERIUS_NOTICE_RE.is_match(&visitor.0) {
+            return;
+        }
+        trace(&format!("Server message: {}", visitor.0));
+        LAST_MESSAGES.with(|messages| messages.borrow_mut().push(visitor.0));
+    }
+
+    fn enter(&self, _: &tracing::span::Id) {}
+
+    fn exit(&self, _: &tracing::span::Id) {}
+}
+
+/// Reads the formatted `message` field of a tracing event
+struct MessageField(String);
+
+impl tracing::field::Visit for MessageField {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+// Kept alive for the life of the process so the tiberius callsite's interest
+// stays registered; the host's own tracing setup is left alone.
+static MESSAGE_CAPTURE: Lazy<tracing::Dispatch> =
+    Lazy::new(|| tracing::Dispatch::new(InfoMessageCapture));
+
+/// GetLastMessages returns the PRINT and informational messages (RAISERROR
+/// with severity 10 or less, "Changed database context", ...) of the most
+/// recent statement on the calling thread, as a JSON array of strings.
+/// A message worded exactly like one of the driver's own notices (e.g.
+/// "Begin transaction") cannot be told apart from it and is left out.
+/// The caller is responsible for freeing the returned string with FreeCString.
+#[unsafe(no_mangle)]
+pub extern "C" fn GetLastMessages() -> *const c_char {
+    let messages = LAST_MESSAGES.with(|messages| messages.borrow().clone());
+    match to_json_string(&messages) {
+        Ok(json) => CString::new(json).map_or(std::ptr::null(), hand_out_c_string),
+        Err(_) => std::ptr::null(),
+    }
+}
+
+// Emit DECIMAL/NUMERIC values as JSON strings instead of numbers, for callers
+// whose JSON parser reads every number as a double.
+static DECIMAL_AS_STRING: AtomicBool = AtomicBool::new(false);
+
+/// SetDecimalAsString controls how DECIMAL/NUMERIC columns are serialized.
+/// When enabled, values are emitted as exact strings (e.g. "1234567.89");
+/// when disabled (the default) they are emitted as JSON numbers with all of
+/// their digits, which a parser that reads numbers as doubles will round.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetDecimalAsString(enabled: bool) {
+    DECIMAL_AS_STRING.store(enabled, Ordering::Relaxed);
+    trace(&format!("Decimal as string: {}", enabled));
+}
+
+// Emit NaN/±Infinity FLOAT/REAL values as strings instead of null
+static NON_FINITE_AS_STRING: AtomicBool = AtomicBool::new(false);
+
+/// SetNonFiniteAsString controls how NaN and infinite FLOAT/REAL values, which
+/// JSON numbers cannot represent, are serialized. When enabled they are emitted
+/// as the strings "NaN", "Infinity" and "-Infinity"; when disabled (the
+/// default) they are emitted as null and a warning is traced.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetNonFiniteAsString(enabled: bool) {
+    NON_FINITE_AS_STRING.store(enabled, Ordering::Relaxed);
+    trace(&format!("Non-finite floats as string: {}", enabled));
+}
+
+// Emit VARBINARY/BINARY/IMAGE values as "0x..." hex strings instead of base64.
+static BINARY_AS_HEX: AtomicBool = AtomicBool::new(false);
+
+/// SetBinaryAsHex controls how binary columns are serialized.
+/// When enabled, values are emitted as SQL-style hex literals (e.g. "0xDEADBEEF");
+/// when disabled (the default) they are emitted as standard base64 strings.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetBinaryAsHex(enabled: bool) {
+    BINARY_AS_HEX.store(enabled, Ordering::Relaxed);
+    trace(&format!("Binary as hex: {}", enabled));
+}
+
+// How DATETIME/DATE values are serialized (SetDateFormat)
+const DATE_FORMAT_ISO8601: u32 = 0;
+const DATE_FORMAT_EPOCH_MILLIS: u32 = 1;
+const DATE_FORMAT_PATTERN: u32 = 2;
+static DATE_FORMAT_MODE: AtomicU32 = AtomicU32::new(DATE_FORMAT_ISO8601);
+// strftime pattern used in DATE_FORMAT_PATTERN mode
+static DATE_FORMAT_STRFTIME: Lazy<Mutex<String>> = Lazy::new(|| Mutex::new(String::new()));
+
+/// SetDateFormat selects how DATETIME, DATETIME2, SMALLDATETIME and DATE
+/// columns are serialized: "iso8601" (the default, e.g. "2024-01-15T13:45:30"),
+/// "epoch_millis" (integer milliseconds since 1970-01-01 UTC), or any chrono
+/// strftime pattern such as "%Y-%m-%d %H:%M:%S". DATE values are formatted as
+/// midnight of that day. TIME columns are unaffected.
+/// Returns null on success, or a C error string for an invalid pattern.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn SetDateFormat(format: *const c_char) -> *const c_char {
+    let format = match unsafe { c_str_arg(format, "date format") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    let mode = match format.to_lowercase().as_str() {
+        "iso8601" | "iso" => DATE_FORMAT_ISO8601,
+        "epoch_millis" | "epoch" => DATE_FORMAT_EPOCH_MILLIS,
+        _ => {
+            if let Err(e) = check_date_pattern(format) {
+                return create_error_string(&format!("ERROR: {}", e));
+            }
+            *lock_or_recover(&DATE_FORMAT_STRFTIME) = format.to_string();
+            DATE_FORMAT_PATTERN
+        }
+    };
+    DATE_FORMAT_MODE.store(mode, Ordering::Relaxed);
+    trace(&format!("Date format: {}", format));
+    std::ptr::null()
+}
+
+/// Check that a strftime pattern parses and can format a column value.
+/// Values have no time zone, so a pattern that parses but needs one (%z, %Z)
+/// is rejected here rather than failing for every row.
+fn check_date_pattern(format: &str) -> Result<(), String> {
+    use std::fmt::Write;
+    let invalid = format.is_empty()
+        || chrono::format::StrftimeItems::new(format)
+            .any(|item| matches!(item, chrono::format::Item::Error));
+    let naive = NaiveDate::from_ymd_opt(2024, 1, 15)
+        .and_then(|date| date.and_hms_milli_opt(13, 45, 30, 123))
+        .unwrap_or_default();
+    let with_offset = naive.and_utc().fixed_offset();
+    if invalid
+        || write!(String::new(), "{}", naive.format(format)).is_err()
+        || write!(String::new(), "{}", with_offset.format(format)).is_err()
+    {
+        return Err(format!("Invalid date format '{}'", format));
+    }
+    Ok(())
+}
+
+// Indent JSON results for readability instead of emitting them on one line.
+static PRETTY_JSON: AtomicBool = AtomicBool::new(false);
+
+/// SetPrettyJson controls JSON result formatting.
+/// When enabled, results are indented across multiple lines, which is handy
+/// when debugging; when disabled (the default) they are compact.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetPrettyJson(enabled: bool) {
+    PRETTY_JSON.store(enabled, Ordering::Relaxed);
+    trace(&format!("Pretty JSON: {}", enabled));
+}
+
+// Per-statement timeout in seconds for ExecuteSql* calls (0 = no timeout).
+static QUERY_TIMEOUT_SECS: AtomicU32 = AtomicU32::new(0);
+
+// Error returned when a statement exceeds the query timeout
+const QUERY_TIMED_OUT: &str = "query timed out";
+
+/// SetQueryTimeout sets the default timeout for ExecuteSql, ExecuteSqlParams and
+/// ExecuteSqlOnHandle. A statement that runs longer returns "ERROR: query timed out"
+/// and its connection is closed, so ConnectDb/ConnectDbHandle must be called again.
+/// Zero (the default) disables the timeout.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetQueryTimeout(seconds: u32) {
+    QUERY_TIMEOUT_SECS.store(seconds, Ordering::Relaxed);
+    trace(&format!("Query timeout: {}s", seconds));
+}
+
+// Error returned when a statement is aborted by CancelQuery
+const QUERY_CANCELLED: &str = "query cancelled";
+
+// Error returned when tiberius panics on a response it cannot decode. SQL_VARIANT
+// columns are the known case: its metadata decoder has no support for them.
+const UNDECODABLE_RESPONSE: &str = "the server sent a value the TDS driver cannot decode \
+(SQL_VARIANT columns are not supported; CAST them to a concrete type such as NVARCHAR(4000))";
+
+// Wakes every statement currently running in run_statement, and BeginQuery streams
+static CANCEL_NOTIFY: OnceCell<Notify> = OnceCell::new();
+
+fn get_cancel_notify() -> &'static Notify {
+    CANCEL_NOTIFY.get_or_init(Notify::new)
+}
+
+/// CancelQuery aborts the statements currently executing on other threads
+/// (ExecuteSql, ExecuteSqlParams, ExecuteSqlOnHandle) and any open BeginQuery
+/// stream; they return "ERROR: query cancelled". Does nothing if no statement
+/// is running.
+/// The server is not sent an attention signal, so the interrupted connection
+/// is closed and ConnectDb/ConnectDbHandle must be called again.
+#[unsafe(no_mangle)]
+pub extern "C" fn CancelQuery() {
+    trace("Cancel requested");
+    get_cancel_notify().notify_waiters();
+}
+
+// Retry policy for transient server errors (SetRetryPolicy); 1 attempt = no retries
+static RETRY_MAX_ATTEMPTS: AtomicU32 = AtomicU32::new(1);
+static RETRY_BASE_DELAY_MS: AtomicU32 = AtomicU32::new(0);
+
+// Deadlock victim, lock resource exhaustion, and Azure SQL throttling / busy errors
+const TRANSIENT_ERROR_NUMBERS: &[u32] = &[1204, 1205, 10928, 10929, 40501, 40613];
+
+/// SetRetryPolicy retries ExecuteSql, ExecuteSqlParams, ExecuteSqlMulti and
+/// ExecuteSqlOnHandle statements that fail with a transient server error
+/// (1204, 1205, 10928, 10929, 40501, 40613), up to `max_attempts` attempts in
+/// total, waiting `base_delay_ms` and doubling the wait after each failure.
+/// Statements run inside an explicit transaction are never retried, since the
+/// error has already rolled that transaction back. While enabled each statement
+/// costs one extra round-trip to read @@TRANCOUNT. `max_attempts` of 0 or 1
+/// (the default) disables retries.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetRetryPolicy(max_attempts: u32, base_delay_ms: u32) {
+    RETRY_MAX_ATTEMPTS.store(max_attempts, Ordering::Relaxed);
+    RETRY_BASE_DELAY_MS.store(base_delay_ms, Ordering::Relaxed);
+    trace(&format!("Retry policy: {} attempts, {}ms base delay", max_attempts, base_delay_ms));
+}
+
+// Return failures as JSON objects instead of "ERROR: ..." strings (SetErrorFormat)
+static ERRORS_AS_JSON: AtomicBool = AtomicBool::new(false);
+
+/// SetErrorFormat selects how failures are returned to the caller:
+/// 0 (default) returns "ERROR: <message>" strings; 1 returns JSON such as
+/// `{"error":true,"message":"...","sqlErrorNumber":208,"severity":16,"state":1}`,
+/// where the SQL fields are null for errors that did not come from the server.
+/// Other values are ignored.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetErrorFormat(format: u32) {
+    match format {
+        0 | 1 => {
+            ERRORS_AS_JSON.store(format == 1, Ordering::Relaxed);
+            trace(&format!("Error format: {}", if format == 1 { "json" } else { "text" }));
+        }
+        _ => trace(&format!("Ignoring unknown error format {}", format)),
+    }
+}
+
+// Shape of single-result-set SELECT output (SetResultFormat)
+const RESULT_FORMAT_OBJECTS: u32 = 0;
+const RESULT_FORMAT_ENVELOPE: u32 = 1;
+const RESULT_FORMAT_COLUMNAR: u32 = 2;
+static RESULT_FORMAT: AtomicU32 = AtomicU32::new(RESULT_FORMAT_OBJECTS);
+
+/// SetResultFormat selects the JSON shape returned for SELECT results by
+/// ExecuteSql, ExecuteSqlParams and ExecuteSqlOnHandle:
+/// 0 (default) returns an array of row objects (`[{"id":1,"name":"a"}]`);
+/// 1 wraps them with column metadata taken from the server and the
+/// statement's PRINT messages (see GetLastMessages)
+/// (`{"columns":[{"name":"id","type":"Int4"}],"rows":[{"id":1}],"messages":[]}`);
+/// 2 returns the column names once and each row as an array, which is much
+/// smaller for wide or long results (`{"columns":["id"],"rows":[[1]]}`).
+/// Formats 1 and 2 list the columns even when no rows are returned.
+/// Other values are ignored.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetResultFormat(format: u32) {
+    match format {
+        RESULT_FORMAT_OBJECTS | RESULT_FORMAT_ENVELOPE | RESULT_FORMAT_COLUMNAR => {
+            RESULT_FORMAT.store(format, Ordering::Relaxed);
+            trace(&format!("Result format: {}", format));
+        }
+        _ => trace(&format!("Ignoring unknown result format {}", format)),
+    }
+}
+
+// Inject an ID primary key into CREATE TABLE statements that lack one
+static AUTO_PRIMARY_KEY: AtomicBool = AtomicBool::new(true);
+
+/// SetAutoPrimaryKey controls the CREATE TABLE rewrite in ExecuteSql.
+/// When enabled (the default) a table without a PRIMARY KEY gets an
+/// `ID INT PRIMARY KEY IDENTITY(1,1)` column; when disabled DDL is sent untouched.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetAutoPrimaryKey(enabled: bool) {
+    AUTO_PRIMARY_KEY.store(enabled, Ordering::Relaxed);
+    trace(&format!("Auto primary key: {}", enabled));
+}
+
+// Let concurrent ExecuteSql calls use separate pooled connections
+static CONCURRENT_EXECUTE: AtomicBool = AtomicBool::new(false);
+
+/// SetConcurrentExecute lets ExecuteSql calls from several threads run in
+/// parallel. When enabled and the global connection is busy on another
+/// thread, the call checks out its own connection from the pool (same
+/// connection string), runs on it and returns it, instead of waiting.
+/// Such calls do not see the global connection's session state (open
+/// transactions, temp tables, SET options), so keep it disabled (the default)
+/// when that matters; use ConnectDbHandle for full control.
+/// Has no effect when pooling is disabled for the global connection.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetConcurrentExecute(enabled: bool) {
+    CONCURRENT_EXECUTE.store(enabled, Ordering::Relaxed);
+    trace(&format!("Concurrent execute: {}", enabled));
+}
+
+// Reject ';'-separated batches in the single-statement entry points
+static STRICT_SINGLE_STATEMENT: AtomicBool = AtomicBool::new(false);
+
+/// SetStrictSingleStatement controls whether ExecuteSql, ExecuteSqlOnHandle,
+/// ExecuteSqlParams, ExecuteSqlNamedParams, ExecuteSqlCsv,
+/// ExecuteSqlReadUncommitted, ExecuteSqlFull and ExecuteCount reject SQL
+/// containing a `;` statement separator outside comments, string literals and
+/// quoted identifiers (e.g. `SELECT 1; DROP TABLE x`). Trailing semicolons are allowed.
+/// Disabled by default; use ExecuteSqlMulti for intentional batches.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetStrictSingleStatement(enabled: bool) {
+    STRICT_SINGLE_STATEMENT.store(enabled, Ordering::Relaxed);
+    trace(&format!("Strict single statement: {}", enabled));
+}
+
+// Fail ConnectDb on connection string keys parse_connection_string does not know
+static STRICT_CONN_STR: AtomicBool = AtomicBool::new(false);
+
+/// SetStrictConnStr controls how unrecognized connection string keys (e.g. a
+/// misspelled `databse=mydb`) are handled. When enabled, connecting fails with
+/// an error listing them; otherwise (the default) each one is ignored with a
+/// trace warning.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetStrictConnStr(enabled: bool) {
+    STRICT_CONN_STR.store(enabled, Ordering::Relaxed);
+    trace(&format!("Strict connection string: {}", enabled));
 }
 
-// Global Tokio runtime for async operations
-static RUNTIME: OnceCell<Runtime> = OnceCell::new();
+// Global Tokio runtime for async operations, created on first use and
+// released by Shutdown
+static RUNTIME: Mutex<Option<Arc<Runtime>>> = Mutex::new(None);
+
+// Worker threads for the runtime; 0 = one per CPU core (Tokio's default)
+static RUNTIME_THREADS: AtomicU32 = AtomicU32::new(0);
+
+/// SetRuntimeThreads sets the number of worker threads of the internal Tokio
+/// runtime: 1 runs everything on the calling threads (a current-thread
+/// runtime), 0 (the default) uses one worker per CPU core. The runtime is
+/// created by the first database call, so this must be called before that
+/// (or after Shutdown). With 1 thread, background work such as a BeginQuery
+/// stream only advances while a library call is running. Returns null on
+/// success, or an error string if the runtime already exists.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetRuntimeThreads(threads: u32) -> *const c_char {
+    let runtime = lock_or_recover(&RUNTIME);
+    if runtime.is_some() {
+        return create_error_string(
+            "ERROR: SetRuntimeThreads must be called before the first database call (or after Shutdown)",
+        );
+    }
+    RUNTIME_THREADS.store(threads, Ordering::Relaxed);
+    trace(&format!("Runtime threads: {}", threads));
+    record_success();
+    std::ptr::null()
+}
+
+// Whether SELECTs on the global connection run inside a transaction batch
+// (see ConnOptions::wrap_selects); updated by ConnectDb
+static GLOBAL_WRAP_SELECTS: AtomicBool = AtomicBool::new(true);
 
 // Global active database client
 static DB_CLIENT: OnceCell<Arc<Mutex<Option<TibClient>>>> = OnceCell::new();
@@ -57,17 +557,140 @@ static DB_CLIENT: OnceCell<Arc<Mutex<Option<TibClient>>>> = OnceCell::new();
 // When DisconnectDb is called the client is returned here instead of being
 // dropped.  ConnectDb checks the pool first and reuses an existing client
 // if one is available (similar to ADO.NET connection pooling).
-static CONN_POOL: OnceCell<Mutex<HashMap<String, Vec<TibClient>>>> = OnceCell::new();
+static CONN_POOL: OnceCell<Mutex<HashMap<String, Vec<PooledClient>>>> = OnceCell::new();
+
+// An idle client plus the time it was returned, for idle-timeout eviction
+struct PooledClient {
+    client: TibClient,
+    idle_since: Instant,
+    // Session already reset when it was returned (fast pool validation)
+    reset: bool,
+}
+
+// Idle connections older than this are closed instead of reused (0 = keep forever)
+static POOL_IDLE_TIMEOUT_SECS: AtomicU32 = AtomicU32::new(0);
+
+/// SetPoolIdleTimeout closes pooled connections that have been idle longer than
+/// `seconds` instead of reusing them. Eviction happens lazily when ConnectDb or
+/// ConnectDbHandle checks the pool. Zero (the default) keeps idle connections
+/// indefinitely.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetPoolIdleTimeout(seconds: u32) {
+    POOL_IDLE_TIMEOUT_SECS.store(seconds, Ordering::Relaxed);
+    trace(&format!("Pool idle timeout: {}s", seconds));
+}
+
+// Freshness window for fast pool validation in seconds (0 = disabled)
+static FAST_POOL_VALIDATION_SECS: AtomicU32 = AtomicU32::new(0);
+
+/// SetFastPoolValidation trades the round trip on every pool checkout for one
+/// on return. When `seconds` is non-zero, connections are reset as they go back
+/// into the pool, and a connection returned less than `seconds` ago is handed
+/// out without contacting the server. Older ones are reset (which also proves
+/// they are alive) on checkout as usual. A connection that died while idle
+/// surfaces on its first statement, where ExecuteSql reconnects.
+/// Zero (the default) validates every checkout.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetFastPoolValidation(seconds: u32) {
+    FAST_POOL_VALIDATION_SECS.store(seconds, Ordering::Relaxed);
+    trace(&format!("Fast pool validation window: {}s", seconds));
+}
+
+// Extra statement run to validate a pooled connection on checkout (None = the
+// session reset alone)
+static POOL_VALIDATION_QUERY: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+/// SetPoolValidationQuery sets a statement run on every pooled connection
+/// after its session reset at checkout, such as `SELECT 1` or a
+/// database-specific check. Any error marks the connection stale, and a new
+/// one is opened instead. Null or an empty string goes back to the default,
+/// where the reset batch alone proves the connection alive. Not run on
+/// connections handed out by SetFastPoolValidation without a round trip.
+/// Returns null on success, or a C error string for invalid UTF-8.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn SetPoolValidationQuery(sql: *const c_char) -> *const c_char {
+    let query = if sql.is_null() {
+        None
+    } else {
+        match unsafe { c_str_arg(sql, "validation query") } {
+            Ok(s) if s.trim().is_empty() => None,
+            Ok(s) => Some(s.to_string()),
+            Err(e) => return e,
+        }
+    };
+    trace(&format!(
+        "Pool validation query: {}",
+        query.as_deref().unwrap_or("(session reset only)")
+    ));
+    *lock_or_recover(&POOL_VALIDATION_QUERY) = query;
+    record_success();
+    std::ptr::null()
+}
 
 // Stores the connection string used by the current active connection so that
 // DisconnectDb can return the client to the correct pool bucket.
 static CONN_KEY: OnceCell<Mutex<Option<String>>> = OnceCell::new();
 
 // Keep pool growth bounded per connection string to avoid unbounded idle-client
-// accumulation while preserving reuse wins.
-const MAX_IDLE_PER_CONN_STR: usize = 8;
+// accumulation while preserving reuse wins. The default matches ADO.NET's
+// Max Pool Size; adjustable with SetMaxPoolSize.
+const DEFAULT_MAX_IDLE_PER_CONN_STR: usize = 100;
+static MAX_IDLE_PER_CONN_STR: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_IDLE_PER_CONN_STR);
+
+/// SetMaxPoolSize sets how many idle connections are kept per connection string.
+/// Connections returned to a full bucket are closed instead of pooled; 0 disables
+/// pooling. Buckets already above a lowered limit shrink as connections are reused.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetMaxPoolSize(size: u32) {
+    MAX_IDLE_PER_CONN_STR.store(size as usize, Ordering::Relaxed);
+    trace(&format!("Max pool size: {}", size));
+}
+
+// Default bound on connect + login when the connection string has no
+// `connect timeout` key (same default as ADO.NET)
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 15;
+
+// TCP keepalive idle time and probe interval when `tcp keepalive=true` is
+// given without a `keepalive interval`
+const DEFAULT_KEEPALIVE_INTERVAL_SECS: u64 = 30;
+
+// Standard ADO.NET connection string keys that have no equivalent here.
+// They are accepted so a pasted connection string works unchanged.
+const IGNORED_CONN_KEYS: &[&str] = &[
+    "persist security info",
+    "persistsecurityinfo",
+    "workstation id",
+    "wsid",
+    "min pool size",
+    "max pool size",
+    "connection lifetime",
+    "load balance timeout",
+    "enlist",
+    "multisubnetfailover",
+    "multi subnet failover",
+    "connectretrycount",
+    "connect retry count",
+    "connectretryinterval",
+    "connect retry interval",
+    "current language",
+    "language",
+    "failover partner",
+];
 
-fn get_pool() -> &'static Mutex<HashMap<String, Vec<TibClient>>> {
+// Valid range of the `packet size` connection string key, as in ADO.NET
+const MIN_PACKET_SIZE: u32 = 512;
+const MAX_PACKET_SIZE: u32 = 32767;
+// TDS packet size tiberius requests at login (fixed by the driver)
+const TDS_PACKET_SIZE: u32 = 4096;
+
+// Application name reported at login when the connection string has no
+// `application name` key
+const DEFAULT_APPLICATION_NAME: &str = "thinksqlrs";
+
+fn get_pool() -> &'static Mutex<HashMap<String, Vec<PooledClient>>> {
     CONN_POOL.get_or_init(|| Mutex::new(HashMap::new()))
 }
 
@@ -75,21 +698,141 @@ fn get_conn_key() -> &'static Mutex<Option<String>> {
     CONN_KEY.get_or_init(|| Mutex::new(None))
 }
 
+// Handle-based connections (ConnectDbHandle): each handle owns its own client
+// slot so several connections can be used concurrently, alongside the single
+// DB_CLIENT used by ConnectDb/ExecuteSql.
+struct HandleConn {
+    client: Arc<Mutex<Option<TibClient>>>,
+    // Connection string, used as the pool bucket on DisconnectDbHandle
+    // (None when pooling is disabled)
+    conn_key: Option<String>,
+    // Run SELECTs inside BEGIN/COMMIT TRANSACTION (see ConnOptions::wrap_selects)
+    wrap_selects: bool,
+}
+
+static HANDLES: OnceCell<Mutex<HashMap<u64, HandleConn>>> = OnceCell::new();
+
+// 0 is reserved as the failure value of ConnectDbHandle and BeginQuery
+static NEXT_HANDLE: AtomicU64 = AtomicU64::new(1);
+
+fn get_handles() -> &'static Mutex<HashMap<u64, HandleConn>> {
+    HANDLES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// A row streamed by BeginQuery (None once the stream has ended cleanly), or
+// the error (with its SQL details) that ended the stream. A channel closed
+// without either means the producer died.
+type StreamItem = Result<Option<Row>, (String, Option<SqlErrorInfo>)>;
+
+// Open streaming queries started by BeginQuery, keyed by handle
+static CURSORS: OnceCell<Mutex<HashMap<u64, mpsc::Receiver<StreamItem>>>> = OnceCell::new();
+
+// Rows read ahead of FetchRows before the server is back-pressured
+const STREAM_BUFFER_ROWS: usize = 1024;
+
+// Handle of the BeginQuery stream holding the global connection (0 = none)
+static GLOBAL_STREAM: AtomicU64 = AtomicU64::new(0);
+
+// Error returned while a BeginQuery stream holds the global connection
+const CONNECTION_BUSY: &str = "connection busy: query stream open";
+
+/// Why the global connection slot is empty: a BeginQuery stream has the
+/// connection, or there is none.
+fn empty_slot_error() -> &'static str {
+    if GLOBAL_STREAM.load(Ordering::Relaxed) != 0 {
+        CONNECTION_BUSY
+    } else {
+        "Database not connected. Call ConnectDb first."
+    }
+}
+
+fn get_cursors() -> &'static Mutex<HashMap<u64, mpsc::Receiver<StreamItem>>> {
+    CURSORS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// Last error message per calling thread, exposed via GetLastErrorMessage.
+// (Deliberately not named GetLastError to avoid clashing with the Win32 API.)
+thread_local! {
+    static LAST_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+    // Server error details captured by sql_error, attached to the next recorded error
+    static PENDING_SQL_ERROR: Cell<Option<SqlErrorInfo>> = const { Cell::new(None) };
+    // SQL error number of the last call: 0 = success, -1 = client-side failure
+    static LAST_ERROR_CODE: Cell<i32> = const { Cell::new(0) };
+    // Byte length of the last string handed to the caller (0 after a null return)
+    static LAST_RESULT_LENGTH: Cell<usize> = const { Cell::new(0) };
+    // Set by sql_error when the failure was an I/O error on the connection
+    static CONNECTION_LOST: Cell<bool> = const { Cell::new(false) };
+    // PRINT / informational messages of the last statement (see GetLastMessages)
+    static LAST_MESSAGES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Error number, severity (class) and state reported by SQL Server
+#[derive(Debug, Clone, Copy)]
+struct SqlErrorInfo {
+    number: u32,
+    severity: u8,
+    state: u8,
+}
+
+/// Lock a mutex, recovering it if a previous holder panicked so one failure
+/// does not brick the library for the rest of the process.
+fn lock_or_recover<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        trace_at(TRACE_ERRORS, "WARNING: recovering lock poisoned by a panic");
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// Lock a client slot. If a previous holder panicked the client may be
+/// mid-statement, so it is dropped and the slot reads as not connected.
+fn lock_client(slot: &Mutex<Option<TibClient>>) -> MutexGuard<'_, Option<TibClient>> {
+    slot.lock().unwrap_or_else(|poisoned| recover_client(slot, poisoned))
+}
+
+fn recover_client<'a>(
+    slot: &Mutex<Option<TibClient>>,
+    poisoned: PoisonError<MutexGuard<'a, Option<TibClient>>>,
+) -> MutexGuard<'a, Option<TibClient>> {
+    trace_at(TRACE_ERRORS, "WARNING: dropping connection whose lock was poisoned by a panic");
+    slot.clear_poison();
+    let mut guard = poisoned.into_inner();
+    *guard = None;
+    guard
+}
+
 /// Get or initialize the global Tokio runtime
-fn get_runtime() -> &'static Runtime {
-    RUNTIME.get_or_init(|| Runtime::new().expect("Failed to create Tokio runtime"))
+fn get_runtime() -> Arc<Runtime> {
+    let mut runtime = lock_or_recover(&RUNTIME);
+    Arc::clone(runtime.get_or_insert_with(|| Arc::new(build_runtime().expect("Failed to create Tokio runtime"))))
+}
+
+/// Build the runtime with the thread count chosen by SetRuntimeThreads.
+fn build_runtime() -> std::io::Result<Runtime> {
+    let mut builder = match RUNTIME_THREADS.load(Ordering::Relaxed) {
+        0 => tokio::runtime::Builder::new_multi_thread(),
+        1 => tokio::runtime::Builder::new_current_thread(),
+        threads => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            builder.worker_threads(threads as usize);
+            builder
+        }
+    };
+    builder.enable_all().build()
 }
 
 /// ConnectDb establishes a connection to the SQL Server database.
 /// Takes a connection string and returns a C string with an error message if it fails.
 /// Returns null pointer on success. The caller is responsible for freeing the error string.
+/// A connection that is already active is released as by DisconnectDb once the
+/// new one is established; on failure it stays active.
 ///
 /// Connection string format: "server=localhost;user id=sa;password=your_password;database=your_db"
 ///
 /// # Safety
 /// This function is unsafe because it dereferences a raw pointer from C.
 #[unsafe(no_mangle)]
-pub extern "C" fn ConnectDb(conn_str: *const c_char) -> *const c_char {
+pub unsafe extern "C" fn ConnectDb(conn_str: *const c_char) -> *const c_char {
     if conn_str.is_null() {
         return create_error_string("ERROR: Connection string is null");
     }
@@ -101,453 +844,6519 @@ pub extern "C" fn ConnectDb(conn_str: *const c_char) -> *const c_char {
     };
 
     // Parse connection string
-    let config = match parse_connection_string(conn_string) {
-        Ok(cfg) => cfg,
+    let options = match parse_connection_string(conn_string) {
+        Ok(opts) => opts,
         Err(e) => return create_error_string(&format!("ERROR: Failed to parse connection string: {}", e)),
     };
 
     // Initialize the global client storage
     let client_storage = DB_CLIENT.get_or_init(|| Arc::new(Mutex::new(None)));
+    let pooling = options.pooling;
+    let wrap_selects = options.wrap_selects();
 
-    // Try to grab a pooled connection first (avoids TCP + TDS handshake)
-    let runtime = get_runtime();
-    let pooled = {
-        let mut pool = get_pool().lock().unwrap();
-        pool.get_mut(conn_string).and_then(|v| v.pop())
-    };
-
-    let result = if let Some(client) = pooled {
-        // Reuse pooled connection directly without ping validation.
-        // If stale, the first query will fail and the user can reconnect.
-        trace("Pool HIT - reusing pooled connection");
-        Ok(client)
-    } else {
-        trace("Pool MISS - opening new connection");
-        runtime.block_on(open_new_connection_async(config))
-    };
-
-    match result {
+    match checkout_connection(conn_string, options) {
         Ok(client) => {
-            let mut db = client_storage.lock().unwrap();
-            *db = Some(client);
-            // Remember which pool bucket to return to
-            let mut key = get_conn_key().lock().unwrap();
-            *key = Some(conn_string.to_string());
+            let previous = {
+                let mut db = lock_client(client_storage);
+                let previous_client = db.replace(client);
+                // Remember which pool bucket to return to (none when pooling is off)
+                let mut key = lock_or_recover(get_conn_key());
+                let previous_key = std::mem::replace(&mut *key, pooling.then(|| conn_string.to_string()));
+                GLOBAL_WRAP_SELECTS.store(wrap_selects, Ordering::Relaxed);
+                reset_transaction_state();
+                let prepared = lock_or_recover(&GLOBAL_PREPARED).take_handles();
+                previous_client.map(|c| (c, previous_key, prepared))
+            };
+            // A ConnectDb without DisconnectDb replaces the active connection;
+            // release the old one as DisconnectDb would instead of leaking it
+            if let Some((previous_client, previous_key, prepared)) = previous {
+                trace("Replacing active connection - releasing the previous one");
+                if let Some(previous_key) = previous_key {
+                    release_to_pool(previous_key, previous_client, &prepared);
+                }
+            }
+            record_success();
             std::ptr::null() // Success
         }
         Err(e) => create_error_string(&format!("ERROR: {}", e)),
     }
 }
 
-/// Open a brand-new TCP + TDS connection and set snapshot isolation.
-async fn open_new_connection_async(
-    config: Config,
-) -> Result<TibClient, String> {
-    let tcp = TcpStream::connect(config.get_addr())
-        .await
-        .map_err(|e| format!("Failed to connect to server: {}", e))?;
+/// Take an idle client from the pool bucket for this connection string, or
+/// open a new one when the bucket is empty.
+fn checkout_connection(conn_string: &str, options: ConnOptions) -> Result<TibClient, String> {
+    trace(&format!("Connect: {}", conn_string));
+    if !options.pooling {
+        trace("Pooling disabled - opening new connection");
+        return get_runtime().block_on(open_new_connection_async(options));
+    }
 
-    tcp.set_nodelay(true).ok();
+    // Try to grab a pooled connection first (avoids TCP + TDS handshake)
+    let (pooled, expired) = {
+        let mut pool = lock_or_recover(get_pool());
+        match pool.get_mut(conn_string) {
+            Some(bucket) => {
+                let expired = evict_expired(bucket);
+                (bucket.pop(), expired)
+            }
+            None => (None, Vec::new()),
+        }
+    };
+    // Expired clients are closed here, after the pool lock is released
+    if !expired.is_empty() {
+        trace(&format!("Pool evicted {} idle connections", expired.len()));
+    }
+    drop(expired);
 
-    let mut client = Client::connect(config, tcp.compat_write())
-        .await
-        .map_err(|e| format!("Failed to connect to database: {}", e))?;
+    if let Some(PooledClient { mut client, idle_since, reset }) = pooled {
+        let window = FAST_POOL_VALIDATION_SECS.load(Ordering::Relaxed) as u64;
+        if reset && idle_since.elapsed() < Duration::from_secs(window) {
+            trace("Pool HIT - reset on return and still fresh, skipping validation");
+            return Ok(client);
+        }
+        // Reset the session so state left by the previous user (open
+        // transactions, temp tables, SET options) does not leak. A failed
+        // reset also means the connection is stale, so replace it.
+        trace("Pool HIT - resetting pooled connection");
+        let validation = lock_or_recover(&POOL_VALIDATION_QUERY).clone();
+        let checked = get_runtime().block_on(async {
+            reset_session(&mut client, &options).await?;
+            match &validation {
+                Some(query) => validate_pooled(&mut client, query).await,
+                None => Ok(()),
+            }
+        });
+        match checked {
+            Ok(()) => return Ok(client),
+            Err(e) => {
+                PENDING_SQL_ERROR.with(|pending| pending.set(None));
+                trace(&format!("Pooled connection reset failed ({}) - opening new connection", e));
+            }
+        }
+    } else {
+        trace("Pool MISS - opening new connection");
+    }
+    get_runtime().block_on(open_new_connection_async(options))
+}
 
-    // Set snapshot isolation level once at connection time via simple_query.
-    // IMPORTANT: Must NOT use client.execute() here because that wraps in
-    // sp_executesql, and SET TRANSACTION ISOLATION LEVEL inside sp_executesql
-    // is scoped to that procedure — it does NOT persist to the session.
-    trace("EXEC: SET TRANSACTION ISOLATION LEVEL SNAPSHOT");
+/// Run the SetPoolValidationQuery statement on a pooled connection, reading
+/// whatever it returns.
+async fn validate_pooled(client: &mut TibClient, query: &str) -> Result<(), String> {
+    trace_at(TRACE_STATEMENTS, &format!("EXEC (pool validation): {}", query));
     client
-        .simple_query("SET TRANSACTION ISOLATION LEVEL SNAPSHOT")
+        .simple_query(query)
         .await
-        .map_err(|e| format!("Failed to set snapshot isolation: {}", e))?
+        .map_err(|e| sql_error("Pool validation query failed", e))?
         .into_results()
         .await
-        .map_err(|e| format!("Failed to set snapshot isolation: {}", e))?;
+        .map_err(|e| sql_error("Pool validation query failed", e))?;
+    Ok(())
+}
 
-    trace("Connected successfully");
-    Ok(client)
+/// Remove and return the clients in a bucket that exceeded the idle timeout.
+/// Buckets are filled in return order, so expired entries are at the front.
+fn evict_expired(bucket: &mut Vec<PooledClient>) -> Vec<PooledClient> {
+    let secs = POOL_IDLE_TIMEOUT_SECS.load(Ordering::Relaxed);
+    if secs == 0 {
+        return Vec::new();
+    }
+    let limit = Duration::from_secs(secs as u64);
+    let stale = bucket
+        .iter()
+        .take_while(|p| p.idle_since.elapsed() > limit)
+        .count();
+    bucket.drain(..stale).collect()
 }
 
-/// DisconnectDb returns the connection to the pool for reuse.
-/// The underlying TCP connection stays open so the next ConnectDb with the
-/// same connection string can skip the full handshake.
-///
-/// # Safety
-/// This function is safe to call from C.
-#[unsafe(no_mangle)]
-pub extern "C" fn DisconnectDb() {
-    if let Some(client_storage) = DB_CLIENT.get() {
-        let mut db = client_storage.lock().unwrap();
-        if let Some(client) = db.take() {
-            // Return to pool keyed by connection string
-            let key = {
-                let mut k = get_conn_key().lock().unwrap();
-                k.take()
-            };
-            if let Some(key) = key {
-                trace("Returning connection to pool");
-                let mut pool = get_pool().lock().unwrap();
-                let bucket = pool.entry(key).or_default();
-                if bucket.len() < MAX_IDLE_PER_CONN_STR {
-                    bucket.push(client);
-                } else {
-                    trace("Pool bucket full - dropping idle connection");
-                }
-            }
-            // else: no key stored — just drop
-        }
+/// Return a client to the pool bucket for its connection string, dropping it
+/// when the bucket is already full. A transaction the caller left open is
+/// rolled back first; if that fails the connection is closed instead.
+fn return_to_pool(key: String, mut client: TibClient) {
+    // With fast pool validation the full session reset happens now, so a quick
+    // checkout can skip it; otherwise only an open transaction is rolled back
+    let reset_options = match FAST_POOL_VALIDATION_SECS.load(Ordering::Relaxed) {
+        0 => None,
+        _ => parse_connection_string(&key).ok(),
+    };
+    let cleanup = match &reset_options {
+        Some(options) => get_runtime().block_on(reset_session(&mut client, options)),
+        None => get_runtime().block_on(rollback_open_transaction(&mut client)),
+    };
+    if let Err(e) = cleanup {
+        // Not reported to the caller, so do not leave the code pending
+        PENDING_SQL_ERROR.with(|pending| pending.set(None));
+        trace(&format!("{} - dropping connection instead of pooling it", e));
+        return;
+    }
+    trace("Returning connection to pool");
+    let mut pool = lock_or_recover(get_pool());
+    let bucket = pool.entry(key).or_default();
+    if bucket.len() < MAX_IDLE_PER_CONN_STR.load(Ordering::Relaxed) {
+        bucket.push(PooledClient {
+            client,
+            idle_since: Instant::now(),
+            reset: reset_options.is_some(),
+        });
+    } else {
+        trace("Pool bucket full - dropping idle connection");
     }
 }
 
-/// BeginTransaction starts an explicit transaction on the active connection.
-/// Returns null on success, or a C error string on failure.
-/// Use this before a batch of INSERT/UPDATE/DELETE statements to avoid
-/// per-statement auto-commit overhead (log flush per row).
-#[unsafe(no_mangle)]
-pub extern "C" fn BeginTransaction() -> *const c_char {
-    let client_storage = match DB_CLIENT.get() {
-        Some(cs) => cs,
-        None => return create_error_string("ERROR: Database not connected."),
-    };
-    let runtime = get_runtime();
-    let result = runtime.block_on(async {
-        let mut db_guard = client_storage.lock().unwrap();
-        let client = match db_guard.as_mut() {
-            Some(c) => c,
-            None => return Err("Database not connected.".to_string()),
-        };
-        trace("EXEC: BEGIN TRANSACTION");
-        client
-            .simple_query("BEGIN TRANSACTION")
-            .await
-            .map_err(|e| format!("Failed to begin transaction: {}", e))?
-            .into_results()
-            .await
-            .map_err(|e| format!("Failed to begin transaction: {}", e))?;
-        Ok(())
-    });
-    match result {
-        Ok(()) => std::ptr::null(),
-        Err(e) => create_error_string(&format!("ERROR: {}", e)),
+/// Return the global connection's client to the pool, first unpreparing the
+/// statements its prepared-statement cache held
+fn release_to_pool(key: String, mut client: TibClient, prepared: &[i32]) {
+    if !prepared.is_empty() {
+        get_runtime().block_on(unprepare_statements(&mut client, prepared));
     }
+    return_to_pool(key, client);
 }
 
-/// CommitTransaction commits the active explicit transaction.
-/// Returns null on success, or a C error string on failure.
-#[unsafe(no_mangle)]
-pub extern "C" fn CommitTransaction() -> *const c_char {
-    let client_storage = match DB_CLIENT.get() {
-        Some(cs) => cs,
-        None => return create_error_string("ERROR: Database not connected."),
-    };
-    let runtime = get_runtime();
-    let result = runtime.block_on(async {
-        let mut db_guard = client_storage.lock().unwrap();
-        let client = match db_guard.as_mut() {
-            Some(c) => c,
-            None => return Err("Database not connected.".to_string()),
-        };
-        trace("EXEC: COMMIT TRANSACTION");
+/// Roll back a transaction left open on a connection about to be pooled, so
+/// the next caller does not inherit its locks.
+async fn rollback_open_transaction(client: &mut TibClient) -> Result<(), String> {
+    let open = transaction_count(client).await?;
+    if open > 0 {
+        trace(&format!("Rolling back {} open transaction(s) before pooling", open));
         client
-            .simple_query("COMMIT TRANSACTION")
+            .simple_query("ROLLBACK TRANSACTION")
             .await
-            .map_err(|e| format!("Failed to commit transaction: {}", e))?
+            .map_err(|e| sql_error("Failed to roll back open transaction", e))?
             .into_results()
             .await
-            .map_err(|e| format!("Failed to commit transaction: {}", e))?;
-        Ok(())
-    });
-    match result {
-        Ok(()) => std::ptr::null(),
-        Err(e) => create_error_string(&format!("ERROR: {}", e)),
+            .map_err(|e| sql_error("Failed to roll back open transaction", e))?;
     }
+    Ok(())
 }
 
-/// ExecuteSql processes and executes a SQL statement.
-/// Takes a C string as input, processes it, executes it on the connected DB,
-/// and returns a C string with the results (JSON for SELECT) or error message.
-/// The caller is RESPONSIBLE for freeing the returned C string using FreeCString.
-///
-/// # Safety
-/// This function is unsafe because it dereferences a raw pointer from C.
-#[unsafe(no_mangle)]
-pub extern "C" fn ExecuteSql(input_sql: *const c_char) -> *const c_char {
-    if input_sql.is_null() {
-        return create_error_string("ERROR: SQL input is null");
-    }
-
-    let c_str = unsafe { CStr::from_ptr(input_sql) };
-    let sql = match c_str.to_str() {
-        Ok(s) => s,
-        Err(_) => return create_error_string("ERROR: Invalid UTF-8 in SQL string"),
-    };
-
-    // Check if database is connected
-    let client_storage = match DB_CLIENT.get() {
-        Some(cs) => cs,
-        None => return create_error_string("ERROR: Database not connected. Call ConnectDb first."),
+/// Open a brand-new TCP + TDS connection and set the session isolation level.
+async fn open_new_connection_async(
+    options: ConnOptions,
+) -> Result<TibClient, String> {
+    let isolation_levels = options.isolation_candidates();
+    let connect = connect_and_login(options.config, options.tcp_nodelay, options.tcp_keepalive);
+    let mut client = match options.connect_timeout {
+        Some(limit) => tokio::time::timeout(limit, connect)
+            .await
+            .map_err(|_| format!("connection timed out after {}s", limit.as_secs()))??,
+        None => connect.await?,
     };
 
-    let trimmed_upper_sql = sql.trim().to_uppercase();
-    let is_select = trimmed_upper_sql.starts_with("SELECT")
-        || trimmed_upper_sql.starts_with("WITH ")
-        || (trimmed_upper_sql.starts_with("DECLARE")
-            && trimmed_upper_sql.contains("SELECT"));
-
-    // Process the SQL statement - only CREATE TABLE needs transformation
-    let processed_sql = if trimmed_upper_sql.starts_with("CREATE TABLE") {
-        process_create_table(sql)
-    } else {
-        sql.to_string()
-    };
+    apply_isolation(&mut client, &isolation_levels, options.isolation_setup).await?;
+    trace("Connected successfully");
+    Ok(client)
+}
 
-    trace(&format!("Input SQL:  {}", sql.trim()));
-    if processed_sql != sql {
-        trace(&format!("Processed:  {}", processed_sql.trim()));
+/// Apply the configured isolation level to the session. With several
+/// candidates (an `isolation fallback` chain) each is tried in order and the
+/// first one the database accepts is kept.
+async fn apply_isolation(
+    client: &mut TibClient,
+    levels: &[IsolationLevel],
+    setup: IsolationSetup,
+) -> Result<(), String> {
+    // Set the isolation level once at connection time via simple_query.
+    // IMPORTANT: Must NOT use client.execute() here because that wraps in
+    // sp_executesql, and SET TRANSACTION ISOLATION LEVEL inside sp_executesql
+    // is scoped to that procedure — it does NOT persist to the session.
+    if setup == IsolationSetup::Skip {
+        trace("Skipping isolation level setup");
+        return Ok(());
     }
-    trace(&format!("Is SELECT:  {}", is_select));
-
-    // Execute the SQL
-    let runtime = get_runtime();
-    let result = runtime.block_on(async {
-        let mut db_guard = client_storage.lock().unwrap();
-        let client = match db_guard.as_mut() {
-            Some(c) => c,
-            None => return Err("Database not connected. Call ConnectDb first.".to_string()),
+    let mut failures = Vec::new();
+    for &level in levels {
+        // SET ... SNAPSHOT succeeds even where snapshot isolation is not
+        // allowed (reads fail later), so a chain checks the database first
+        let set_isolation = match level {
+            IsolationLevel::Snapshot if levels.len() > 1 => CHECKED_SNAPSHOT_SQL,
+            _ => level.set_statement(),
         };
-
-        if is_select {
-            execute_select_query(client, &processed_sql).await
-        } else {
-            execute_non_select(client, &processed_sql).await
-        }
-    });
-
-    match result {
-        Ok(Some(json)) => {
-            // Return JSON results
-            match CString::new(json) {
-                Ok(c_string) => c_string.into_raw(),
-                Err(_) => create_error_string("ERROR: Failed to create C string from JSON"),
+        trace_at(TRACE_STATEMENTS, &format!("EXEC: {}", set_isolation));
+        let result = match client.simple_query(set_isolation).await {
+            Ok(stream) => stream.into_results().await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
+                if levels.len() > 1 {
+                    trace(&format!("Isolation fallback: using {}", level.name()));
+                }
+                return Ok(());
+            }
+            Err(e) => {
+                if levels.len() > 1 {
+                    trace(&format!("Isolation fallback: {} rejected ({})", level.name(), e));
+                }
+                failures.push((level, e.to_string()));
             }
         }
-        Ok(None) => std::ptr::null(), // Success for non-SELECT
-        Err(e) => create_error_string(&format!("ERROR: {}", e)),
     }
+    let msg = match failures.as_slice() {
+        [(level, e)] => format!("Failed to set {} isolation: {}", level.name(), e),
+        _ => format!(
+            "Failed to set any fallback isolation level: {}",
+            failures
+                .iter()
+                .map(|(level, e)| format!("{} ({})", level.name(), e))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    if setup == IsolationSetup::Required {
+        return Err(msg);
+    }
+    trace_at(TRACE_ERRORS, &format!("WARNING: {} (continuing with server default)", msg));
+    Ok(())
 }
 
-/// FreeCString frees the memory for a C string allocated by Rust.
-/// This MUST be called by the client code for any returned strings.
-///
-/// # Safety
-/// This function is unsafe because it reconstructs a CString from a raw pointer.
-/// The pointer must have been created by CString::into_raw() and must not be null.
-#[unsafe(no_mangle)]
-pub extern "C" fn FreeCString(s: *mut c_char) {
-    if !s.is_null() {
-        unsafe {
-            let _ = CString::from_raw(s); // Reclaim and drop
+/// Snapshot SET used in an isolation fallback chain: raises an error instead
+/// when the current database does not have ALLOW_SNAPSHOT_ISOLATION on.
+const CHECKED_SNAPSHOT_SQL: &str = "\
+IF (SELECT snapshot_isolation_state FROM sys.databases WHERE database_id = DB_ID()) = 1
+    SET TRANSACTION ISOLATION LEVEL SNAPSHOT
+ELSE
+    RAISERROR(N'Snapshot isolation is not allowed in this database', 16, 1)";
+
+/// Batch that clears session state left behind by the previous user of a
+/// pooled connection. sp_reset_connection is only reachable through the RPC
+/// reset flag, so the equivalent work is done here in T-SQL: roll back any
+/// open transaction, drop local temp tables, and put the SET options and
+/// CONTEXT_INFO back to what a new connection starts with (the ODBC defaults
+/// the server applies at login, and the login's default language).
+const RESET_SESSION_SQL: &str = "\
+IF @@TRANCOUNT > 0 ROLLBACK TRANSACTION;
+DECLARE @__drop NVARCHAR(MAX) = N'';
+SELECT @__drop += N'DROP TABLE ' + QUOTENAME(LEFT(name, CHARINDEX(N'___', name) - 1)) + N';'
+FROM tempdb.sys.tables
+WHERE name LIKE N'#%' AND name NOT LIKE N'##%' AND CHARINDEX(N'___', name) > 0
+  AND object_id = OBJECT_ID(N'tempdb..' + QUOTENAME(LEFT(name, CHARINDEX(N'___', name) - 1)));
+IF @__drop <> N'' EXEC (@__drop);
+SET IMPLICIT_TRANSACTIONS OFF;
+SET XACT_ABORT OFF;
+SET NOCOUNT OFF;
+SET LOCK_TIMEOUT -1;
+SET DEADLOCK_PRIORITY NORMAL;
+SET ROWCOUNT 0;
+SET TEXTSIZE 2147483647;
+SET ANSI_NULLS ON;
+SET ANSI_NULL_DFLT_ON ON;
+SET ANSI_PADDING ON;
+SET ANSI_WARNINGS ON;
+SET CONCAT_NULL_YIELDS_NULL ON;
+SET QUOTED_IDENTIFIER ON;
+SET ARITHABORT OFF;
+SET NUMERIC_ROUNDABORT OFF;
+SET CURSOR_CLOSE_ON_COMMIT OFF;
+SET CONTEXT_INFO 0x;
+DECLARE @__language SYSNAME = COALESCE(
+    (SELECT default_language_name FROM sys.server_principals WHERE sid = SUSER_SID()),
+    (SELECT name FROM sys.syslanguages WHERE langid = CONVERT(INT, (
+        SELECT value_in_use FROM sys.configurations WHERE name = N'default language'))),
+    N'us_english');
+DECLARE @__dateformat NCHAR(3), @__datefirst TINYINT;
+SELECT @__dateformat = dateformat, @__datefirst = datefirst
+FROM sys.syslanguages WHERE name = @__language OR alias = @__language;
+SET LANGUAGE @__language;
+IF @__dateformat IS NOT NULL
+BEGIN
+    SET DATEFORMAT @__dateformat;
+    SET DATEFIRST @__datefirst;
+END;";
+
+/// Reset a pooled connection before handing it to a new caller.
+async fn reset_session(client: &mut TibClient, options: &ConnOptions) -> Result<(), String> {
+    trace_at(TRACE_STATEMENTS, "EXEC: session reset batch");
+    // Switch back to the connection string's database in case UseDatabase
+    // moved the previous user elsewhere
+    let batch = match &options.database {
+        Some(database) => Cow::Owned(format!("{}\n{};", RESET_SESSION_SQL, use_database_statement(database))),
+        None => Cow::Borrowed(RESET_SESSION_SQL),
+    };
+    client
+        .simple_query(batch)
+        .await
+        .map_err(|e| sql_error("Failed to reset pooled connection", e))?
+        .into_results()
+        .await
+        .map_err(|e| sql_error("Failed to reset pooled connection", e))?;
+    apply_isolation(client, &options.isolation_candidates(), options.isolation_setup).await
+}
+
+/// TCP connect followed by the TDS prelogin/login handshake
+async fn connect_and_login(
+    mut config: Config,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+) -> Result<TibClient, String> {
+    let mut redirected = false;
+    loop {
+        let tcp = open_socket(&config, nodelay, keepalive).await?;
+        match Client::connect(config.clone(), tcp.compat_write()).await {
+            // An availability group listener sends read-only intent
+            // connections on to a readable secondary; follow it once
+            Err(tiberius::error::Error::Routing { host, port }) if !redirected => {
+                trace(&format!("Server routed the connection to {}:{}", host, port));
+                config.host(host);
+                config.port(port);
+                redirected = true;
+            }
+            result => return result.map_err(|e| sql_error("Failed to connect to database", e)),
         }
     }
 }
 
-// Helper function to create error strings
-fn create_error_string(msg: &str) -> *const c_char {
-    match CString::new(msg) {
-        Ok(c_string) => c_string.into_raw(),
-        Err(_) => std::ptr::null(),
+/// Connect the TCP socket for a login and apply the socket options.
+async fn open_socket(
+    config: &Config,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+) -> Result<TcpStream, String> {
+    // Recorded before connecting so a failed attempt can be diagnosed too
+    let endpoint = config.get_addr();
+    trace(&format!("Connecting to {}", endpoint));
+    *lock_or_recover(&RESOLVED_ENDPOINT) = Some(endpoint);
+
+    // connect_named resolves a named instance through SQL Browser and
+    // connects directly to host:port otherwise
+    let tcp = TcpStream::connect_named(config)
+        .await
+        .map_err(|e| sql_error("Failed to connect to server", e))?;
+    if let Ok(peer) = tcp.peer_addr() {
+        trace(&format!("Connected to {}", peer));
     }
-}
 
-// Parse connection string into tiberius Config
-fn parse_connection_string(conn_str: &str) -> Result<Config, String> {
-    let mut config = Config::new();
-    let mut user: Option<String> = None;
-    let mut password: Option<String> = None;
-    
-    for part in conn_str.split(';') {
-        let part = part.trim();
-        if part.is_empty() {
-            continue;
+    tcp.set_nodelay(nodelay).ok();
+    if let Some(interval) = keepalive {
+        // Keeps idle pooled connections from being dropped silently by
+        // firewalls and NAT devices
+        let params = socket2::TcpKeepalive::new().with_time(interval).with_interval(interval);
+        if let Err(e) = socket2::SockRef::from(&tcp).set_tcp_keepalive(&params) {
+            trace_at(TRACE_ERRORS, &format!("WARNING: failed to enable TCP keepalive: {}", e));
         }
+    }
+    Ok(tcp)
+}
 
-        let key_value: Vec<&str> = part.splitn(2, '=').collect();
-        if key_value.len() != 2 {
-            continue;
-        }
+// host:port of the most recent new connection attempt (GetResolvedEndpoint)
+static RESOLVED_ENDPOINT: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
 
-        let key = key_value[0].trim().to_lowercase();
-        let value = key_value[1].trim();
+/// GetResolvedEndpoint returns the `host:port` the most recent new connection
+/// (ConnectDb or ConnectDbHandle missing the pool, or a reconnect) was opened
+/// to, as parsed from the connection string, e.g. "db01:1433" for
+/// `server=db01,1433`. It is recorded before connecting, so it is available
+/// after a failed attempt too. For a named instance it is the SQL Browser
+/// address (port 1434) the instance's port is looked up from; an availability
+/// group redirect updates it.
+/// Returns null if no connection has been attempted. The caller is
+/// responsible for freeing the returned string with FreeCString.
+#[unsafe(no_mangle)]
+pub extern "C" fn GetResolvedEndpoint() -> *const c_char {
+    match lock_or_recover(&RESOLVED_ENDPOINT).clone() {
+        Some(endpoint) => CString::new(endpoint).map_or(std::ptr::null(), hand_out_c_string),
+        None => std::ptr::null(),
+    }
+}
 
-        match key.as_str() {
-            "server" | "host" => config.host(value),
-            "port" => {
-                if let Ok(port) = value.parse::<u16>() {
-                    config.port(port);
-                }
-            }
-            "user id" | "uid" | "user" => user = Some(value.to_string()),
-            "password" | "pwd" => {
-                password = Some(value.to_string());
-            }
-            "database" | "initial catalog" => config.database(value),
-            "trust server certificate" => {
-                if value.eq_ignore_ascii_case("true") || value == "1" {
-                    config.trust_cert();
-                }
+/// DisconnectDb returns the connection to the pool for reuse.
+/// The underlying TCP connection stays open so the next ConnectDb with the
+/// same connection string can skip the full handshake.
+///
+/// # Safety
+/// This function is safe to call from C.
+#[unsafe(no_mangle)]
+pub extern "C" fn DisconnectDb() {
+    if let Some(client_storage) = DB_CLIENT.get() {
+        let mut db = lock_client(client_storage);
+        // Forgotten even while a BeginQuery stream holds the client, so the
+        // stream does not hand it back afterwards
+        let key = lock_or_recover(get_conn_key()).take();
+        GLOBAL_STREAM.store(0, Ordering::Relaxed);
+        let prepared = lock_or_recover(&GLOBAL_PREPARED).take_handles();
+        if let Some(client) = db.take() {
+            // Return to pool keyed by connection string
+            if let Some(key) = key {
+                release_to_pool(key, client, &prepared);
             }
-            _ => {}
+            // else: no key stored — just drop
         }
     }
+    reset_transaction_state();
+}
 
-    if let Some(user) = user {
-        config.authentication(tiberius::AuthMethod::sql_server(
-            user,
-            password.unwrap_or_default(),
+/// DisconnectDbClose closes the connection instead of pooling it, and also
+/// closes the idle pooled connections for the same connection string, so no
+/// session is left on the server (e.g. before dropping the database). An open
+/// transaction is rolled back by the server.
+#[unsafe(no_mangle)]
+pub extern "C" fn DisconnectDbClose() {
+    if let Some(client_storage) = DB_CLIENT.get() {
+        let mut slot = lock_client(client_storage);
+        let client = slot.take();
+        let key = lock_or_recover(get_conn_key()).take();
+        GLOBAL_STREAM.store(0, Ordering::Relaxed);
+        lock_or_recover(&GLOBAL_PREPARED).take_handles();
+        drop(slot);
+        let drained = key.and_then(|key| lock_or_recover(get_pool()).remove(&key));
+        trace(&format!(
+            "Closed connection ({} idle pooled connections for it)",
+            drained.map_or(0, |bucket| bucket.len())
         ));
+        drop(client);
     }
+    reset_transaction_state();
+}
 
-    Ok(config)
+/// ClearPool closes every idle pooled connection, e.g. after a server restart
+/// or failover. Connections currently in use are unaffected.
+#[unsafe(no_mangle)]
+pub extern "C" fn ClearPool() {
+    // Take the clients out under the lock, close them after releasing it
+    let drained: Vec<PooledClient> = {
+        let mut pool = lock_or_recover(get_pool());
+        pool.drain().flat_map(|(_, bucket)| bucket).collect()
+    };
+    trace(&format!("Cleared pool ({} idle connections)", drained.len()));
 }
 
-/// Process CREATE TABLE to inject primary key if not present
-fn process_create_table(sql: &str) -> String {
-    let upper_sql = sql.to_uppercase();
-    if upper_sql.contains("PRIMARY KEY") {
-        return sql.to_string();
+/// Shutdown tears the library down so a host can unload or re-initialize it:
+/// the active connection, every handle and streaming query, and all pooled
+/// connections are closed (open transactions are rolled back by the server),
+/// then the async runtime is stopped. Any later call starts from scratch and
+/// needs a fresh ConnectDb or ConnectDbHandle.
+#[unsafe(no_mangle)]
+pub extern "C" fn Shutdown() {
+    lock_or_recover(get_cursors()).clear();
+    if let Some(client_storage) = DB_CLIENT.get() {
+        drop(lock_client(client_storage).take());
     }
-
-    // Find the first opening parenthesis
-    if let Some(first_paren_index) = sql.find('(') {
-        let primary_key_column = "ID INT PRIMARY KEY IDENTITY(1,1), ";
-        let mut processed_sql = String::with_capacity(sql.len() + primary_key_column.len());
-        processed_sql.push_str(&sql[..=first_paren_index]);
-        processed_sql.push_str(primary_key_column);
-        processed_sql.push_str(&sql[first_paren_index + 1..]);
-        return processed_sql;
+    lock_or_recover(get_conn_key()).take();
+    GLOBAL_STREAM.store(0, Ordering::Relaxed);
+    lock_or_recover(&GLOBAL_PREPARED).take_handles();
+    reset_transaction_state();
+    let handles: Vec<HandleConn> = lock_or_recover(get_handles()).drain().map(|(_, h)| h).collect();
+    for handle in handles {
+        // Waits for any in-flight call on the handle to finish
+        drop(lock_client(&handle.client).take());
     }
+    ClearPool();
 
-    sql.to_string()
+    // Clients are closed above while the runtime that drives them is alive.
+    // Tasks still running (e.g. a stream being drained) are abandoned.
+    let runtime = lock_or_recover(&RUNTIME).take();
+    if let Some(runtime) = runtime.and_then(|rt| Arc::try_unwrap(rt).ok()) {
+        runtime.shutdown_background();
+    }
+    trace("Shutdown complete");
 }
 
-/// Process SELECT - snapshot isolation is now set at connection time,
-/// so this just returns the SQL as-is (no extra round-trip needed).
-#[allow(dead_code)]
-fn process_select(sql: &str) -> String {
-    sql.to_string()
+/// ClearPoolFor closes the idle pooled connections for one connection string.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ClearPoolFor(conn_str: *const c_char) {
+    let conn_string = match unsafe { c_str_arg(conn_str, "connection string") } {
+        Ok(s) => s,
+        Err(e) => return free_error_string(e),
+    };
+    let drained = lock_or_recover(get_pool()).remove(conn_string);
+    let count = drained.map_or(0, |bucket| bucket.len());
+    trace(&format!("Cleared pool bucket ({} idle connections)", count));
 }
 
-/// Execute SELECT query and return JSON results.
-/// Sends BEGIN TRANSACTION + SELECT + COMMIT TRANSACTION as a **single batch**
-/// via simple_query, so snapshot isolation is honoured with only ONE round-trip
-/// instead of three.  The result sets are iterated to find the one containing rows.
-async fn execute_select_query(
-    client: &mut Client<tokio_util::compat::Compat<TcpStream>>,
-    sql: &str,
-) -> Result<Option<String>, String> {
-    // Build a single-batch string: BEGIN TRAN; SELECT …; COMMIT TRAN
-    let batch = format!("BEGIN TRANSACTION; {} ; COMMIT TRANSACTION", sql.trim());
-    trace(&format!("EXEC (batch): {}", batch));
+/// GetPoolStats returns a JSON snapshot of the idle connection pool:
+/// `{"buckets":2,"idle":5,"keys":[...],"perBucket":[{"key":"...","idle":3},...]}`.
+/// Keys are the pooled connection strings with passwords and tokens masked.
+/// The caller is responsible for freeing the returned string with FreeCString.
+#[unsafe(no_mangle)]
+pub extern "C" fn GetPoolStats() -> *const c_char {
+    let mut per_bucket: Vec<(String, usize)> = {
+        let pool = lock_or_recover(get_pool());
+        pool.iter()
+            .map(|(key, bucket)| (redact_connection_string(key), bucket.len()))
+            .collect()
+    };
+    per_bucket.sort();
 
-    let stream = client
-        .simple_query(&batch)
-        .await
-        .map_err(|e| format!("Query execution failed: {}", e))?;
+    let stats = serde_json::json!({
+        "buckets": per_bucket.len(),
+        "idle": per_bucket.iter().map(|(_, idle)| idle).sum::<usize>(),
+        "keys": per_bucket.iter().map(|(key, _)| key).collect::<Vec<_>>(),
+        "perBucket": per_bucket
+            .iter()
+            .map(|(key, idle)| serde_json::json!({ "key": key, "idle": idle }))
+            .collect::<Vec<_>>(),
+    });
+    sql_result_to_c_string(to_json_string(&stats).map(Some))
+}
 
-    // simple_query can return multiple result sets (one per statement).
-    // The SELECT results will be in the set that actually contains rows.
-    let result_sets = stream
-        .into_results()
-        .await
-        .map_err(|e| format!("Failed to fetch results: {}", e))?;
+// Rebuild a connection string for display with password values masked
+fn redact_connection_string(conn_str: &str) -> String {
+    match split_connection_string(conn_str) {
+        Ok(pairs) => pairs
+            .into_iter()
+            .map(|(key, value)| match key.as_str() {
+                "password" | "pwd" | "access token" | "accesstoken" => format!("{}=***", key),
+                _ if value.contains(';') => format!("{}={{{}}}", key, value.replace('}', "}}")),
+                _ => format!("{}={}", key, value),
+            })
+            .collect::<Vec<_>>()
+            .join(";"),
+        Err(_) => "<unparseable>".to_string(),
+    }
+}
 
-    // Find the first non-empty result set (the SELECT output)
-    let rows = result_sets
-        .into_iter()
-        .find(|rs| !rs.is_empty())
-        .unwrap_or_default();
+// BeginTransaction nesting depth on the global connection. Only the outermost
+// level is a real transaction; inner levels are savepoints.
+static TRANSACTION_DEPTH: AtomicU32 = AtomicU32::new(0);
 
-    let num_rows = rows.len();
-    trace(&format!("SELECT returned {} rows", num_rows));
+// Whether the last @@TRANCOUNT read on the global connection showed an open
+// transaction, including one begun with plain SQL (ExecuteSql("BEGIN TRAN"))
+static SESSION_TRANSACTION_OPEN: AtomicBool = AtomicBool::new(false);
 
-    // Pre-allocate with exact capacity and cache column names
-    let mut results: Vec<serde_json::Map<String, Value>> = Vec::with_capacity(num_rows);
-    let column_names: Vec<String> = rows
-        .first()
-        .map(|r| r.columns().iter().map(|c| c.name().to_owned()).collect())
-        .unwrap_or_default();
+/// Forget the transaction state when the global connection is replaced or closed
+fn reset_transaction_state() {
+    TRANSACTION_DEPTH.store(0, Ordering::Relaxed);
+    SESSION_TRANSACTION_OPEN.store(false, Ordering::Relaxed);
+}
 
-    // Detect column types from first row to avoid repeated type checks
-    let column_types: Vec<ColumnType> = if let Some(first_row) = rows.first() {
-        (0..column_names.len())
-            .map(|i| detect_column_type(first_row, i))
-            .collect()
-    } else {
-        Vec::new()
-    };
+/// Whether the global session is known to be inside a transaction
+fn in_global_transaction() -> bool {
+    TRANSACTION_DEPTH.load(Ordering::Relaxed) > 0 || SESSION_TRANSACTION_OPEN.load(Ordering::Relaxed)
+}
 
-    // Build row maps using cached column names and types
-    for row in &rows {
-        let mut row_map = serde_json::Map::with_capacity(column_names.len());
-        for (i, name) in column_names.iter().enumerate() {
-            let value = match column_types.get(i) {
-                Some(ColumnType::I64) => row.try_get::<i64, _>(i).ok().flatten().map(|v| Value::Number(v.into())),
-                Some(ColumnType::I32) => row.try_get::<i32, _>(i).ok().flatten().map(|v| Value::Number(v.into())),
-                Some(ColumnType::Str) => row.try_get::<&str, _>(i).ok().flatten().map(|v| Value::String(v.to_string())),
-                Some(ColumnType::F64) => row.try_get::<f64, _>(i).ok().flatten().and_then(|v| serde_json::Number::from_f64(v).map(Value::Number)),
-                Some(ColumnType::Bool) => row.try_get::<bool, _>(i).ok().flatten().map(Value::Bool),
-                _ => None,
-            };
-            row_map.insert(name.clone(), value.unwrap_or(Value::Null));
+/// InTransaction returns the session's @@TRANCOUNT on the global connection:
+/// 0 when no transaction is open, 1 or more inside one (savepoints from nested
+/// BeginTransaction calls do not add to it). Transactions begun through
+/// ExecuteSql count too. Returns -1 on failure; call GetLastErrorMessage for
+/// the reason.
+#[unsafe(no_mangle)]
+pub extern "C" fn InTransaction() -> i32 {
+    let client_storage = match DB_CLIENT.get() {
+        Some(cs) => cs,
+        None => {
+            set_last_error("ERROR: Database not connected.");
+            return -1;
+        }
+    };
+    let mut db_guard = lock_client(client_storage);
+    let client = match db_guard.as_mut() {
+        Some(c) => c,
+        None => {
+            set_last_error(&format!("ERROR: {}", empty_slot_error()));
+            return -1;
+        }
+    };
+    let result = run_statement(transaction_count(client));
+    discard_if_abandoned(&mut db_guard, &result);
+    match result {
+        Ok(count) => {
+            record_success();
+            count
+        }
+        Err(e) => {
+            set_last_error(&format!("ERROR: {}", e));
+            -1
         }
-        results.push(row_map);
     }
+}
 
-    // Serialize directly to bytes (more efficient than to_string for large payloads)
-    let json_bytes = serde_json::to_vec(&results)
-        .map_err(|e| format!("Failed to marshal JSON: {}", e))?;
-    let json = String::from_utf8(json_bytes)
-        .map_err(|e| format!("Failed to convert JSON bytes to string: {}", e))?;
-
-    Ok(Some(json))
+/// Savepoint standing in for nesting level `depth` (1 = first inner level)
+fn nested_savepoint(depth: u32) -> String {
+    format!("__thinksqlrs_nest_{}", depth)
 }
 
-/// Execute non-SELECT statement using simple_query (avoids sp_executesql overhead)
-async fn execute_non_select(
-    client: &mut Client<tokio_util::compat::Compat<TcpStream>>,
-    sql: &str,
-) -> Result<Option<String>, String> {
-    trace(&format!("EXEC (non-select): {}", sql.trim()));
-    client
-        .simple_query(sql)
-        .await
-        .map_err(|e| format!("SQL execution failed: {}", e))?
-        .into_results()
-        .await
-        .map_err(|e| format!("SQL execution failed: {}", e))?;
+// Transaction control sent as ordinary SQL rather than through BeginTransaction
+static TRANSACTION_CONTROL_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\b(BEGIN\s+(DISTRIBUTED\s+)?TRAN(SACTION)?|COMMIT|ROLLBACK|IMPLICIT_TRANSACTIONS)\b")
+        .unwrap()
+});
 
-    trace("Non-select completed OK");
-    Ok(None) // Success
+/// Finish a statement that ran caller SQL on the global connection: drop an
+/// abandoned connection and keep the transaction state in step with it.
+fn finish_global_statement<T>(slot: &mut Option<TibClient>, sql: &str, result: &Result<T, String>) {
+    discard_if_abandoned(slot, result);
+    sync_transaction_state(slot, sql, result.is_err());
 }
 
-/// Detect column type from first row to avoid repeated type checks per cell
-fn detect_column_type(row: &tiberius::Row, index: usize) -> ColumnType {
-    if row.try_get::<i64, _>(index).ok().flatten().is_some() {
-        return ColumnType::I64;
+/// Keep TRANSACTION_DEPTH and SESSION_TRANSACTION_OPEN in step with the
+/// session after a statement on the global connection. The server ends a
+/// transaction on its own after some errors (deadlock victim, XACT_ABORT,
+/// severity 16 and up), caller SQL can begin, commit or roll back one itself,
+/// and a discarded connection takes its transaction with it.
+fn sync_transaction_state(slot: &mut Option<TibClient>, sql: &str, failed: bool) {
+    let tracked = in_global_transaction();
+    match slot.as_mut() {
+        None => {
+            if tracked {
+                trace_at(TRACE_ERRORS, "Connection discarded - its transaction is gone");
+            }
+            reset_transaction_state();
+        }
+        Some(client)
+            if (failed && tracked)
+                || TRANSACTION_CONTROL_RE.is_match(&mask_comments_and_literals(sql)) =>
+        {
+            refresh_transaction_depth(client);
+        }
+        Some(_) => {}
     }
-    if row.try_get::<i32, _>(index).ok().flatten().is_some() {
-        return ColumnType::I32;
+}
+
+/// Re-read @@TRANCOUNT and reset TRANSACTION_DEPTH if the transaction has
+/// ended; returns the depth afterwards.
+fn refresh_transaction_depth(client: &mut TibClient) -> u32 {
+    let depth = TRANSACTION_DEPTH.load(Ordering::Relaxed);
+    // Not run_statement: that would clear the PRINT messages of the statement
+    let count = get_runtime().block_on(transaction_count(client));
+    if let Ok(count) = count {
+        SESSION_TRANSACTION_OPEN.store(count > 0, Ordering::Relaxed);
     }
-    if row.try_get::<&str, _>(index).ok().flatten().is_some() {
+    match count {
+        Ok(0) if depth > 0 => {
+            trace_at(
+                TRACE_ERRORS,
+                "WARNING: transaction ended on the server - resetting BeginTransaction depth",
+            );
+            TRANSACTION_DEPTH.store(0, Ordering::Relaxed);
+            0
+        }
+        Ok(_) => depth,
+        Err(e) => {
+            trace_at(TRACE_ERRORS, &format!("WARNING: could not check @@TRANCOUNT: {}", e));
+            depth
+        }
+    }
+}
+
+/// The BeginTransaction depth, re-checked against the server first
+fn current_transaction_depth() -> u32 {
+    let depth = TRANSACTION_DEPTH.load(Ordering::Relaxed);
+    if depth == 0 {
+        return 0;
+    }
+    let Some(client_storage) = DB_CLIENT.get() else {
+        return depth;
+    };
+    match lock_client(client_storage).as_mut() {
+        Some(client) => refresh_transaction_depth(client),
+        None => depth,
+    }
+}
+
+/// BeginTransaction starts an explicit transaction on the active connection.
+/// Returns null on success, or a C error string on failure.
+/// Use this before a batch of INSERT/UPDATE/DELETE statements to avoid
+/// per-statement auto-commit overhead (log flush per row).
+/// Calls nest: inside an open BeginTransaction a savepoint is created instead,
+/// which the matching CommitTransaction keeps and RollbackTransaction undoes.
+/// SELECTs run inside the open transaction rather than their own, so under
+/// snapshot isolation they all see the same consistent snapshot.
+/// The nesting depth is checked against @@TRANCOUNT, so a transaction the
+/// server ended on its own (deadlock victim, XACT_ABORT) or a COMMIT sent
+/// through ExecuteSql resets it instead of leaving it stuck above zero.
+#[unsafe(no_mangle)]
+pub extern "C" fn BeginTransaction() -> *const c_char {
+    let depth = current_transaction_depth();
+    let result = if depth == 0 {
+        run_control_statement("BEGIN TRANSACTION", "begin transaction")
+    } else {
+        run_control_statement(
+            &format!("SAVE TRANSACTION {}", nested_savepoint(depth)),
+            "begin nested transaction",
+        )
+    };
+    if result.is_null() {
+        TRANSACTION_DEPTH.store(depth + 1, Ordering::Relaxed);
+    }
+    result
+}
+
+/// CommitTransaction commits the active explicit transaction.
+/// Returns null on success, or a C error string on failure.
+/// For a nested BeginTransaction only the outermost commit issues COMMIT;
+/// inner commits just close their level.
+#[unsafe(no_mangle)]
+pub extern "C" fn CommitTransaction() -> *const c_char {
+    let depth = current_transaction_depth();
+    if depth > 1 {
+        TRANSACTION_DEPTH.store(depth - 1, Ordering::Relaxed);
+        trace(&format!("Closed nested transaction level {}", depth - 1));
+        record_success();
+        return std::ptr::null();
+    }
+    // At depth 0 the server reports a commit without a matching BEGIN
+    let result = run_control_statement("COMMIT TRANSACTION", "commit transaction");
+    reset_transaction_state();
+    result
+}
+
+/// RollbackTransaction rolls back the active explicit transaction.
+/// Returns null on success, or a C error string on failure (e.g. the server's
+/// "no corresponding BEGIN TRANSACTION" error when no transaction is open).
+/// For a nested BeginTransaction only the innermost level is rolled back.
+#[unsafe(no_mangle)]
+pub extern "C" fn RollbackTransaction() -> *const c_char {
+    let depth = current_transaction_depth();
+    if depth > 1 {
+        let result = run_control_statement(
+            &format!("ROLLBACK TRANSACTION {}", nested_savepoint(depth - 1)),
+            "roll back nested transaction",
+        );
+        // A failure means the savepoint is gone, i.e. the server already
+        // rolled back the whole transaction
+        TRANSACTION_DEPTH.store(if result.is_null() { depth - 1 } else { 0 }, Ordering::Relaxed);
+        return result;
+    }
+    let result = run_control_statement("ROLLBACK TRANSACTION", "roll back transaction");
+    reset_transaction_state();
+    result
+}
+
+/// UseDatabase switches the active connection to another database
+/// (`USE [name]`). The name is bracket-quoted, so any database name is accepted
+/// without risk of injection. Pooled connections are switched back to the
+/// connection string's database when they are reused.
+/// Returns null on success, or a C error string on failure.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn UseDatabase(name: *const c_char) -> *const c_char {
+    let name = match unsafe { c_str_arg(name, "database name") } {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if name.is_empty() || name.chars().count() > 128 {
+        return create_error_string(&format!("ERROR: Invalid database name '{}'", name));
+    }
+    run_control_statement(&use_database_statement(name), "switch database")
+}
+
+/// `USE [name]` with any `]` in the name escaped
+fn use_database_statement(name: &str) -> String {
+    format!("USE [{}]", name.replace(']', "]]"))
+}
+
+/// PingDb checks that the active connection is alive with a `SELECT 1` round-trip.
+/// Returns null on success, or a C error string if the connection is missing or broken.
+#[unsafe(no_mangle)]
+pub extern "C" fn PingDb() -> *const c_char {
+    run_control_statement("SELECT 1", "ping server")
+}
+
+/// GetServerInfo returns `{"version":...,"database":...,"spid":...}` for the
+/// active connection (`@@VERSION`, `DB_NAME()` and `@@SPID`), gathered in one
+/// round-trip. Useful for diagnostics and for branching on server features.
+/// The caller is responsible for freeing the returned string with FreeCString.
+#[unsafe(no_mangle)]
+pub extern "C" fn GetServerInfo() -> *const c_char {
+    let client_storage = match DB_CLIENT.get() {
+        Some(cs) => cs,
+        None => return create_error_string("ERROR: Database not connected. Call ConnectDb first."),
+    };
+    let mut db_guard = lock_client(client_storage);
+    let client = match db_guard.as_mut() {
+        Some(c) => c,
+        None => return create_error_string(&format!("ERROR: {}", empty_slot_error())),
+    };
+    let result = run_statement(server_info(client));
+    discard_if_abandoned(&mut db_guard, &result);
+    sql_result_to_c_string(result.map(Some))
+}
+
+async fn server_info(client: &mut TibClient) -> Result<String, String> {
+    let sql = "SELECT @@VERSION, DB_NAME(), CAST(@@SPID AS INT)";
+    trace_at(TRACE_STATEMENTS, &format!("EXEC: {}", sql));
+    let row = client
+        .simple_query(sql)
+        .await
+        .map_err(|e| sql_error("Failed to read server info", e))?
+        .into_row()
+        .await
+        .map_err(|e| sql_error("Failed to read server info", e))?
+        .ok_or("Server returned no info row")?;
+    to_json_string(&serde_json::json!({
+        "version": row.get::<&str, _>(0),
+        "database": row.get::<&str, _>(1),
+        "spid": row.get::<i32, _>(2),
+    }))
+}
+
+/// ListTables returns the tables and views of the current database as
+/// `[{"schema":"dbo","name":"Orders","type":"BASE TABLE"},...]`, from
+/// INFORMATION_SCHEMA.TABLES ordered by schema and name. `type` is
+/// "BASE TABLE" or "VIEW".
+/// The caller is responsible for freeing the returned string with FreeCString.
+#[unsafe(no_mangle)]
+pub extern "C" fn ListTables() -> *const c_char {
+    let result = run_catalog_query(LIST_TABLES_SQL, &[]).and_then(|tables| to_json_string(&tables));
+    sql_result_to_c_string(result.map(Some))
+}
+
+/// DescribeTable returns the columns of a table or view as
+/// `[{"name":"id","type":"int","maxLength":null,"precision":10,"scale":0,
+/// "nullable":false,"default":null},...]` in column order, from
+/// INFORMATION_SCHEMA.COLUMNS (`maxLength` is -1 for `(MAX)` types). `name` is `table` or `schema.table` and may be
+/// bracket-quoted (`[dbo].[Order Lines]`); without a schema the caller's
+/// default schema is used. The name is bound as a parameter, never spliced
+/// into the SQL. Returns an error string if the table does not exist.
+/// The caller is responsible for freeing the returned string with FreeCString.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn DescribeTable(name: *const c_char) -> *const c_char {
+    let name = match unsafe { c_str_arg(name, "Table name") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    let params = [SqlParam::Str(name.trim().to_string())];
+    let result = run_catalog_query(DESCRIBE_TABLE_SQL, &params).and_then(|columns| {
+        if columns.is_empty() {
+            return Err(format!("Table '{}' not found", name));
+        }
+        to_json_string(&columns)
+    });
+    sql_result_to_c_string(result.map(Some))
+}
+
+const LIST_TABLES_SQL: &str = "\
+SELECT TABLE_SCHEMA AS [schema], TABLE_NAME AS name, TABLE_TYPE AS type
+FROM INFORMATION_SCHEMA.TABLES
+ORDER BY TABLE_SCHEMA, TABLE_NAME";
+
+// PARSENAME splits and unquotes `schema.table` server-side
+const DESCRIBE_TABLE_SQL: &str = "\
+SELECT COLUMN_NAME AS name, DATA_TYPE AS type, CHARACTER_MAXIMUM_LENGTH AS maxLength,
+  NUMERIC_PRECISION AS precision, NUMERIC_SCALE AS scale,
+  CAST(CASE IS_NULLABLE WHEN 'YES' THEN 1 ELSE 0 END AS BIT) AS nullable,
+  COLUMN_DEFAULT AS [default]
+FROM INFORMATION_SCHEMA.COLUMNS
+WHERE TABLE_NAME = PARSENAME(@P1, 1)
+  AND TABLE_SCHEMA = COALESCE(PARSENAME(@P1, 2), SCHEMA_NAME())
+ORDER BY ORDINAL_POSITION";
+
+/// Run a catalog query on the global connection and return its rows as
+/// objects, independent of SetResultFormat.
+fn run_catalog_query(
+    sql: &str,
+    params: &[SqlParam],
+) -> Result<Vec<serde_json::Map<String, Value>>, String> {
+    let client_storage = DB_CLIENT
+        .get()
+        .ok_or("Database not connected. Call ConnectDb first.")?;
+    let mut db_guard = lock_client(client_storage);
+    let client = db_guard
+        .as_mut()
+        .ok_or_else(empty_slot_error)?;
+    let result = run_statement(async {
+        trace_at(TRACE_STATEMENTS, &format!("EXEC (catalog): {}", sql));
+        let args: Vec<&dyn ToSql> = params.iter().map(|p| p as &dyn ToSql).collect();
+        let stream = client
+            .query(sql, &args)
+            .await
+            .map_err(|e| sql_error("Catalog query failed", e))?;
+        let set = collect_result_sets(stream)
+            .await
+            .map_err(|e| sql_error("Catalog query failed", e))?;
+        Ok(rows_to_maps(&select_result_set(set).unwrap_or_default().rows))
+    });
+    discard_if_abandoned(&mut db_guard, &result);
+    result
+}
+
+/// SaveTransaction creates a named savepoint inside the active transaction
+/// (`SAVE TRANSACTION <name>`). Names may only contain letters, digits and
+/// underscores. Returns null on success, or a C error string on failure.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn SaveTransaction(name: *const c_char) -> *const c_char {
+    let name = match unsafe { c_str_arg(name, "savepoint name") } {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = validate_savepoint_name(name) {
+        return create_error_string(&format!("ERROR: {}", e));
+    }
+    run_control_statement(&format!("SAVE TRANSACTION {}", name), "save transaction")
+}
+
+/// RollbackToSavepoint rolls back to a savepoint created by SaveTransaction
+/// (`ROLLBACK TRANSACTION <name>`). The outer transaction stays open.
+/// Returns null on success, or a C error string on failure.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn RollbackToSavepoint(name: *const c_char) -> *const c_char {
+    let name = match unsafe { c_str_arg(name, "savepoint name") } {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = validate_savepoint_name(name) {
+        return create_error_string(&format!("ERROR: {}", e));
+    }
+    run_control_statement(&format!("ROLLBACK TRANSACTION {}", name), "roll back to savepoint")
+}
+
+/// Savepoint names are spliced into the statement text, so only allow
+/// plain identifiers (SQL Server caps them at 32 characters).
+fn validate_savepoint_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > 32 {
+        return Err(format!("Invalid savepoint name '{}': must be 1-32 characters", name));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!(
+            "Invalid savepoint name '{}': only letters, digits and '_' are allowed",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Run a transaction-control statement on the active connection via simple_query.
+/// Returns null on success, or a C error string on failure.
+fn run_control_statement(statement: &str, action: &str) -> *const c_char {
+    let client_storage = match DB_CLIENT.get() {
+        Some(cs) => cs,
+        None => return create_error_string("ERROR: Database not connected."),
+    };
+    let mut db_guard = lock_client(client_storage);
+    let client = match db_guard.as_mut() {
+        Some(c) => c,
+        None => return create_error_string(&format!("ERROR: {}", empty_slot_error())),
+    };
+    let runtime = get_runtime();
+    let result = runtime.block_on(async {
+        trace_at(TRACE_STATEMENTS, &format!("EXEC: {}", statement));
+        client
+            .simple_query(statement)
+            .await
+            .map_err(|e| sql_error(&format!("Failed to {}", action), e))?
+            .into_results()
+            .await
+            .map_err(|e| sql_error(&format!("Failed to {}", action), e))?;
+        Ok::<(), String>(())
+    });
+    match result {
+        Ok(()) => {
+            record_success();
+            std::ptr::null()
+        }
+        Err(e) => create_error_string(&format!("ERROR: {}", e)),
+    }
+}
+
+/// ExecuteSql processes and executes a SQL statement.
+/// Takes a C string as input, processes it, executes it on the connected DB,
+/// and returns a C string with the results (JSON for SELECT) or error message.
+/// A connection dropped since the last call (I/O error) is reopened from the
+/// ConnectDb connection string, except inside a transaction. A read-only
+/// SELECT is then retried once; any other statement returns the error.
+/// The caller is RESPONSIBLE for freeing the returned C string using FreeCString.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ExecuteSql(input_sql: *const c_char) -> *const c_char {
+    if input_sql.is_null() {
+        return create_error_string("ERROR: SQL input is null");
+    }
+
+    let c_str = unsafe { CStr::from_ptr(input_sql) };
+    let sql = match c_str.to_str() {
+        Ok(s) => s,
+        Err(_) => return create_error_string("ERROR: Invalid UTF-8 in SQL string"),
+    };
+
+    sql_result_to_c_string(execute_global(sql))
+}
+
+/// Run one ExecuteSql call on the global connection.
+fn execute_global(sql: &str) -> Result<Option<String>, String> {
+    // Check if database is connected
+    let client_storage = DB_CLIENT
+        .get()
+        .ok_or("Database not connected. Call ConnectDb first.")?;
+
+    let mut db_guard = match client_storage.try_lock() {
+        Ok(guard) => guard,
+        // Another thread is using the global connection: when enabled, run on
+        // a pooled connection of our own instead of waiting for it
+        Err(TryLockError::WouldBlock) if CONCURRENT_EXECUTE.load(Ordering::Relaxed) => {
+            let key = lock_or_recover(get_conn_key()).clone();
+            match key {
+                Some(key) => return execute_on_separate_connection(&key, sql),
+                None => lock_client(client_storage),
+            }
+        }
+        Err(TryLockError::WouldBlock) => lock_client(client_storage),
+        Err(TryLockError::Poisoned(poisoned)) => recover_client(client_storage, poisoned),
+    };
+    let client = db_guard
+        .as_mut()
+        .ok_or_else(empty_slot_error)?;
+    CONNECTION_LOST.with(|lost| lost.set(false));
+    let wrap_selects = GLOBAL_WRAP_SELECTS.load(Ordering::Relaxed);
+    let result = match execute_sql_on(client, sql, wrap_selects) {
+        Err(e) if CONNECTION_LOST.with(Cell::take) => {
+            reconnect_and_retry(&mut db_guard, sql, e, |client, wrap_selects| {
+                execute_sql_on(client, sql, wrap_selects)
+            })
+        }
+        result => result,
+    };
+    finish_global_statement(&mut db_guard, sql, &result);
+    result
+}
+
+/// The global connection failed with an I/O error (dropped by the server,
+/// idle timeout, failover): open a fresh one from the remembered connection
+/// string. Not done inside a transaction, whose work was lost with the old
+/// connection. Only a read-only SELECT is run once more; anything else may
+/// have reached the server before the connection dropped, and running it
+/// again could apply it twice, so its error is returned instead.
+fn reconnect_and_retry<T>(
+    slot: &mut Option<TibClient>,
+    sql: &str,
+    error: String,
+    rerun: impl FnOnce(&mut TibClient, bool) -> Result<T, String>,
+) -> Result<T, String> {
+    if in_global_transaction() {
+        trace_at(TRACE_ERRORS, "Connection lost inside a transaction - not reconnecting");
+        return Err(error);
+    }
+    let Some(key) = lock_or_recover(get_conn_key()).clone() else {
+        return Err(error);
+    };
+    let replay = is_read_only_select(sql);
+    trace_at(
+        TRACE_ERRORS,
+        &format!(
+            "Connection lost ({}) - reconnecting{}",
+            error,
+            if replay { " and retrying once" } else { "" }
+        ),
+    );
+    *slot = None;
+    // Its prepared statements were lost with the old session
+    lock_or_recover(&GLOBAL_PREPARED).take_handles();
+    let options = parse_connection_string(&key)?;
+    let wrap_selects = options.wrap_selects();
+    let client = checkout_connection(&key, options)
+        .map_err(|e| format!("{} (reconnect failed: {})", error, e))?;
+    let client = slot.insert(client);
+    if !replay {
+        return Err(format!("{} (reconnected; the statement was not retried)", error));
+    }
+    PENDING_SQL_ERROR.with(|pending| pending.set(None));
+    rerun(client, wrap_selects)
+}
+
+// Keywords that make a SELECT more than a plain read (SELECT ... INTO,
+// a CTE feeding a DELETE, a table variable being filled)
+static WRITE_KEYWORD_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)\b(INTO|INSERT|UPDATE|DELETE|MERGE|EXEC|EXECUTE)\b").unwrap());
+
+/// A SELECT that writes nothing, so running it a second time is harmless
+fn is_read_only_select(sql: &str) -> bool {
+    StatementKind::of(&statement_head(sql)) == StatementKind::Select
+        && !WRITE_KEYWORD_RE.is_match(&mask_comments_and_literals(sql))
+}
+
+// Host-supplied completion callback for ExecuteSqlAsync: (result, error, messages)
+type ResultCallback = extern "C" fn(*const c_char, *const c_char, *const c_char);
+
+/// ExecuteSqlAsync queues ExecuteSql on the shared runtime's blocking pool and
+/// returns at once, so the calling thread is never blocked. When the statement
+/// finishes `cb` is called, from a pool thread, with `(result, error, messages)`:
+/// on success `error` is null and `result` is the JSON (null for statements
+/// without rows); on failure `result` is null and `error` is the error string.
+/// `messages` is the statement's PRINT/informational messages as a JSON array,
+/// as GetLastMessages would return them (which cannot be called on the pool
+/// thread). The strings are only valid for the duration of the callback, so
+/// copy them; do not pass them to FreeCString. Calls on the global connection still run one at a time
+/// unless SetConcurrentExecute is enabled. Returns null once the work has been
+/// queued, or an error string for invalid arguments.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ExecuteSqlAsync(
+    input_sql: *const c_char,
+    cb: Option<ResultCallback>,
+) -> *const c_char {
+    let sql = match unsafe { c_str_arg(input_sql, "SQL string") } {
+        Ok(s) => s.to_string(),
+        Err(e) => return e,
+    };
+    let Some(cb) = cb else {
+        return create_error_string("ERROR: Callback is null");
+    };
+
+    // The blocking pool rather than an async task: execution drives the
+    // runtime with block_on, which is not allowed on its worker threads. The
+    // pool is bounded, so heavy fan-out queues instead of starting a thread
+    // per call.
+    get_runtime().spawn_blocking(move || {
+        LAST_MESSAGES.with(|messages| messages.borrow_mut().clear());
+        let result = execute_global(&sql);
+        let (output, error) = match result {
+            Ok(json) => (json, None),
+            Err(e) => (None, Some(error_text(&format!("ERROR: {}", e)))),
+        };
+        let messages = LAST_MESSAGES.with(|messages| messages.take());
+        let messages = to_json_string(&messages).unwrap_or_else(|_| "[]".to_string());
+        // Interior NULs cannot cross the C boundary; replace them
+        let to_c = |text: String| CString::new(text.replace('\0', " ")).unwrap_or_default();
+        let output = output.map(to_c);
+        let error = error.map(to_c);
+        let messages = to_c(messages);
+        cb(
+            output.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+            error.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+            messages.as_ptr(),
+        );
+    });
+    record_success();
+    std::ptr::null()
+}
+
+/// Run one ExecuteSql call on a connection checked out from the pool for the
+/// global connection's connection string, then return it to the pool.
+fn execute_on_separate_connection(conn_string: &str, sql: &str) -> Result<Option<String>, String> {
+    trace("Global connection busy - using a separate pooled connection");
+    let options = parse_connection_string(conn_string)?;
+    let wrap_selects = options.wrap_selects();
+    let mut slot = Some(checkout_connection(conn_string, options)?);
+    let result = execute_sql_on(slot.as_mut().unwrap(), sql, wrap_selects);
+    discard_if_abandoned(&mut slot, &result);
+    if let Some(client) = slot {
+        return_to_pool(conn_string.to_string(), client);
+    }
+    result
+}
+
+/// Classify, preprocess and execute a SQL statement on the given client.
+/// Returns Some(json) for SELECT-like statements and EXECs that produce a result set,
+/// and None for everything else.
+fn execute_sql_on(
+    client: &mut TibClient,
+    sql: &str,
+    wrap_selects: bool,
+) -> Result<Option<String>, String> {
+    check_single_statement(sql)?;
+    let head = statement_head(sql);
+    let kind = StatementKind::of(&head);
+
+    let processed_sql = apply_auto_primary_key(sql, &head);
+
+    trace_at(TRACE_STATEMENTS, &format!("Input SQL:  {}", sql.trim()));
+    if processed_sql != sql {
+        trace_at(TRACE_STATEMENTS, &format!("Processed:  {}", processed_sql.trim()));
+    }
+    trace(&format!("Kind:       {:?}", kind));
+
+    // Execute the SQL
+    run_with_retry(client, async |client: &mut TibClient| match kind {
+        StatementKind::Select => execute_select_query(client, &processed_sql, wrap_selects).await,
+        StatementKind::Exec => execute_proc_statement(client, &processed_sql).await,
+        StatementKind::Other => execute_non_select(client, &processed_sql).await,
+    })
+}
+
+/// Run a statement, retrying transient server errors (deadlock victim,
+/// throttling) with exponential backoff according to SetRetryPolicy.
+fn run_with_retry<T, F>(client: &mut TibClient, mut statement: F) -> Result<T, String>
+where
+    F: AsyncFnMut(&mut TibClient) -> Result<T, String>,
+{
+    let max_attempts = RETRY_MAX_ATTEMPTS.load(Ordering::Relaxed).max(1);
+    if max_attempts == 1 {
+        return run_statement(statement(client));
+    }
+
+    // A deadlock or throttling error rolls back the caller's whole transaction,
+    // so a statement inside an explicit transaction must not be retried alone
+    let in_transaction = run_statement(transaction_count(client))? > 0;
+
+    let base_delay = RETRY_BASE_DELAY_MS.load(Ordering::Relaxed) as u64;
+    let mut attempt = 1;
+    loop {
+        let result = run_statement(statement(&mut *client));
+        let transient = result.is_err()
+            && PENDING_SQL_ERROR
+                .with(Cell::get)
+                .is_some_and(|info| TRANSIENT_ERROR_NUMBERS.contains(&info.number));
+        if !transient || in_transaction || attempt >= max_attempts {
+            return result;
+        }
+
+        let delay = base_delay.saturating_mul(1 << (attempt - 1).min(16));
+        trace(&format!(
+            "Transient error, retry {}/{} in {}ms",
+            attempt + 1,
+            max_attempts,
+            delay
+        ));
+        PENDING_SQL_ERROR.with(|pending| pending.set(None));
+        std::thread::sleep(Duration::from_millis(delay));
+        attempt += 1;
+    }
+}
+
+/// Number of open transactions on the session (@@TRANCOUNT)
+async fn transaction_count(client: &mut TibClient) -> Result<i32, String> {
+    let row = client
+        .simple_query("SELECT @@TRANCOUNT")
+        .await
+        .map_err(|e| sql_error("Failed to read @@TRANCOUNT", e))?
+        .into_row()
+        .await
+        .map_err(|e| sql_error("Failed to read @@TRANCOUNT", e))?;
+    Ok(row.and_then(|r| r.get::<i32, _>(0)).unwrap_or(0))
+}
+
+/// Drive a statement future on the runtime, bounded by the SetQueryTimeout value
+/// and abandoned early if CancelQuery is called.
+fn run_statement<T, F>(statement: F) -> Result<T, String>
+where
+    F: Future<Output = Result<T, String>>,
+{
+    let secs = QUERY_TIMEOUT_SECS.load(Ordering::Relaxed);
+    LAST_MESSAGES.with(|messages| messages.borrow_mut().clear());
+    let statement = catch_driver_panic(statement);
+    let bounded = async {
+        let timed = async {
+            if secs == 0 {
+                return statement.await;
+            }
+            match tokio::time::timeout(Duration::from_secs(secs as u64), statement).await {
+                Ok(result) => result,
+                Err(_) => {
+                    trace_at(TRACE_ERRORS, &format!("Query timed out after {}s", secs));
+                    Err(QUERY_TIMED_OUT.to_string())
+                }
+            }
+        };
+        tokio::select! {
+            result = timed => result,
+            _ = get_cancel_notify().notified() => {
+                trace_at(TRACE_ERRORS, "Query cancelled");
+                Err(QUERY_CANCELLED.to_string())
+            }
+        }
+    };
+    // block_on polls the statement on this thread, so its INFO events reach
+    // the capture subscriber set as this thread's default for the call
+    tracing::dispatcher::with_default(&MESSAGE_CAPTURE, || get_runtime().block_on(bounded))
+}
+
+/// A panic must not unwind across the C boundary (that aborts the host
+/// process) or silently end a background task, so a panic while polling the
+/// statement becomes an UNDECODABLE_RESPONSE error and the connection is
+/// discarded.
+async fn catch_driver_panic<T, F>(statement: F) -> Result<T, String>
+where
+    F: Future<Output = Result<T, String>>,
+{
+    let mut statement = std::pin::pin!(statement);
+    std::future::poll_fn(move |cx| {
+        match std::panic::catch_unwind(AssertUnwindSafe(|| statement.as_mut().poll(cx))) {
+            Ok(poll) => poll,
+            Err(payload) => {
+                let reason = payload
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                trace_at(TRACE_ERRORS, &format!("TDS driver panicked: {}", reason));
+                Poll::Ready(Err(UNDECODABLE_RESPONSE.to_string()))
+            }
+        }
+    })
+    .await
+}
+
+/// Whether `error` means the statement was abandoned mid-response (timeout,
+/// cancel or a driver panic), leaving the TDS stream out of sync.
+fn is_abandoning_error(error: &str) -> bool {
+    [QUERY_TIMED_OUT, QUERY_CANCELLED, UNDECODABLE_RESPONSE].contains(&error)
+}
+
+/// After a statement was abandoned mid-response the client cannot be reused
+/// or pooled: drop it from its slot.
+fn discard_if_abandoned<T>(slot: &mut Option<TibClient>, result: &Result<T, String>) {
+    if matches!(result, Err(e) if is_abandoning_error(e)) {
+        trace("Closing connection after abandoned statement");
+        *slot = None;
+    }
+}
+
+/// ExecuteSqlMulti executes a batch on the global connection and returns every
+/// result set as a JSON array of arrays-of-objects, in the order the server
+/// produced them (e.g. `[[{"a":1}],[{"b":2}]]`). Unlike ExecuteSql the batch is
+/// sent as-is: no transaction wrapper and no CREATE TABLE rewriting.
+/// The caller is responsible for freeing the returned string with FreeCString.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ExecuteSqlMulti(input_sql: *const c_char) -> *const c_char {
+    let sql = match unsafe { c_str_arg(input_sql, "SQL string") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+
+    // Check if database is connected
+    let client_storage = match DB_CLIENT.get() {
+        Some(cs) => cs,
+        None => return create_error_string("ERROR: Database not connected. Call ConnectDb first."),
+    };
+
+    let mut db_guard = lock_client(client_storage);
+    let client = match db_guard.as_mut() {
+        Some(c) => c,
+        None => return create_error_string(&format!("ERROR: {}", empty_slot_error())),
+    };
+    let result = run_with_retry(client, async |client: &mut TibClient| {
+        execute_multi_query(client, sql).await
+    });
+    finish_global_statement(&mut db_guard, sql, &result);
+    sql_result_to_c_string(result)
+}
+
+/// ExecuteBatch runs a script on the global connection, split into batches on
+/// `GO` lines (a client-side separator as in sqlcmd/SSMS, not T-SQL; matched
+/// case-insensitively on a line of its own, outside comments and literals).
+/// `GO n` runs the batch before it n times. Batches run in order and the result
+/// is a JSON array with one `{"statement":"...","rowsAffected":n}` or
+/// `{"statement":"...","error":"..."}` entry per batch run, where rowsAffected
+/// is the count of the batch's last statement. Entries also carry the batch's
+/// PRINT/informational `messages`, if any; GetLastMessages returns those of
+/// the whole script. After a failed batch the rest are skipped unless
+/// `continue_on_error` is set.
+/// Batches run on the same session, so SET options, USE and #temp tables from
+/// one batch carry into the next, as in sqlcmd.
+/// The caller is responsible for freeing the returned string with FreeCString.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ExecuteBatch(input_sql: *const c_char, continue_on_error: bool) -> *const c_char {
+    let sql = match unsafe { c_str_arg(input_sql, "SQL string") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+
+    let client_storage = match DB_CLIENT.get() {
+        Some(cs) => cs,
+        None => return create_error_string("ERROR: Database not connected. Call ConnectDb first."),
+    };
+
+    let mut db_guard = lock_client(client_storage);
+    let client = match db_guard.as_mut() {
+        Some(c) => c,
+        None => return create_error_string(&format!("ERROR: {}", empty_slot_error())),
+    };
+    let result = execute_go_batches(client, sql, continue_on_error);
+    finish_global_statement(&mut db_guard, sql, &result);
+    sql_result_to_c_string(result)
+}
+
+/// Split a script into batches on lines holding only `GO`, optionally followed
+/// by a repeat count as in sqlcmd (`GO 3`). A GO inside a comment or string
+/// literal is not a separator. Returns each non-empty batch with the number of
+/// times to run it.
+fn split_go_batches(script: &str) -> Result<Vec<(String, u32)>, String> {
+    let masked = mask_comments_and_literals(script);
+    let mut batches = Vec::new();
+    let mut batch_start = 0;
+    let mut line_start = 0;
+    for line in masked.split_inclusive('\n') {
+        let line_end = line_start + line.len();
+        let mut words = line.split_whitespace();
+        if words.next().is_some_and(|word| word.eq_ignore_ascii_case("GO")) {
+            let count = match (words.next(), words.next()) {
+                (None, _) => Some(1),
+                (Some(count), None) => count.parse::<u32>().ok().filter(|&n| n > 0),
+                _ => None,
+            }
+            .ok_or_else(|| format!("Invalid GO line: {}", script[line_start..line_end].trim()))?;
+            batches.push((script[batch_start..line_start].trim().to_string(), count));
+            batch_start = line_end;
+        }
+        line_start = line_end;
+    }
+    batches.push((script[batch_start..].trim().to_string(), 1));
+    batches.retain(|(batch, _)| !batch.is_empty());
+    Ok(batches)
+}
+
+// Statements whose body runs to the end of the batch, so nothing can be
+// appended after them
+static WHOLE_BATCH_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(concat!(
+        r"(?i)^\s*(CREATE|ALTER)\s+(OR\s+ALTER\s+)?",
+        r"(PROC|PROCEDURE|VIEW|FUNCTION|TRIGGER|SCHEMA|DEFAULT|RULE)\b",
+    ))
+    .unwrap()
+});
+
+/// Run one GO batch as a plain batch, so its session state (SET options, USE,
+/// #temp tables) carries into the next one, and return the row count of its
+/// last statement.
+async fn execute_go_batch(client: &mut TibClient, batch: &str) -> Result<i64, String> {
+    if WHOLE_BATCH_RE.is_match(&mask_comments_and_literals(batch)) {
+        client
+            .simple_query(batch)
+            .await
+            .map_err(|e| sql_error("Batch failed", e))?
+            .into_results()
+            .await
+            .map_err(|e| sql_error("Batch failed", e))?;
+        return Ok(0);
+    }
+    // A newline keeps the SELECT out of a trailing `--` comment
+    let batch = format!("{}\n;SELECT ROWCOUNT_BIG()", batch);
+    let result_sets = client
+        .simple_query(batch)
+        .await
+        .map_err(|e| sql_error("Batch failed", e))?
+        .into_results()
+        .await
+        .map_err(|e| sql_error("Batch failed", e))?;
+    Ok(result_sets
+        .last()
+        .and_then(|rows| rows.first())
+        .and_then(|row| row.try_get::<i64, _>(0).ok().flatten())
+        .unwrap_or(0))
+}
+
+/// Execute each GO batch of a script and report the outcome of every batch run.
+/// A timeout, cancel or driver panic aborts the whole script, since the
+/// connection is then unusable.
+fn execute_go_batches(
+    client: &mut TibClient,
+    script: &str,
+    continue_on_error: bool,
+) -> Result<Option<String>, String> {
+    let batches = split_go_batches(script)?;
+    trace(&format!("Executing script of {} batches", batches.len()));
+    let mut results = Vec::with_capacity(batches.len());
+    let mut messages = Vec::new();
+    let mut aborted = None;
+    let runs = batches
+        .iter()
+        .flat_map(|(batch, count)| std::iter::repeat_n(batch, *count as usize));
+    for (i, batch) in runs.enumerate() {
+        trace_at(TRACE_STATEMENTS, &format!("EXEC (batch {}): {}", i + 1, batch));
+        let outcome = run_statement(execute_go_batch(client, batch));
+        let batch_messages = LAST_MESSAGES.with(|last| std::mem::take(&mut *last.borrow_mut()));
+        let mut entry = serde_json::Map::new();
+        entry.insert("statement".to_string(), Value::String(batch.clone()));
+        if !batch_messages.is_empty() {
+            entry.insert("messages".to_string(), Value::from(batch_messages.clone()));
+        }
+        messages.extend(batch_messages);
+        match outcome {
+            Ok(rows) => {
+                entry.insert("rowsAffected".to_string(), Value::Number(rows.into()));
+                results.push(entry);
+            }
+            Err(e) if is_abandoning_error(&e) => {
+                aborted = Some(e);
+                break;
+            }
+            Err(e) => {
+                trace_at(TRACE_ERRORS, &format!("Batch {} failed: {}", i + 1, e));
+                entry.insert("error".to_string(), Value::String(e));
+                results.push(entry);
+                if !continue_on_error {
+                    break;
+                }
+            }
+        }
+    }
+    // run_statement keeps only its own batch's messages; GetLastMessages
+    // returns those of the whole script
+    LAST_MESSAGES.with(|last| *last.borrow_mut() = messages);
+    match aborted {
+        Some(e) => Err(e),
+        None => to_json_string(&results).map(Some),
+    }
+}
+
+/// ExecuteSqlCsv runs a SELECT on the global connection and returns the first
+/// result set as RFC 4180 CSV with a header row (just the header when no rows
+/// come back).
+/// Fields containing commas, quotes or line breaks are quoted and NULLs are
+/// empty fields. The caller is responsible for freeing the returned string
+/// with FreeCString.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ExecuteSqlCsv(input_sql: *const c_char) -> *const c_char {
+    let sql = match unsafe { c_str_arg(input_sql, "SQL string") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    if let Err(e) = check_single_statement(sql) {
+        return create_error_string(&format!("ERROR: {}", e));
+    }
+
+    let client_storage = match DB_CLIENT.get() {
+        Some(cs) => cs,
+        None => return create_error_string("ERROR: Database not connected. Call ConnectDb first."),
+    };
+
+    let mut db_guard = lock_client(client_storage);
+    let client = match db_guard.as_mut() {
+        Some(c) => c,
+        None => return create_error_string(&format!("ERROR: {}", empty_slot_error())),
+    };
+    let wrap_selects = GLOBAL_WRAP_SELECTS.load(Ordering::Relaxed);
+    let result = run_with_retry(client, async |client: &mut TibClient| {
+        let set = fetch_select_rows(client, sql, wrap_selects).await?;
+        Ok(Some(rows_to_csv(&set.unwrap_or_default())))
+    });
+    discard_if_abandoned(&mut db_guard, &result);
+    sql_result_to_c_string(result)
+}
+
+/// ExecuteSqlReadUncommitted runs a SELECT on the global connection under READ
+/// UNCOMMITTED (the equivalent of WITH (NOLOCK) on every table), without the
+/// snapshot transaction wrapper. Dirty reads: the result may include
+/// uncommitted or rolled-back data. The isolation change is scoped to this one
+/// query; the session keeps its configured level. Returns JSON like ExecuteSql.
+/// The caller is responsible for freeing the returned string with FreeCString.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ExecuteSqlReadUncommitted(input_sql: *const c_char) -> *const c_char {
+    let sql = match unsafe { c_str_arg(input_sql, "SQL string") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    if let Err(e) = check_single_statement(sql) {
+        return create_error_string(&format!("ERROR: {}", e));
+    }
+    if !is_select_statement(&statement_head(sql)) {
+        return create_error_string("ERROR: ExecuteSqlReadUncommitted only runs SELECT statements");
+    }
+
+    let client_storage = match DB_CLIENT.get() {
+        Some(cs) => cs,
+        None => return create_error_string("ERROR: Database not connected. Call ConnectDb first."),
+    };
+
+    let mut db_guard = lock_client(client_storage);
+    let client = match db_guard.as_mut() {
+        Some(c) => c,
+        None => return create_error_string(&format!("ERROR: {}", empty_slot_error())),
+    };
+    // Sent through sp_executesql, so the SET only lasts for this call and the
+    // session's own isolation level is back in force afterwards
+    let dirty_sql = format!(
+        "{}; {}",
+        IsolationLevel::ReadUncommitted.set_statement(),
+        sql.trim()
+    );
+    trace("READ UNCOMMITTED: dirty reads in effect for this query (no transaction wrapper)");
+    let result = run_with_retry(client, async |client: &mut TibClient| {
+        execute_params_query(client, &dirty_sql, &[], StatementKind::Select, None).await
+    });
+    discard_if_abandoned(&mut db_guard, &result);
+    sql_result_to_c_string(result)
+}
+
+/// ExecuteSqlFull runs a statement on the global connection and returns both
+/// the rows it produced and the number of rows it affected, as
+/// `{"rows":[...],"rowsAffected":N}`. Meant for DML that also returns rows,
+/// such as `INSERT ... OUTPUT inserted.*` or MERGE with an OUTPUT clause.
+/// `rows` is the first result set with rows (an empty array if none) and
+/// `rowsAffected` is ROWCOUNT_BIG() after the last statement of the batch.
+/// The caller is responsible for freeing the returned string with FreeCString.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ExecuteSqlFull(input_sql: *const c_char) -> *const c_char {
+    let sql = match unsafe { c_str_arg(input_sql, "SQL string") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    if let Err(e) = check_single_statement(sql) {
+        return create_error_string(&format!("ERROR: {}", e));
+    }
+
+    let client_storage = match DB_CLIENT.get() {
+        Some(cs) => cs,
+        None => return create_error_string("ERROR: Database not connected. Call ConnectDb first."),
+    };
+
+    let mut db_guard = lock_client(client_storage);
+    let client = match db_guard.as_mut() {
+        Some(c) => c,
+        None => return create_error_string(&format!("ERROR: {}", empty_slot_error())),
+    };
+    let result = run_with_retry(client, async |client: &mut TibClient| {
+        execute_full_query(client, sql).await.map(Some)
+    });
+    finish_global_statement(&mut db_guard, sql, &result);
+    sql_result_to_c_string(result)
+}
+
+/// Run a statement followed by SELECT ROWCOUNT_BIG() in one batch and return its
+/// rows together with the affected count.
+async fn execute_full_query(client: &mut TibClient, sql: &str) -> Result<String, String> {
+    // A newline keeps the SELECT out of a trailing `--` comment
+    // ROWCOUNT_BIG() is a bigint, so counts above 2^31 do not overflow
+    let batch = format!("{}\n;SELECT ROWCOUNT_BIG()", sql.trim());
+    trace_at(TRACE_STATEMENTS, &format!("EXEC (batch): {}", batch));
+    let mut result_sets = client
+        .simple_query(batch)
+        .await
+        .map_err(|e| sql_error("Query execution failed", e))?
+        .into_results()
+        .await
+        .map_err(|e| sql_error("Failed to fetch results", e))?;
+
+    let rows_affected = result_sets
+        .pop()
+        .and_then(|rows| rows.into_iter().next())
+        .and_then(|row| row.try_get::<i64, _>(0).ok().flatten())
+        .unwrap_or(0);
+    let rows = result_sets
+        .into_iter()
+        .find(|rs| !rs.is_empty())
+        .unwrap_or_default();
+    trace(&format!("Statement returned {} rows, affected {}", rows.len(), rows_affected));
+    to_json_string(&serde_json::json!({
+        "rows": rows_to_maps(&rows),
+        "rowsAffected": rows_affected,
+    }))
+}
+
+/// ExecuteCount runs a query on the global connection and returns the first
+/// column of its first row as an integer, e.g. for `SELECT COUNT(*) FROM t`,
+/// without a JSON round trip. The column must be an integer type (or a
+/// DECIMAL/NUMERIC with no fractional part). Returns -1 on failure, including
+/// no rows or a NULL value; call GetLastErrorMessage for the reason.
+/// Transient errors are retried per SetRetryPolicy, and a dropped connection
+/// is reopened and the query retried once, as for ExecuteSql.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ExecuteCount(input_sql: *const c_char) -> i64 {
+    let sql = match unsafe { c_str_arg(input_sql, "SQL string") } {
+        Ok(s) => s,
+        Err(e) => {
+            free_error_string(e);
+            return -1;
+        }
+    };
+    let result = check_single_statement(sql).and_then(|_| {
+        let client_storage = DB_CLIENT
+            .get()
+            .ok_or("Database not connected. Call ConnectDb first.")?;
+        let mut db_guard = lock_client(client_storage);
+        let client = db_guard
+            .as_mut()
+            .ok_or_else(empty_slot_error)?;
+
+        CONNECTION_LOST.with(|lost| lost.set(false));
+        let count = |client: &mut TibClient| {
+            run_with_retry(client, async |client: &mut TibClient| {
+                execute_count_query(client, sql).await
+            })
+        };
+        let result = match count(client) {
+            Err(e) if CONNECTION_LOST.with(Cell::take) => {
+                reconnect_and_retry(&mut db_guard, sql, e, |client, _| count(client))
+            }
+            result => result,
+        };
+        finish_global_statement(&mut db_guard, sql, &result);
+        result
+    });
+
+    match result {
+        Ok(count) => {
+            record_success();
+            count
+        }
+        Err(e) => {
+            set_last_error(&format!("ERROR: {}", e));
+            -1
+        }
+    }
+}
+
+/// Run a query and read the first column of its first row as an integer.
+async fn execute_count_query(client: &mut TibClient, sql: &str) -> Result<i64, String> {
+    trace_at(TRACE_STATEMENTS, &format!("EXEC (count): {}", sql));
+    let row = client
+        .simple_query(sql)
+        .await
+        .map_err(|e| sql_error("Query execution failed", e))?
+        .into_row()
+        .await
+        .map_err(|e| sql_error("Failed to fetch results", e))?
+        .ok_or("Query returned no rows")?;
+    let value = match row.cells().next().map(|(_, data)| data) {
+        None => return Err("Query returned no columns".to_string()),
+        Some(ColumnData::U8(v)) => v.map(i64::from),
+        Some(ColumnData::I16(v)) => v.map(i64::from),
+        Some(ColumnData::I32(v)) => v.map(i64::from),
+        Some(ColumnData::I64(v)) => *v,
+        Some(ColumnData::Numeric(Some(n))) if n.scale() == 0 => Some(
+            i64::try_from(n.value()).map_err(|_| format!("Value {} does not fit in a BIGINT", n))?,
+        ),
+        Some(ColumnData::Numeric(None)) => None,
+        Some(_) => {
+            return Err(format!(
+                "First column is {}, not an integer",
+                declared_type_name(&row, 0)
+            ));
+        }
+    };
+    let count = value.ok_or("Query returned NULL")?;
+    trace(&format!("Count: {}", count));
+    Ok(count)
+}
+
+/// ExecutePaged runs one page of a SELECT on the global connection and returns
+/// it with the total number of rows the query matches, as
+/// `{"total":N,"rows":[...]}`. The query must end in an ORDER BY (SQL Server
+/// requires one for paging); `OFFSET offset ROWS FETCH NEXT limit ROWS ONLY`
+/// is appended to it, and the total comes from a COUNT over the same query
+/// without its ORDER BY, in the same batch. Every column must have a name.
+/// The caller is responsible for freeing the returned string with FreeCString.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ExecutePaged(input_sql: *const c_char, offset: i64, limit: i64) -> *const c_char {
+    let sql = match unsafe { c_str_arg(input_sql, "SQL string") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    if offset < 0 || limit <= 0 {
+        return create_error_string(&format!(
+            "ERROR: Invalid page (offset {} must be 0 or more, limit {} more than 0)",
+            offset, limit
+        ));
+    }
+    let batch = match build_paged_batch(sql) {
+        Ok(b) => b,
+        Err(e) => return create_error_string(&format!("ERROR: {}", e)),
+    };
+
+    let client_storage = match DB_CLIENT.get() {
+        Some(cs) => cs,
+        None => return create_error_string("ERROR: Database not connected. Call ConnectDb first."),
+    };
+
+    let mut db_guard = lock_client(client_storage);
+    let client = match db_guard.as_mut() {
+        Some(c) => c,
+        None => return create_error_string(&format!("ERROR: {}", empty_slot_error())),
+    };
+    let page = [SqlParam::Int(offset), SqlParam::Int(limit)];
+    let result = run_with_retry(client, async |client: &mut TibClient| {
+        execute_paged_query(client, &batch, &page).await.map(Some)
+    });
+    discard_if_abandoned(&mut db_guard, &result);
+    sql_result_to_c_string(result)
+}
+
+// Top-level clauses ExecutePaged looks for in a SELECT
+static PAGING_KEYWORD_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)\b(SELECT|ORDER\s+BY|OFFSET|OPTION|FOR)\b").unwrap());
+
+// Top-level clauses that end a SELECT list, or combine queries
+static COUNT_CLAUSE_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\b(FROM|WHERE|GROUP\s+BY|HAVING|UNION|EXCEPT|INTERSECT)\b").unwrap()
+});
+
+/// Build ExecutePaged's batch: the page (OFFSET @P1 / FETCH @P2) followed by
+/// the total count, e.g. for `SELECT a, COUNT(*) FROM t GROUP BY a ORDER BY a`:
+///   SELECT a, COUNT(*) FROM t GROUP BY a ORDER BY a OFFSET @P1 ROWS FETCH NEXT @P2 ROWS ONLY;
+///   SELECT COUNT_BIG(*) FROM (SELECT 1 AS [__row] FROM t GROUP BY a) AS [__paged];
+/// The count swaps the select list for a constant, so unnamed or duplicate
+/// columns do not matter. SELECT DISTINCT and UNION/EXCEPT/INTERSECT keep
+/// their select lists, which decide the rows. A leading WITH clause is
+/// repeated in front of the count.
+fn build_paged_batch(sql: &str) -> Result<String, String> {
+    let code = mask_comments_and_literals(sql);
+    if has_statement_separator(&code) {
+        return Err("ExecutePaged takes a single SELECT statement".to_string());
+    }
+    // Drop trailing semicolons (and comments, blank in the mask)
+    let end = code.trim_end_matches(|c: char| c == ';' || c.is_whitespace()).len();
+    let (sql, code) = (&sql[..end], &code[..end]);
+    let head = statement_head(sql);
+    if !starts_with_keyword(&head, "SELECT") && !starts_with_keyword(&head, "WITH") {
+        return Err("ExecutePaged only runs SELECT statements".to_string());
+    }
+
+    // Clauses outside parentheses belong to the outer query; CTE bodies and
+    // subqueries are all parenthesized
+    let mut depth_at = Vec::with_capacity(code.len());
+    let mut depth = 0i32;
+    for b in code.bytes() {
+        match b {
+            b'(' => depth += 1,
+            b')' => depth -= 1,
+            _ => {}
+        }
+        depth_at.push(depth);
+    }
+    let top_level: Vec<(usize, String)> = PAGING_KEYWORD_RE
+        .find_iter(code)
+        .filter(|m| depth_at[m.start()] == 0)
+        .map(|m| (m.start(), m.as_str().split_whitespace().next().unwrap_or("").to_uppercase()))
+        .collect();
+    let Some(select_at) = top_level.iter().find(|(_, k)| k == "SELECT").map(|(at, _)| *at) else {
+        return Err("ExecutePaged only runs SELECT statements".to_string());
+    };
+    let Some(order_at) = top_level.iter().rev().find(|(_, k)| k == "ORDER").map(|(at, _)| *at) else {
+        return Err("ExecutePaged requires the query to end in an ORDER BY clause".to_string());
+    };
+    if let Some((_, clause)) = top_level.iter().find(|(at, k)| *at > order_at && k != "SELECT") {
+        return Err(format!("ExecutePaged does not support {} after ORDER BY", clause));
+    }
+
+    let clauses: Vec<(usize, String)> = COUNT_CLAUSE_RE
+        .find_iter(&code[..order_at])
+        .filter(|m| m.start() > select_at && depth_at[m.start()] == 0)
+        .map(|m| (m.start(), m.as_str().split_whitespace().next().unwrap_or("").to_uppercase()))
+        .collect();
+    let distinct = starts_with_keyword(
+        &code[select_at + "SELECT".len()..order_at].trim_start().to_ascii_uppercase(),
+        "DISTINCT",
+    );
+    let combined = clauses
+        .iter()
+        .any(|(_, k)| matches!(k.as_str(), "UNION" | "EXCEPT" | "INTERSECT"));
+    let counted = if distinct || combined {
+        sql[select_at..order_at].trim_end().to_string()
+    } else {
+        let list_end = clauses.first().map_or(order_at, |(at, _)| *at);
+        format!("SELECT 1 AS [__row] {}", sql[list_end..order_at].trim())
+    };
+
+    Ok(format!(
+        "{} OFFSET @P1 ROWS FETCH NEXT @P2 ROWS ONLY;\n{}SELECT COUNT_BIG(*) FROM ({}) AS [__paged];",
+        sql,
+        &sql[..select_at],
+        counted.trim_end()
+    ))
+}
+
+/// Run ExecutePaged's batch and pair the page's rows with the total count.
+async fn execute_paged_query(client: &mut TibClient, batch: &str, page: &[SqlParam]) -> Result<String, String> {
+    trace_at(TRACE_STATEMENTS, &format!("EXEC (paged): {}", batch));
+    let args: Vec<&dyn ToSql> = page.iter().map(|p| p as &dyn ToSql).collect();
+    let stream = client
+        .query(batch, &args)
+        .await
+        .map_err(|e| sql_error("Query execution failed", e))?;
+    let mut result_sets = collect_result_sets(stream)
+        .await
+        .map_err(|e| sql_error("Failed to fetch results", e))?;
+
+    let total = result_sets
+        .pop()
+        .and_then(|set| set.rows.into_iter().next())
+        .and_then(|row| row.try_get::<i64, _>(0).ok().flatten())
+        .unwrap_or(0);
+    let rows = result_sets.into_iter().next().unwrap_or_default().rows;
+    trace(&format!("Page returned {} rows of {}", rows.len(), total));
+    to_json_string(&serde_json::json!({
+        "total": total,
+        "rows": rows_to_maps(&rows),
+    }))
+}
+
+/// ExecuteInsertReturningId runs an INSERT on the global connection and returns
+/// the identity value it generated, as a JSON scalar (e.g. `42`), or `null`
+/// when the INSERT produced none. `SELECT SCOPE_IDENTITY()` is sent in the same
+/// batch, so ids generated by triggers are not picked up.
+/// The caller is responsible for freeing the returned string with FreeCString.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ExecuteInsertReturningId(input_sql: *const c_char) -> *const c_char {
+    let sql = match unsafe { c_str_arg(input_sql, "SQL string") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    if let Err(e) = check_single_statement(sql) {
+        return create_error_string(&format!("ERROR: {}", e));
+    }
+    if !starts_with_keyword(&statement_head(sql), "INSERT") {
+        return create_error_string("ERROR: ExecuteInsertReturningId only runs INSERT statements");
+    }
+
+    let client_storage = match DB_CLIENT.get() {
+        Some(cs) => cs,
+        None => return create_error_string("ERROR: Database not connected. Call ConnectDb first."),
+    };
+
+    let mut db_guard = lock_client(client_storage);
+    let client = match db_guard.as_mut() {
+        Some(c) => c,
+        None => return create_error_string(&format!("ERROR: {}", empty_slot_error())),
+    };
+    let result = run_with_retry(client, async |client: &mut TibClient| {
+        execute_insert_returning_id(client, sql).await.map(Some)
+    });
+    finish_global_statement(&mut db_guard, sql, &result);
+    sql_result_to_c_string(result)
+}
+
+/// Run an INSERT followed by SELECT SCOPE_IDENTITY() in one batch and return
+/// the id as JSON.
+async fn execute_insert_returning_id(client: &mut TibClient, sql: &str) -> Result<String, String> {
+    // A newline keeps the SELECT out of a trailing `--` comment
+    let batch = format!("{}\n;SELECT SCOPE_IDENTITY()", sql.trim());
+    trace_at(TRACE_STATEMENTS, &format!("EXEC (batch): {}", batch));
+    let result_sets = client
+        .simple_query(batch)
+        .await
+        .map_err(|e| sql_error("Query execution failed", e))?
+        .into_results()
+        .await
+        .map_err(|e| sql_error("Failed to fetch results", e))?;
+
+    // SCOPE_IDENTITY() is NUMERIC(38,0); emit it as a plain integer when it fits
+    let id = result_sets
+        .last()
+        .and_then(|rows| rows.first())
+        .and_then(|row| row.try_get::<Numeric, _>(0).ok().flatten());
+    let value = match id {
+        None => Value::Null,
+        Some(n) => match i64::try_from(n.value()) {
+            Ok(v) if n.scale() == 0 => Value::Number(v.into()),
+            _ => Value::String(format_numeric(n)),
+        },
+    };
+    trace(&format!("Generated identity: {}", value));
+    to_json_string(&value)
+}
+
+/// In strict mode, fail if `sql` holds more than one `;`-separated statement.
+/// Separators inside comments, literals and quoted identifiers do not count.
+fn check_single_statement(sql: &str) -> Result<(), String> {
+    if !STRICT_SINGLE_STATEMENT.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    if has_statement_separator(&mask_comments_and_literals(sql)) {
+        return Err("Multiple statements are not allowed in strict single-statement mode".to_string());
+    }
+    Ok(())
+}
+
+/// Whether masked SQL (see mask_comments_and_literals) has a `;` before its end.
+fn has_statement_separator(code: &str) -> bool {
+    code.trim_end_matches(|c: char| c == ';' || c.is_whitespace())
+        .contains(';')
+}
+
+/// How a statement's output is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StatementKind {
+    /// Returns rows, as JSON (an empty array when there are none)
+    Select,
+    /// EXEC/EXECUTE, or DML with an OUTPUT clause: returns rows if the
+    /// statement produced a result set, else null
+    Exec,
+    /// No rows expected
+    Other,
+}
+
+impl StatementKind {
+    /// Classify a statement from its statement_head
+    fn of(head: &str) -> Self {
+        if is_select_statement(head) {
+            Self::Select
+        } else if starts_with_keyword(head, "EXEC")
+            || starts_with_keyword(head, "EXECUTE")
+            || has_output_clause(head)
+        {
+            Self::Exec
+        } else {
+            Self::Other
+        }
+    }
+}
+
+/// The uppercased statement text after any leading whitespace and comments.
+fn statement_head(sql: &str) -> String {
+    let masked = mask_comments_and_literals(sql);
+    // Comments are blanked in the mask, so the first visible byte starts the
+    // statement (literals are blanked too, but cannot start one)
+    let start = masked
+        .bytes()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(sql.len());
+    sql[start..].trim_end().to_uppercase()
+}
+
+/// Whether `head` starts with `keyword` as a whole word
+fn starts_with_keyword(head: &str, keyword: &str) -> bool {
+    head.strip_prefix(keyword)
+        .is_some_and(|rest| !rest.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_'))
+}
+
+/// Heuristic for statements that return rows (expects a statement_head)
+fn is_select_statement(head: &str) -> bool {
+    // A parenthesized query such as (SELECT ...) UNION (SELECT ...)
+    let head = head.trim_start_matches(|c: char| c == '(' || c.is_ascii_whitespace());
+    starts_with_keyword(head, "SELECT")
+        || starts_with_keyword(head, "WITH")
+        || (starts_with_keyword(head, "DECLARE") && head.contains("SELECT"))
+}
+
+static OUTPUT_KEYWORD_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)\bOUTPUT\b").unwrap());
+static INTO_KEYWORD_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)\bINTO\b").unwrap());
+
+/// INSERT/UPDATE/DELETE/MERGE whose OUTPUT clause returns rows to the client
+/// rather than writing them INTO a table (expects a statement_head)
+fn has_output_clause(head: &str) -> bool {
+    if !["INSERT", "UPDATE", "DELETE", "MERGE"].iter().any(|verb| starts_with_keyword(head, verb)) {
+        return false;
+    }
+    let code = mask_comments_and_literals(head);
+    OUTPUT_KEYWORD_RE
+        .find(&code)
+        .is_some_and(|output| !INTO_KEYWORD_RE.is_match(&code[output.end()..]))
+}
+
+/// ExecuteSqlParams executes a parameterized statement on the global
+/// connection. `params_json` is a JSON array of scalars (string, number,
+/// bool or null) bound positionally to @P1, @P2, ... so values never need to
+/// be interpolated into the SQL text. Returns the same as ExecuteSql.
+/// The statement is sent through sp_executesql, whose cached plan the server
+/// reuses for the same SQL text and parameter types, or through a prepared
+/// handle when SetPreparedCacheSize is non-zero.
+///
+/// # Safety
+/// This function is unsafe because it dereferences raw pointers from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ExecuteSqlParams(
+    input_sql: *const c_char,
+    params_json: *const c_char,
+) -> *const c_char {
+    let sql = match unsafe { c_str_arg(input_sql, "SQL string") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    let params_str = match unsafe { c_str_arg(params_json, "parameter JSON") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    let params = match parse_sql_params(params_str) {
+        Ok(p) => p,
+        Err(e) => return create_error_string(&format!("ERROR: {}", e)),
+    };
+    sql_result_to_c_string(execute_params_global(sql, &params, params_str))
+}
+
+/// ExecuteSqlNamedParams executes a parameterized statement like
+/// ExecuteSqlParams, but binds by name: `params_json` is a JSON object mapping
+/// `@name` (the `@` is optional) to a scalar value. Every `@name` in the SQL
+/// is bound to its value, so a name used more than once (e.g.
+/// `WHERE a = @id OR b = @id`) needs a single entry. Names are matched
+/// case-insensitively; `@variables` without an entry are left untouched.
+/// Returns the same as ExecuteSql.
+///
+/// # Safety
+/// This function is unsafe because it dereferences raw pointers from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ExecuteSqlNamedParams(
+    input_sql: *const c_char,
+    params_json: *const c_char,
+) -> *const c_char {
+    let sql = match unsafe { c_str_arg(input_sql, "SQL string") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    let params_str = match unsafe { c_str_arg(params_json, "parameter JSON") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    let (names, params) = match parse_named_sql_params(params_str) {
+        Ok(p) => p,
+        Err(e) => return create_error_string(&format!("ERROR: {}", e)),
+    };
+    let sql = match bind_named_params(sql, &names) {
+        Ok(s) => s,
+        Err(e) => return create_error_string(&format!("ERROR: {}", e)),
+    };
+    sql_result_to_c_string(execute_params_global(&sql, &params, params_str))
+}
+
+/// Run a parameterized statement on the global connection; `described` is the
+/// parameter list as shown in the trace.
+fn execute_params_global(sql: &str, params: &[SqlParam], described: &str) -> Result<Option<String>, String> {
+    check_single_statement(sql)?;
+
+    // Check if database is connected
+    let client_storage = DB_CLIENT
+        .get()
+        .ok_or("Database not connected. Call ConnectDb first.")?;
+
+    let mut db_guard = lock_client(client_storage);
+    let client = db_guard
+        .as_mut()
+        .ok_or_else(empty_slot_error)?;
+
+    let kind = StatementKind::of(&statement_head(sql));
+    trace_at(TRACE_STATEMENTS, &format!("Input SQL:  {}", sql.trim()));
+    trace_at(TRACE_STATEMENTS, &format!("Params:     {}", described));
+    trace(&format!("Kind:       {:?}", kind));
+
+    let prepared = (lock_or_recover(&GLOBAL_PREPARED).capacity > 0).then_some(&*GLOBAL_PREPARED);
+    let result = run_with_retry(client, async |client: &mut TibClient| {
+        execute_params_query(client, sql, params, kind, prepared).await
+    });
+    finish_global_statement(&mut db_guard, sql, &result);
+    result
+}
+
+// `@name` tokens; a leading `@@` marks a system function such as @@ROWCOUNT
+static NAMED_PARAM_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"@@?[\w@#$]+").unwrap());
+
+/// Parse a JSON object of named parameters into their names (lowercase,
+/// without `@`) and the values in the same order, bound as @P1, @P2, ...
+fn parse_named_sql_params(params_json: &str) -> Result<(Vec<String>, Vec<SqlParam>), String> {
+    let value: Value = serde_json::from_str(params_json)
+        .map_err(|e| format!("Invalid parameter JSON: {}", e))?;
+    let entries = match value {
+        Value::Object(entries) => entries,
+        _ => return Err("Parameter JSON must be an object".to_string()),
+    };
+    let mut names: Vec<String> = Vec::with_capacity(entries.len());
+    let mut params = Vec::with_capacity(entries.len());
+    for (key, item) in entries {
+        let name = key.strip_prefix('@').unwrap_or(&key);
+        if !is_plain_identifier(name) || is_positional_name(name) {
+            return Err(format!("Invalid parameter name: {}", key));
+        }
+        // Errors name the parameter as the caller wrote it, not its @Pn
+        let param = json_value_to_sql_param(item)
+            .map_err(|e| format!("Parameter @{} {}", name, e))?;
+        let name = name.to_ascii_lowercase();
+        if names.contains(&name) {
+            return Err(format!("Duplicate parameter name: {}", key));
+        }
+        names.push(name);
+        params.push(param);
+    }
+    Ok((names, params))
+}
+
+/// Names of the form P1, P2, ... would collide with the positional binding
+fn is_positional_name(name: &str) -> bool {
+    name.len() > 1
+        && name.starts_with(['P', 'p'])
+        && name[1..].chars().all(|c| c.is_ascii_digit())
+}
+
+/// Rewrite each `@name` in `sql` that appears in `names` to its positional
+/// @Pn, skipping comments, string literals and quoted identifiers.
+fn bind_named_params(sql: &str, names: &[String]) -> Result<String, String> {
+    let code = mask_comments_and_literals(sql);
+    let mut out = String::with_capacity(sql.len());
+    let mut last = 0;
+    let mut used = vec![false; names.len()];
+    for m in NAMED_PARAM_RE.find_iter(&code) {
+        let token = &m.as_str()[1..];
+        if token.starts_with('@') {
+            continue;
+        }
+        if is_positional_name(token) {
+            return Err(format!(
+                "@{} cannot be used together with named parameters",
+                token
+            ));
+        }
+        let lowered = token.to_ascii_lowercase();
+        if let Some(index) = names.iter().position(|n| *n == lowered) {
+            used[index] = true;
+            out.push_str(&sql[last..m.start()]);
+            out.push_str(&format!("@P{}", index + 1));
+            last = m.end();
+        }
+    }
+    out.push_str(&sql[last..]);
+    for (name, _) in names.iter().zip(&used).filter(|(_, used)| !**used) {
+        trace(&format!("Parameter @{} is not used in the SQL", name));
+    }
+    Ok(out)
+}
+
+// Type tags for ExecuteSqlTyped
+const PARAM_TYPE_NULL: u8 = 0;
+const PARAM_TYPE_INT: u8 = 1;
+const PARAM_TYPE_BIGINT: u8 = 2;
+const PARAM_TYPE_DOUBLE: u8 = 3;
+const PARAM_TYPE_BOOL: u8 = 4;
+const PARAM_TYPE_STRING: u8 = 5;
+
+/// ExecuteSqlTyped executes a parameterized statement like ExecuteSqlParams,
+/// but takes the parameters as two parallel arrays of `count` entries instead
+/// of JSON: `types` holds a tag per parameter (0 = NULL, 1 = INT, 2 = BIGINT,
+/// 3 = FLOAT, 4 = BIT, 5 = NVARCHAR) and `values` the value as text (e.g. "42",
+/// "3.5", "true"). A null value pointer binds NULL whatever the tag. Values are
+/// bound positionally to @P1, @P2, ... Returns the same as ExecuteSql.
+///
+/// # Safety
+/// This function is unsafe because it dereferences raw pointers from C;
+/// `types` and `values` must each point to `count` elements.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ExecuteSqlTyped(
+    input_sql: *const c_char,
+    types: *const u8,
+    values: *const *const c_char,
+    count: usize,
+) -> *const c_char {
+    let sql = match unsafe { c_str_arg(input_sql, "SQL string") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    if count > 0 && (types.is_null() || values.is_null()) {
+        return create_error_string("ERROR: Parameter arrays are null");
+    }
+    let (types, values) = if count == 0 {
+        (&[][..], &[][..])
+    } else {
+        // SAFETY: the caller guarantees both arrays hold `count` elements
+        unsafe {
+            (
+                std::slice::from_raw_parts(types, count),
+                std::slice::from_raw_parts(values, count),
+            )
+        }
+    };
+    let params = match types
+        .iter()
+        .zip(values)
+        .enumerate()
+        .map(|(i, (&tag, &value))| unsafe { typed_sql_param(i, tag, value) })
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(p) => p,
+        Err(e) => return create_error_string(&format!("ERROR: {}", e)),
+    };
+    let described = format!("{} typed", params.len());
+    sql_result_to_c_string(execute_params_global(sql, &params, &described))
+}
+
+/// Decode one ExecuteSqlTyped parameter; `index` is zero-based (@P{index+1})
+///
+/// # Safety
+/// `value` must be null or a NUL-terminated string.
+unsafe fn typed_sql_param(index: usize, tag: u8, value: *const c_char) -> Result<SqlParam, String> {
+    if tag == PARAM_TYPE_NULL || value.is_null() {
+        return Ok(SqlParam::Null);
+    }
+    let text = unsafe { CStr::from_ptr(value) }
+        .to_str()
+        .map_err(|_| format!("Invalid UTF-8 in parameter @P{}", index + 1))?;
+    let invalid = |what: &str| format!("Parameter @P{} is not a valid {}: '{}'", index + 1, what, text);
+    match tag {
+        PARAM_TYPE_INT => text.trim().parse().map(SqlParam::Int32).map_err(|_| invalid("INT")),
+        PARAM_TYPE_BIGINT => text.trim().parse().map(SqlParam::Int).map_err(|_| invalid("BIGINT")),
+        PARAM_TYPE_DOUBLE => text.trim().parse().map(SqlParam::Float).map_err(|_| invalid("FLOAT")),
+        PARAM_TYPE_BOOL => match text.trim().to_lowercase().as_str() {
+            "1" | "true" => Ok(SqlParam::Bool(true)),
+            "0" | "false" => Ok(SqlParam::Bool(false)),
+            _ => Err(invalid("BIT")),
+        },
+        PARAM_TYPE_STRING => Ok(SqlParam::Str(text.to_string())),
+        _ => Err(format!("Unknown type tag {} for parameter @P{}", tag, index + 1)),
+    }
+}
+
+/// A positional query parameter decoded from the JSON parameter array (or
+/// from ExecuteSqlTyped's typed arrays)
+enum SqlParam {
+    Null,
+    Bool(bool),
+    // INT, from ExecuteSqlTyped; JSON numbers always bind as BIGINT
+    Int32(i32),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+impl ToSql for SqlParam {
+    fn to_sql(&self) -> ColumnData<'_> {
+        match self {
+            // Untyped NULL is sent as NVARCHAR; SQL Server converts it implicitly
+            SqlParam::Null => ColumnData::String(None),
+            SqlParam::Bool(b) => ColumnData::Bit(Some(*b)),
+            SqlParam::Int32(i) => ColumnData::I32(Some(*i)),
+            SqlParam::Int(i) => ColumnData::I64(Some(*i)),
+            SqlParam::Float(f) => ColumnData::F64(Some(*f)),
+            SqlParam::Str(s) => ColumnData::String(Some(Cow::Borrowed(s.as_str()))),
+        }
+    }
+}
+
+/// Parse a JSON array of scalars into positional parameters
+fn parse_sql_params(params_json: &str) -> Result<Vec<SqlParam>, String> {
+    let value: Value = serde_json::from_str(params_json)
+        .map_err(|e| format!("Invalid parameter JSON: {}", e))?;
+    let items = match value {
+        Value::Array(items) => items,
+        _ => return Err("Parameter JSON must be an array".to_string()),
+    };
+    items
+        .into_iter()
+        .enumerate()
+        .map(|(i, item)| json_to_sql_param(i, item))
+        .collect()
+}
+
+/// Convert one JSON scalar to a parameter; `index` is zero-based (@P{index+1})
+fn json_to_sql_param(index: usize, item: Value) -> Result<SqlParam, String> {
+    json_value_to_sql_param(item).map_err(|e| format!("Parameter @P{} {}", index + 1, e))
+}
+
+/// Convert one JSON scalar to a parameter; the error completes "Parameter @x ..."
+fn json_value_to_sql_param(item: Value) -> Result<SqlParam, &'static str> {
+    match item {
+        Value::Null => Ok(SqlParam::Null),
+        Value::Bool(b) => Ok(SqlParam::Bool(b)),
+        Value::Number(n) => match n.as_i64() {
+            Some(v) => Ok(SqlParam::Int(v)),
+            None => n.as_f64().map(SqlParam::Float).ok_or("is not a representable number"),
+        },
+        Value::String(s) => Ok(SqlParam::Str(s)),
+        _ => Err("must be a string, number, bool or null"),
+    }
+}
+
+/// Execute a parameterized statement via sp_executesql (client.query), or
+/// through a prepared handle when `prepared` holds the connection's cache
+async fn execute_params_query(
+    client: &mut TibClient,
+    sql: &str,
+    params: &[SqlParam],
+    kind: StatementKind,
+    prepared: Option<&Mutex<PreparedCache>>,
+) -> Result<Option<String>, String> {
+    let result_sets = match prepared {
+        Some(cache) => execute_prepared(client, sql, params, cache).await?,
+        None => {
+            trace_at(TRACE_STATEMENTS, &format!("EXEC (params): {}", sql.trim()));
+            let args: Vec<&dyn ToSql> = params.iter().map(|p| p as &dyn ToSql).collect();
+            let stream = client
+                .query(sql, &args)
+                .await
+                .map_err(|e| sql_error("Query execution failed", e))?;
+            collect_result_sets(stream)
+                .await
+                .map_err(|e| sql_error("Failed to fetch results", e))?
+        }
+    };
+
+    if kind == StatementKind::Other {
+        trace("Parameterized statement completed OK");
+        return Ok(None);
+    }
+    let set = match select_result_set(result_sets) {
+        Some(set) => set,
+        None if kind == StatementKind::Exec => return Ok(None),
+        None => ResultSet::default(),
+    };
+    trace(&format!("Statement returned {} rows", set.rows.len()));
+    rows_to_json(&set).map(Some)
+}
+
+// Statements prepared on the global connection. Prepared handles belong to
+// one session, so the cache is emptied whenever another connection takes the
+// global slot, and its handles are unprepared before a connection is pooled.
+static GLOBAL_PREPARED: Lazy<Mutex<PreparedCache>> =
+    Lazy::new(|| Mutex::new(PreparedCache::default()));
+
+// Server error raised by sp_execute for a handle the session no longer has
+const UNKNOWN_PREPARED_HANDLE: u32 = 8179;
+
+/// SetPreparedCacheSize keeps up to `size` parameterized statements prepared
+/// on the global connection. ExecuteSqlParams, ExecuteSqlNamedParams and
+/// ExecuteSqlTyped then prepare a statement (sp_prepare) the first time its
+/// SQL text and parameter types are seen and run the handle (sp_execute)
+/// afterwards, evicting the least recently used statement when the cache is
+/// full. Handles are dropped when the connection is replaced or reconnects,
+/// and unprepared when it goes back to the pool. Zero (the default) sends
+/// every call through sp_executesql instead.
+#[unsafe(no_mangle)]
+pub extern "C" fn SetPreparedCacheSize(size: u32) {
+    lock_or_recover(&GLOBAL_PREPARED).capacity = size as usize;
+    trace(&format!("Prepared statement cache size: {}", size));
+}
+
+/// GetPreparedCacheStats returns the global connection's prepared-statement
+/// cache as JSON: `{"size":2,"capacity":64,"hits":118,"prepares":2}`, where
+/// `size` is the number of statements prepared on the current connection and
+/// `hits` and `prepares` count cached handle reuses and sp_prepare calls
+/// since the library was loaded. The caller is responsible for freeing the
+/// returned string with FreeCString.
+#[unsafe(no_mangle)]
+pub extern "C" fn GetPreparedCacheStats() -> *const c_char {
+    let stats = {
+        let cache = lock_or_recover(&GLOBAL_PREPARED);
+        serde_json::json!({
+            "size": cache.entries.len(),
+            "capacity": cache.capacity,
+            "hits": cache.hits,
+            "prepares": cache.prepares,
+        })
+    };
+    sql_result_to_c_string(to_json_string(&stats).map(Some))
+}
+
+/// Prepared statement handles of one connection, least recently used first
+#[derive(Default)]
+struct PreparedCache {
+    // (parameter declarations and SQL text, sp_prepare handle)
+    entries: Vec<(String, i32)>,
+    capacity: usize,
+    hits: u64,
+    prepares: u64,
+}
+
+impl PreparedCache {
+    /// The handle prepared for `key`, marking it most recently used
+    fn lookup(&mut self, key: &str) -> Option<i32> {
+        let index = self.entries.iter().position(|(k, _)| k == key)?;
+        let entry = self.entries.remove(index);
+        let handle = entry.1;
+        self.entries.push(entry);
+        self.hits += 1;
+        Some(handle)
+    }
+
+    /// Remember a newly prepared statement; returns the handles evicted to
+    /// stay within the capacity, which the caller must unprepare
+    fn insert(&mut self, key: String, handle: i32) -> Vec<i32> {
+        self.prepares += 1;
+        self.entries.retain(|(k, _)| *k != key);
+        self.entries.push((key, handle));
+        let excess = self.entries.len().saturating_sub(self.capacity.max(1));
+        self.entries.drain(..excess).map(|(_, h)| h).collect()
+    }
+
+    fn forget(&mut self, key: &str) {
+        self.entries.retain(|(k, _)| k != key);
+    }
+
+    /// Empty the cache, returning the handles that are still prepared
+    fn take_handles(&mut self) -> Vec<i32> {
+        self.entries.drain(..).map(|(_, h)| h).collect()
+    }
+}
+
+/// The parameter declarations tiberius sends for `params` through
+/// sp_executesql, e.g. "@P1 bigint, @P2 nvarchar(4000)", so a prepared
+/// statement sees the same types as an unprepared one.
+fn param_declarations(params: &[SqlParam]) -> String {
+    params
+        .iter()
+        .enumerate()
+        .map(|(i, param)| {
+            let declared = match param {
+                SqlParam::Null => "nvarchar(4000)",
+                SqlParam::Bool(_) => "bit",
+                SqlParam::Int32(_) => "int",
+                SqlParam::Int(_) => "bigint",
+                SqlParam::Float(_) => "float(53)",
+                // tiberius compares the UTF-8 length, not the UTF-16 one
+                SqlParam::Str(s) if s.len() <= 4000 => "nvarchar(4000)",
+                SqlParam::Str(_) => "nvarchar(max)",
+            };
+            format!("@P{} {}", i + 1, declared)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Run a parameterized statement through a handle from `cache`, preparing it
+/// first when the cache has none for its SQL text and parameter types.
+async fn execute_prepared(
+    client: &mut TibClient,
+    sql: &str,
+    params: &[SqlParam],
+    cache: &Mutex<PreparedCache>,
+) -> Result<Vec<ResultSet>, String> {
+    let declarations = param_declarations(params);
+    let key = format!("{}\n{}", declarations, sql);
+    let cached = lock_or_recover(cache).lookup(&key);
+    if let Some(handle) = cached {
+        trace_at(TRACE_STATEMENTS, &format!("EXEC (prepared {}): {}", handle, sql.trim()));
+        let result = execute_handle(client, handle, params).await;
+        let unknown = PENDING_SQL_ERROR
+            .with(Cell::get)
+            .is_some_and(|info| info.number == UNKNOWN_PREPARED_HANDLE);
+        if !(result.is_err() && unknown) {
+            return result;
+        }
+        trace(&format!("Prepared handle {} is gone - preparing again", handle));
+        PENDING_SQL_ERROR.with(|pending| pending.set(None));
+        lock_or_recover(cache).forget(&key);
+    }
+
+    let handle = prepare_statement(client, sql, declarations).await?;
+    let evicted = lock_or_recover(cache).insert(key, handle);
+    if !evicted.is_empty() {
+        unprepare_statements(client, &evicted).await;
+    }
+    trace_at(TRACE_STATEMENTS, &format!("EXEC (prepared {}): {}", handle, sql.trim()));
+    execute_handle(client, handle, params).await
+}
+
+// sp_prepare called through sp_executesql; the handle outlives the call,
+// since prepared statements belong to the session
+const PREPARE_SQL: &str =
+    "DECLARE @handle INT; EXEC sp_prepare @handle OUTPUT, @P1, @P2; SELECT @handle";
+
+/// Prepare `sql` with the given parameter declarations, returning its handle
+async fn prepare_statement(
+    client: &mut TibClient,
+    sql: &str,
+    declarations: String,
+) -> Result<i32, String> {
+    trace(&format!("Preparing: {}", sql.trim()));
+    let declarations = match declarations.is_empty() {
+        true => SqlParam::Null,
+        false => SqlParam::Str(declarations),
+    };
+    let results = client
+        .query(PREPARE_SQL, &[&declarations, &sql])
+        .await
+        .map_err(|e| sql_error("Query execution failed", e))?
+        .into_results()
+        .await
+        .map_err(|e| sql_error("Query execution failed", e))?;
+    // sp_prepare may return an empty metadata result set before the handle
+    results
+        .iter()
+        .flatten()
+        .last()
+        .and_then(|row| row.get::<i32, _>(0))
+        .ok_or_else(|| "sp_prepare returned no handle".to_string())
+}
+
+/// Run a prepared statement's handle with the parameter values
+async fn execute_handle(
+    client: &mut TibClient,
+    handle: i32,
+    params: &[SqlParam],
+) -> Result<Vec<ResultSet>, String> {
+    let placeholders = (1..=params.len() + 1)
+        .map(|i| format!("@P{}", i))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut args: Vec<&dyn ToSql> = vec![&handle];
+    args.extend(params.iter().map(|p| p as &dyn ToSql));
+    let stream = client
+        .query(format!("EXEC sp_execute {}", placeholders), &args)
+        .await
+        .map_err(|e| sql_error("Query execution failed", e))?;
+    collect_result_sets(stream)
+        .await
+        .map_err(|e| sql_error("Failed to fetch results", e))
+}
+
+/// Release prepared handles on the server. Failures are only traced: the
+/// handles go away with the session anyway.
+async fn unprepare_statements(client: &mut TibClient, handles: &[i32]) {
+    trace(&format!("Unpreparing {} statement(s)", handles.len()));
+    let batch: String = handles
+        .iter()
+        .map(|handle| format!("EXEC sp_unprepare {};", handle))
+        .collect();
+    let result = match client.simple_query(batch).await {
+        Ok(stream) => stream.into_results().await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        trace_at(TRACE_ERRORS, &format!("WARNING: failed to unprepare statements: {}", e));
+    }
+}
+
+/// ExecuteProc calls a stored procedure on the global connection.
+/// `params_json` is a JSON array; each element is either a plain value (positional
+/// input) or an object `{"name":"@x","value":1,"output":true,"type":"INT"}`.
+/// `name` binds by parameter name; output parameters require `type` (the SQL type
+/// to declare, e.g. `"NVARCHAR(50)"`) and use `value` as their initial value.
+/// Returns `{"resultSets":[...],"outputs":{"x":...},"returnValue":0}`.
+/// The caller is responsible for freeing the returned string with FreeCString.
+///
+/// # Safety
+/// This function is unsafe because it dereferences raw pointers from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ExecuteProc(
+    proc_name: *const c_char,
+    params_json: *const c_char,
+) -> *const c_char {
+    let name = match unsafe { c_str_arg(proc_name, "procedure name") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    let params_str = match unsafe { c_str_arg(params_json, "parameter JSON") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    if let Err(e) = validate_object_name(name, "procedure") {
+        return create_error_string(&format!("ERROR: {}", e));
+    }
+    let params = match parse_proc_params(params_str) {
+        Ok(p) if p.iter().any(|p| p.tvp) => {
+            return create_error_string("ERROR: Table-valued parameters require ExecuteProcWithTvp");
+        }
+        Ok(p) => p,
+        Err(e) => return create_error_string(&format!("ERROR: {}", e)),
+    };
+    let batch = build_proc_batch(name, &params);
+
+    // Check if database is connected
+    let client_storage = match DB_CLIENT.get() {
+        Some(cs) => cs,
+        None => return create_error_string("ERROR: Database not connected. Call ConnectDb first."),
+    };
+
+    let mut db_guard = lock_client(client_storage);
+    let client = match db_guard.as_mut() {
+        Some(c) => c,
+        None => return create_error_string(&format!("ERROR: {}", empty_slot_error())),
+    };
+
+    trace_at(TRACE_STATEMENTS, &format!("Procedure:  {}", name));
+    trace_at(TRACE_STATEMENTS, &format!("Params:     {}", params_str));
+    let values: Vec<&SqlParam> = params.iter().map(|p| &p.value).collect();
+    let result = run_with_retry(client, async |client: &mut TibClient| {
+        execute_proc_batch(client, &batch, &values).await
+    });
+    discard_if_abandoned(&mut db_guard, &result);
+    sql_result_to_c_string(result)
+}
+
+/// A stored procedure argument decoded from ExecuteProc's parameter JSON
+struct ProcParam {
+    // Parameter name without '@', for named binding and the outputs object
+    name: Option<String>,
+    value: SqlParam,
+    // Declared SQL type when this is an OUTPUT parameter
+    output_type: Option<String>,
+    // Placeholder for ExecuteProcWithTvp's table-valued parameter
+    tvp: bool,
+}
+
+/// Parse ExecuteProc's parameter array (plain values or parameter objects)
+fn parse_proc_params(params_json: &str) -> Result<Vec<ProcParam>, String> {
+    let value: Value = serde_json::from_str(params_json)
+        .map_err(|e| format!("Invalid parameter JSON: {}", e))?;
+    let items = match value {
+        Value::Array(items) => items,
+        _ => return Err("Parameter JSON must be an array".to_string()),
+    };
+    let mut params = Vec::with_capacity(items.len());
+    for (i, item) in items.into_iter().enumerate() {
+        let Value::Object(mut obj) = item else {
+            params.push(ProcParam {
+                name: None,
+                value: json_to_sql_param(i, item)?,
+                output_type: None,
+                tvp: false,
+            });
+            continue;
+        };
+        let name = match obj.remove("name") {
+            Some(Value::String(n)) => {
+                let n = n.strip_prefix('@').unwrap_or(&n).to_string();
+                if !is_plain_identifier(&n) {
+                    return Err(format!("Invalid parameter name '{}'", n));
+                }
+                Some(n)
+            }
+            None | Some(Value::Null) => None,
+            Some(_) => return Err(format!("Parameter {} name must be a string", i + 1)),
+        };
+        let output = matches!(obj.get("output"), Some(Value::Bool(true)));
+        let tvp = matches!(obj.get("tvp"), Some(Value::Bool(true)));
+        if tvp && output {
+            return Err(format!("Table-valued parameter {} cannot be an output", i + 1));
+        }
+        let output_type = match obj.remove("type") {
+            Some(Value::String(t)) if output => {
+                validate_sql_type(&t)?;
+                Some(t)
+            }
+            _ if output => {
+                return Err(format!("Output parameter {} requires a \"type\"", i + 1));
+            }
+            _ => None,
+        };
+        let value = json_to_sql_param(i, obj.remove("value").unwrap_or(Value::Null))?;
+        params.push(ProcParam {
+            name,
+            value,
+            output_type,
+            tvp,
+        });
+    }
+    Ok(params)
+}
+
+// Letters, digits and '_', not starting with a digit
+fn is_plain_identifier(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 128
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Accept up to four dot-separated parts, each plain (optionally #temp) or
+/// [bracketed]. Empty parts are allowed for the `db..object` form.
+fn validate_object_name(name: &str, what: &str) -> Result<(), String> {
+    let parts: Vec<&str> = name.split('.').collect();
+    let valid = parts.len() <= 4
+        && parts.last().is_some_and(|last| !last.is_empty())
+        && parts.iter().all(|part| match part.strip_prefix('[') {
+            Some(inner) => inner
+                .strip_suffix(']')
+                .is_some_and(|n| !n.is_empty() && !n.contains(']')),
+            None => {
+                let unprefixed = part.strip_prefix("##").or(part.strip_prefix('#')).unwrap_or(part);
+                part.is_empty() || is_plain_identifier(unprefixed)
+            }
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid {} name '{}'", what, name))
+    }
+}
+
+/// Accept type names such as INT, NVARCHAR(50), VARCHAR(MAX), DECIMAL(18, 2)
+fn validate_sql_type(sql_type: &str) -> Result<(), String> {
+    let (base, args) = match sql_type.split_once('(') {
+        Some((base, rest)) => (base.trim(), rest.strip_suffix(')')),
+        None => (sql_type.trim(), Some("")),
+    };
+    let valid = !base.is_empty()
+        && base.chars().all(|c| c.is_ascii_alphabetic() || c == ' ')
+        && args.is_some_and(|a| {
+            a.split(',').all(|n| {
+                let n = n.trim();
+                n.is_empty() || n.eq_ignore_ascii_case("max") || n.chars().all(|c| c.is_ascii_digit())
+            })
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid output parameter type '{}'", sql_type))
+    }
+}
+
+/// Build the batch that calls the procedure and selects its return code and
+/// output values as a final one-row result set:
+///   DECLARE @__rc INT; DECLARE @__o2 INT = @P2;
+///   EXEC @__rc = dbo.Proc @P1, @total = @__o2 OUTPUT;
+///   SELECT @__rc AS [__return_value], @__o2 AS [total];
+fn build_proc_batch(name: &str, params: &[ProcParam]) -> String {
+    let mut declares = String::from("DECLARE @__rc INT; ");
+    let mut args = Vec::with_capacity(params.len());
+    let mut selects = vec!["@__rc AS [__return_value]".to_string()];
+    for (i, p) in params.iter().enumerate() {
+        let n = i + 1;
+        let arg = match &p.output_type {
+            // Table-valued parameters are READONLY; the variable is filled by
+            // build_tvp_insert ahead of this batch
+            None if p.tvp => TVP_VARIABLE.to_string(),
+            Some(sql_type) => {
+                declares.push_str(&format!("DECLARE @__o{} {} = @P{}; ", n, sql_type, n));
+                let column = p.name.clone().unwrap_or_else(|| format!("P{}", n));
+                selects.push(format!("@__o{} AS [{}]", n, column));
+                format!("@__o{} OUTPUT", n)
+            }
+            None => format!("@P{}", n),
+        };
+        args.push(match &p.name {
+            Some(param_name) => format!("@{} = {}", param_name, arg),
+            None => arg,
+        });
+    }
+    format!(
+        "{}EXEC @__rc = {} {}; SELECT {};",
+        declares,
+        name,
+        args.join(", "),
+        selects.join(", ")
+    )
+}
+
+/// ExecuteProcWithTvp calls a stored procedure that takes a table-valued
+/// parameter. `tvp_type_name` is the user-defined table type (e.g.
+/// `dbo.IntList`) and `rows_json` a JSON array of row objects keyed by column
+/// name; missing keys insert NULL. `scalar_params_json` is the same parameter
+/// array ExecuteProc takes. The TVP is passed as the first argument unless
+/// that array holds a `{"name":"@ids","tvp":true}` placeholder marking its
+/// name and position. Returns the same JSON shape as ExecuteProc.
+///
+/// The TDS driver cannot send table-valued parameters, so the rows are sent
+/// as one NVARCHAR(MAX) JSON parameter and inserted into a table variable of
+/// that type with OPENJSON (database compatibility level 130 or later), in
+/// the same batch as the call. Row keys are matched case-insensitively to the
+/// type's columns, read from sys.table_types and sys.columns; identity,
+/// computed and rowversion columns cannot be filled.
+/// The caller is responsible for freeing the returned string with FreeCString.
+///
+/// # Safety
+/// This function is unsafe because it dereferences raw pointers from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ExecuteProcWithTvp(
+    proc_name: *const c_char,
+    tvp_type_name: *const c_char,
+    rows_json: *const c_char,
+    scalar_params_json: *const c_char,
+) -> *const c_char {
+    let name = match unsafe { c_str_arg(proc_name, "procedure name") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    let type_name = match unsafe { c_str_arg(tvp_type_name, "table type name") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    let rows_str = match unsafe { c_str_arg(rows_json, "row JSON") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    let params_str = match unsafe { c_str_arg(scalar_params_json, "parameter JSON") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    let prepared = validate_object_name(name, "procedure")
+        .and_then(|_| validate_object_name(type_name, "table type"))
+        .and_then(|_| {
+            let rows: Vec<serde_json::Map<String, Value>> = serde_json::from_str(rows_str)
+                .map_err(|e| format!("Row JSON must be an array of objects: {}", e))?;
+            let mut params = parse_proc_params(params_str)?;
+            match params.iter().filter(|p| p.tvp).count() {
+                0 => params.insert(
+                    0,
+                    ProcParam {
+                        name: None,
+                        value: SqlParam::Null,
+                        output_type: None,
+                        tvp: true,
+                    },
+                ),
+                1 => {}
+                _ => return Err("Only one table-valued parameter placeholder is allowed".to_string()),
+            }
+            let batch = build_proc_batch(name, &params);
+            let values: Vec<SqlParam> = params.into_iter().map(|p| p.value).collect();
+            Ok((rows, batch, values))
+        });
+    let (rows, batch, values) = match prepared {
+        Ok(p) => p,
+        Err(e) => return create_error_string(&format!("ERROR: {}", e)),
+    };
+
+    let client_storage = match DB_CLIENT.get() {
+        Some(cs) => cs,
+        None => return create_error_string("ERROR: Database not connected. Call ConnectDb first."),
+    };
+
+    let mut db_guard = lock_client(client_storage);
+    let client = match db_guard.as_mut() {
+        Some(c) => c,
+        None => return create_error_string(&format!("ERROR: {}", empty_slot_error())),
+    };
+
+    trace_at(TRACE_STATEMENTS, &format!("Procedure:  {}", name));
+    trace_at(TRACE_STATEMENTS, &format!("Params:     {}", params_str));
+    trace(&format!("TVP:        {} ({} rows)", type_name, rows.len()));
+    let result = run_with_retry(client, async |client: &mut TibClient| {
+        let columns = table_type_columns(client, type_name).await?;
+        let (insert, rows_json) = build_tvp_insert(type_name, &columns, &rows, values.len())?;
+        let rows_param = SqlParam::Str(rows_json);
+        let mut args: Vec<&SqlParam> = values.iter().collect();
+        args.push(&rows_param);
+        execute_proc_batch(client, &format!("{}{}", insert, batch), &args).await
+    });
+    discard_if_abandoned(&mut db_guard, &result);
+    sql_result_to_c_string(result)
+}
+
+// Table variable standing in for ExecuteProcWithTvp's table-valued parameter
+const TVP_VARIABLE: &str = "@__tvp";
+
+// Insertable columns of a user-defined table type and the type OPENJSON
+// reads each one as, in column order
+const TABLE_TYPE_COLUMNS_SQL: &str = "\
+SELECT c.name,
+    CASE
+        WHEN t.name IN (N'nvarchar', N'nchar') THEN t.name + N'('
+            + IIF(c.max_length = -1, N'max', CAST(c.max_length / 2 AS NVARCHAR(10))) + N')'
+        WHEN t.name IN (N'varchar', N'char', N'varbinary', N'binary') THEN t.name + N'('
+            + IIF(c.max_length = -1, N'max', CAST(c.max_length AS NVARCHAR(10))) + N')'
+        WHEN t.name IN (N'decimal', N'numeric') THEN t.name + N'('
+            + CAST(c.precision AS NVARCHAR(10)) + N',' + CAST(c.scale AS NVARCHAR(10)) + N')'
+        WHEN t.name IN (N'datetime2', N'time', N'datetimeoffset') THEN t.name + N'('
+            + CAST(c.scale AS NVARCHAR(10)) + N')'
+        ELSE t.name
+    END
+FROM sys.table_types AS tt
+JOIN sys.columns AS c ON c.object_id = tt.type_table_object_id
+JOIN sys.types AS t ON t.user_type_id = c.system_type_id
+WHERE tt.user_type_id = TYPE_ID(@P1)
+  AND c.is_identity = 0 AND c.is_computed = 0 AND t.name <> N'timestamp'
+ORDER BY c.column_id";
+
+/// Read the fillable columns of a table type as (name, OPENJSON type) pairs
+async fn table_type_columns(
+    client: &mut TibClient,
+    type_name: &str,
+) -> Result<Vec<(String, String)>, String> {
+    let rows = client
+        .query(TABLE_TYPE_COLUMNS_SQL, &[&type_name])
+        .await
+        .map_err(|e| sql_error("Failed to read table type columns", e))?
+        .into_first_result()
+        .await
+        .map_err(|e| sql_error("Failed to read table type columns", e))?;
+    let columns: Vec<(String, String)> = rows
+        .iter()
+        .filter_map(|row| {
+            let name = row.get::<&str, _>(0)?;
+            Some((name.to_string(), row.get::<&str, _>(1)?.to_string()))
+        })
+        .collect();
+    if columns.is_empty() {
+        return Err(format!(
+            "Table type '{}' was not found or has no insertable columns",
+            type_name
+        ));
+    }
+    Ok(columns)
+}
+
+/// Build the statements that declare the TVP table variable and fill it from
+/// the JSON parameter bound after the `bound` procedure parameters:
+///   DECLARE @__tvp dbo.IntList;
+///   INSERT INTO @__tvp ([Id]) SELECT [Id] FROM OPENJSON(@P3) WITH ([Id] int '$."Id"');
+/// Returns the statements and the rows as JSON, re-keyed to the type's column
+/// names so the OPENJSON paths match whatever case the caller used.
+fn build_tvp_insert(
+    type_name: &str,
+    columns: &[(String, String)],
+    rows: &[serde_json::Map<String, Value>],
+    bound: usize,
+) -> Result<(String, String), String> {
+    let mut keyed = Vec::with_capacity(rows.len());
+    for (i, row) in rows.iter().enumerate() {
+        let mut entry = serde_json::Map::with_capacity(row.len());
+        for (key, value) in row {
+            let Some((column, _)) = columns.iter().find(|(c, _)| c.eq_ignore_ascii_case(key)) else {
+                return Err(format!(
+                    "Row {} has '{}', which is not a column of {}",
+                    i + 1,
+                    key,
+                    type_name
+                ));
+            };
+            if value.is_array() || value.is_object() {
+                return Err(format!(
+                    "Row {} column '{}' must be a string, number, bool or null",
+                    i + 1,
+                    key
+                ));
+            }
+            entry.insert(column.clone(), value.clone());
+        }
+        keyed.push(Value::Object(entry));
+    }
+
+    let mut sql = format!("DECLARE {} {}; ", TVP_VARIABLE, type_name);
+    if !rows.is_empty() {
+        let quoted = |c: &str| format!("[{}]", c.replace(']', "]]"));
+        let column_list = columns.iter().map(|(c, _)| quoted(c)).collect::<Vec<_>>().join(", ");
+        let schema = columns
+            .iter()
+            .map(|(c, sql_type)| {
+                let path = format!("$.\"{}\"", c.replace('\\', "\\\\").replace('"', "\\\""));
+                format!("{} {} '{}'", quoted(c), sql_type, path.replace('\'', "''"))
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        sql.push_str(&format!(
+            "INSERT INTO {} ({}) SELECT {} FROM OPENJSON(@P{}) WITH ({}); ",
+            TVP_VARIABLE,
+            column_list,
+            column_list,
+            bound + 1,
+            schema
+        ));
+    }
+    let json = to_json_string(&Value::Array(keyed))?;
+    Ok((sql, json))
+}
+
+/// Run the procedure batch and split the trailing return/output row off the
+/// procedure's own result sets.
+async fn execute_proc_batch(
+    client: &mut TibClient,
+    batch: &str,
+    params: &[&SqlParam],
+) -> Result<Option<String>, String> {
+    trace_at(TRACE_STATEMENTS, &format!("EXEC (proc): {}", batch));
+    let args: Vec<&dyn ToSql> = params.iter().map(|p| *p as &dyn ToSql).collect();
+    let mut result_sets = client
+        .query(batch, &args)
+        .await
+        .map_err(|e| sql_error("Query execution failed", e))?
+        .into_results()
+        .await
+        .map_err(|e| sql_error("Failed to fetch results", e))?;
+
+    let mut outputs = result_sets
+        .pop()
+        .map(|rows| rows_to_maps(&rows))
+        .and_then(|mut maps| maps.pop())
+        .unwrap_or_default();
+    let return_value = outputs.remove("__return_value").unwrap_or(Value::Null);
+    let sets: Vec<Vec<serde_json::Map<String, Value>>> =
+        result_sets.iter().map(|rows| rows_to_maps(rows)).collect();
+
+    let response = serde_json::json!({
+        "resultSets": sets,
+        "outputs": outputs,
+        "returnValue": return_value,
+    });
+    to_json_string(&response).map(Some)
+}
+
+/// BulkInsert streams rows into an existing table using TDS bulk copy, which is
+/// much faster than individual INSERTs. `rows_json` is a JSON array of objects
+/// keyed by column name; missing keys insert NULL, and identity, computed and
+/// rowversion columns are skipped. Values are converted to each column's type
+/// (dates as ISO-8601 strings, binary as base64 or "0x..." hex).
+/// Returns the number of rows copied, or -1 on failure (see GetLastErrorMessage).
+///
+/// # Safety
+/// This function is unsafe because it dereferences raw pointers from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn BulkInsert(table: *const c_char, rows_json: *const c_char) -> i64 {
+    let table = match unsafe { c_str_arg(table, "table name") } {
+        Ok(s) => s,
+        Err(e) => {
+            free_error_string(e);
+            return -1;
+        }
+    };
+    let rows_str = match unsafe { c_str_arg(rows_json, "row JSON") } {
+        Ok(s) => s,
+        Err(e) => {
+            free_error_string(e);
+            return -1;
+        }
+    };
+    let result = validate_object_name(table, "table").and_then(|_| {
+        let rows: Vec<serde_json::Map<String, Value>> = serde_json::from_str(rows_str)
+            .map_err(|e| format!("Row JSON must be an array of objects: {}", e))?;
+
+        let client_storage = DB_CLIENT
+            .get()
+            .ok_or("Database not connected. Call ConnectDb first.")?;
+        let mut db_guard = lock_client(client_storage);
+        let client = db_guard
+            .as_mut()
+            .ok_or_else(empty_slot_error)?;
+
+        trace(&format!("Bulk insert: {} rows into {}", rows.len(), table));
+        let result = run_statement(bulk_insert_rows(client, table, &rows));
+        discard_if_abandoned(&mut db_guard, &result);
+        result
+    });
+
+    match result {
+        Ok(count) => {
+            record_success();
+            trace(&format!("Bulk insert copied {} rows", count));
+            count as i64
+        }
+        Err(e) => {
+            set_last_error(&format!("ERROR: {}", e));
+            -1
+        }
+    }
+}
+
+/// A bulk-insert target column as described by sys.columns
+struct BulkColumn {
+    name: String,
+    type_name: String,
+    scale: u8,
+}
+
+/// Look up the writable columns of `table`, in table order
+async fn bulk_target_columns(client: &mut TibClient, table: &str) -> Result<Vec<BulkColumn>, String> {
+    // Temp tables live in tempdb and must be resolved there
+    let object_part = table.rsplit('.').next().unwrap_or(table);
+    let is_temp = object_part.trim_start_matches('[').starts_with('#');
+    let (catalog, object) = if is_temp {
+        ("tempdb.", format!("tempdb..{}", object_part))
+    } else {
+        ("", table.to_string())
+    };
+    let sql = format!(
+        "SELECT c.name, t.name, c.scale FROM {0}sys.columns c \
+         JOIN {0}sys.types t ON t.user_type_id = c.system_type_id \
+         WHERE c.object_id = OBJECT_ID(@P1) AND c.is_identity = 0 AND c.is_computed = 0 \
+         AND t.name <> 'timestamp' ORDER BY c.column_id",
+        catalog
+    );
+    let rows = client
+        .query(sql, &[&object.as_str()])
+        .await
+        .map_err(|e| sql_error("Failed to read table columns", e))?
+        .into_first_result()
+        .await
+        .map_err(|e| sql_error("Failed to read table columns", e))?;
+    if rows.is_empty() {
+        return Err(format!("Table '{}' not found or has no writable columns", table));
+    }
+    Ok(rows
+        .iter()
+        .map(|r| BulkColumn {
+            name: r.get::<&str, _>(0).unwrap_or_default().to_string(),
+            type_name: r.get::<&str, _>(1).unwrap_or_default().to_lowercase(),
+            scale: r.get::<u8, _>(2).unwrap_or_default(),
+        })
+        .collect())
+}
+
+/// Convert the JSON rows for the target columns and stream them with bulk_insert
+async fn bulk_insert_rows(
+    client: &mut TibClient,
+    table: &str,
+    rows: &[serde_json::Map<String, Value>],
+) -> Result<u64, String> {
+    let columns = bulk_target_columns(client, table).await?;
+
+    // Convert everything up front so a bad value fails before any data is sent
+    let mut token_rows = Vec::with_capacity(rows.len());
+    for (i, row) in rows.iter().enumerate() {
+        if let Some(unknown) = row
+            .keys()
+            .find(|k| !columns.iter().any(|c| c.name.eq_ignore_ascii_case(k)))
+        {
+            return Err(format!("Row {}: unknown column '{}'", i + 1, unknown));
+        }
+        let mut token_row = TokenRow::with_capacity(columns.len());
+        for column in &columns {
+            let value = row
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(&column.name))
+                .map_or(&Value::Null, |(_, v)| v);
+            let data = json_to_column_data(column, value)
+                .map_err(|e| format!("Row {}, column '{}': {}", i + 1, column.name, e))?;
+            token_row.push(data);
+        }
+        token_rows.push(token_row);
+    }
+
+    let mut request = client
+        .bulk_insert(table)
+        .await
+        .map_err(|e| sql_error("Failed to start bulk insert", e))?;
+    for token_row in token_rows {
+        request
+            .send(token_row)
+            .await
+            .map_err(|e| sql_error("Bulk insert failed", e))?;
+    }
+    let result = request
+        .finalize()
+        .await
+        .map_err(|e| sql_error("Bulk insert failed", e))?;
+    Ok(result.total())
+}
+
+/// Convert a JSON value to the exact ColumnData variant the bulk-copy encoder
+/// expects for the column's SQL type.
+fn json_to_column_data(column: &BulkColumn, value: &Value) -> Result<ColumnData<'static>, String> {
+    let data = match column.type_name.as_str() {
+        "bit" => ColumnData::Bit(match value {
+            Value::Null => None,
+            Value::Bool(b) => Some(*b),
+            Value::Number(n) => Some(n.as_i64() != Some(0)),
+            _ => return Err("expected a bool".to_string()),
+        }),
+        "tinyint" => ColumnData::U8(json_int(value, "TINYINT")?),
+        "smallint" => ColumnData::I16(json_int(value, "SMALLINT")?),
+        "int" => ColumnData::I32(json_int(value, "INT")?),
+        "bigint" => ColumnData::I64(json_int(value, "BIGINT")?),
+        "real" => ColumnData::F32(json_float(value)?.map(|f| f as f32)),
+        "float" => ColumnData::F64(json_float(value)?),
+        "decimal" | "numeric" => ColumnData::Numeric(match value {
+            Value::Null => None,
+            Value::Number(n) => Some(parse_numeric(&n.to_string(), column.scale)?),
+            Value::String(s) => Some(parse_numeric(s, column.scale)?),
+            _ => return Err("expected a number or numeric string".to_string()),
+        }),
+        "char" | "varchar" | "nchar" | "nvarchar" | "text" | "ntext" => {
+            ColumnData::String(match value {
+                Value::Null => None,
+                Value::String(s) => Some(Cow::Owned(s.clone())),
+                Value::Number(_) | Value::Bool(_) => Some(Cow::Owned(value.to_string())),
+                _ => return Err("expected a string".to_string()),
+            })
+        }
+        "uniqueidentifier" => ColumnData::Guid(match json_str(value)? {
+            Some(s) => Some(Uuid::parse_str(s).map_err(|e| format!("invalid GUID: {}", e))?),
+            None => None,
+        }),
+        "binary" | "varbinary" | "image" => ColumnData::Binary(match json_str(value)? {
+            Some(s) => Some(Cow::Owned(parse_binary(s)?)),
+            None => None,
+        }),
+        "date" => match json_str(value)? {
+            Some(s) => NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .map_err(|e| format!("invalid date '{}': {}", s, e))?
+                .into_sql(),
+            None => ColumnData::Date(None),
+        },
+        "time" => ColumnData::Time(match json_str(value)? {
+            Some(s) => {
+                let t = NaiveTime::parse_from_str(s, "%H:%M:%S%.f")
+                    .map_err(|e| format!("invalid time '{}': {}", s, e))?;
+                let nanos = t.num_seconds_from_midnight() as u64 * 1_000_000_000 + t.nanosecond() as u64;
+                Some(tiberius::time::Time::new(
+                    nanos / 10u64.pow(9 - column.scale.min(7) as u32),
+                    column.scale,
+                ))
+            }
+            None => None,
+        }),
+        "datetime2" => match json_str(value)? {
+            Some(s) => parse_json_datetime(s)?.into_sql(),
+            None => ColumnData::DateTime2(None),
+        },
+        "datetime" => ColumnData::DateTime(match json_str(value)? {
+            Some(s) => {
+                let dt = parse_json_datetime(s)?;
+                let days = (dt.date() - base_1900()).num_days() as i32;
+                let t = dt.time();
+                let nanos = t.num_seconds_from_midnight() as u64 * 1_000_000_000 + t.nanosecond() as u64;
+                // DATETIME stores 1/300 second ticks
+                Some(tiberius::time::DateTime::new(days, (nanos * 3 / 10_000_000) as u32))
+            }
+            None => None,
+        }),
+        "smalldatetime" => ColumnData::SmallDateTime(match json_str(value)? {
+            Some(s) => {
+                let dt = parse_json_datetime(s)?;
+                let days = u16::try_from((dt.date() - base_1900()).num_days())
+                    .map_err(|_| "out of range for SMALLDATETIME")?;
+                let minutes = (dt.time().num_seconds_from_midnight() / 60) as u16;
+                Some(tiberius::time::SmallDateTime::new(days, minutes))
+            }
+            None => None,
+        }),
+        other => {
+            return Err(format!("type '{}' is not supported by BulkInsert", other));
+        }
+    };
+    Ok(data)
+}
+
+fn json_int<T: TryFrom<i64>>(value: &Value, sql_type: &str) -> Result<Option<T>, String> {
+    let n = match value {
+        Value::Null => return Ok(None),
+        Value::Number(n) => n.as_i64().ok_or("expected an integer")?,
+        Value::Bool(b) => *b as i64,
+        _ => return Err("expected an integer".to_string()),
+    };
+    T::try_from(n)
+        .map(Some)
+        .map_err(|_| format!("{} is out of range for {}", n, sql_type))
+}
+
+fn json_float(value: &Value) -> Result<Option<f64>, String> {
+    match value {
+        Value::Null => Ok(None),
+        Value::Number(n) => n.as_f64().map(Some).ok_or_else(|| "expected a number".to_string()),
+        _ => Err("expected a number".to_string()),
+    }
+}
+
+fn json_str(value: &Value) -> Result<Option<&str>, String> {
+    match value {
+        Value::Null => Ok(None),
+        Value::String(s) => Ok(Some(s.as_str())),
+        _ => Err("expected a string".to_string()),
+    }
+}
+
+fn base_1900() -> NaiveDate {
+    NaiveDate::from_ymd_opt(1900, 1, 1).unwrap()
+}
+
+/// Parse ISO-8601 date-times as produced by the JSON output ("T" or space separator)
+fn parse_json_datetime(s: &str) -> Result<NaiveDateTime, String> {
+    ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(s, fmt).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(|| format!("invalid date-time '{}'", s))
+}
+
+/// Parse a decimal string exactly at the column's scale
+fn parse_numeric(s: &str, scale: u8) -> Result<Numeric, String> {
+    let s = s.trim();
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let (int_part, frac_part) = digits.split_once('.').unwrap_or((digits, ""));
+    let frac_part = frac_part.trim_end_matches('0');
+    if int_part.is_empty() && frac_part.is_empty()
+        || !int_part.chars().chain(frac_part.chars()).all(|c| c.is_ascii_digit())
+    {
+        return Err(format!("invalid decimal '{}'", s));
+    }
+    if frac_part.len() > scale as usize {
+        return Err(format!("'{}' has more than {} decimal places", s, scale));
+    }
+    let scaled = format!("{}{:0<width$}", int_part, frac_part, width = scale as usize);
+    let value: i128 = scaled
+        .parse()
+        .map_err(|_| format!("decimal '{}' is out of range", s))?;
+    Ok(Numeric::new_with_scale(if negative { -value } else { value }, scale))
+}
+
+/// Decode binary input in either output format: "0x..." hex or base64
+fn parse_binary(s: &str) -> Result<Vec<u8>, String> {
+    match s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        Some(hex) => {
+            if !hex.is_ascii() || hex.len() % 2 != 0 {
+                return Err(format!("invalid hex '{}'", s));
+            }
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<Result<_, _>>()
+                .map_err(|_| format!("invalid hex '{}'", s))
+        }
+        None => BASE64_STANDARD
+            .decode(s)
+            .map_err(|e| format!("invalid base64: {}", e)),
+    }
+}
+
+/// Convert an execution result into the C return convention:
+/// JSON string for results, null for success without results, error string on failure.
+fn sql_result_to_c_string(result: Result<Option<String>, String>) -> *const c_char {
+    match result {
+        Ok(Some(json)) => {
+            record_success();
+            // Return JSON results
+            match CString::new(json) {
+                Ok(c_string) => hand_out_c_string(c_string),
+                Err(_) => create_error_string("ERROR: Failed to create C string from JSON"),
+            }
+        }
+        Ok(None) => {
+            record_success();
+            std::ptr::null() // Success for non-SELECT
+        }
+        Err(e) => create_error_string(&format!("ERROR: {}", e)),
+    }
+}
+
+/// ConnectDbHandle opens an independent connection and returns an opaque
+/// handle for use with ExecuteSqlOnHandle/DisconnectDbHandle. Unlike ConnectDb,
+/// any number of handles can be open at once and used from different threads.
+/// Returns 0 on failure; call GetLastErrorMessage for the reason.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ConnectDbHandle(conn_str: *const c_char) -> u64 {
+    let conn_string = match unsafe { c_str_arg(conn_str, "connection string") } {
+        Ok(s) => s,
+        Err(e) => {
+            free_error_string(e);
+            return 0;
+        }
+    };
+    let options = match parse_connection_string(conn_string) {
+        Ok(opts) => opts,
+        Err(e) => {
+            set_last_error(&format!("ERROR: Failed to parse connection string: {}", e));
+            return 0;
+        }
+    };
+    let pooling = options.pooling;
+    let wrap_selects = options.wrap_selects();
+    match checkout_connection(conn_string, options) {
+        Ok(client) => {
+            let handle = NEXT_HANDLE.fetch_add(1, Ordering::Relaxed);
+            lock_or_recover(get_handles()).insert(
+                handle,
+                HandleConn {
+                    client: Arc::new(Mutex::new(Some(client))),
+                    conn_key: pooling.then(|| conn_string.to_string()),
+                    wrap_selects,
+                },
+            );
+            trace(&format!("Opened connection handle {}", handle));
+            record_success();
+            handle
+        }
+        Err(e) => {
+            set_last_error(&format!("ERROR: {}", e));
+            0
+        }
+    }
+}
+
+/// ExecuteSqlOnHandle works like ExecuteSql but runs on a connection opened
+/// with ConnectDbHandle. Calls on different handles do not block each other.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ExecuteSqlOnHandle(handle: u64, input_sql: *const c_char) -> *const c_char {
+    let sql = match unsafe { c_str_arg(input_sql, "SQL string") } {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    // Only hold the handle map lock long enough to clone the client slot
+    let (client_storage, wrap_selects) = match lock_or_recover(get_handles()).get(&handle) {
+        Some(conn) => (Arc::clone(&conn.client), conn.wrap_selects),
+        None => return create_error_string(&format!("ERROR: Invalid connection handle {}", handle)),
+    };
+    let mut db_guard = lock_client(&client_storage);
+    let client = match db_guard.as_mut() {
+        Some(c) => c,
+        None => return create_error_string(&format!("ERROR: Connection handle {} is closed", handle)),
+    };
+    let result = execute_sql_on(client, sql, wrap_selects);
+    discard_if_abandoned(&mut db_guard, &result);
+    sql_result_to_c_string(result)
+}
+
+/// DisconnectDbHandle releases a handle and returns its connection to the pool.
+/// Unknown or already-released handles are ignored.
+#[unsafe(no_mangle)]
+pub extern "C" fn DisconnectDbHandle(handle: u64) {
+    let conn = lock_or_recover(get_handles()).remove(&handle);
+    if let Some(conn) = conn {
+        // Waits for any in-flight call on this handle to finish
+        let client = lock_client(&conn.client).take();
+        if let (Some(client), Some(key)) = (client, conn.conn_key) {
+            return_to_pool(key, client);
+        }
+        trace(&format!("Closed connection handle {}", handle));
+    }
+}
+
+/// BeginQuery starts a SELECT on the global connection and returns a handle
+/// for reading its rows in chunks with FetchRows, so large results never have
+/// to be held in memory at once. Only the first result set is returned.
+/// The global connection is busy until the stream has been read to the end or
+/// closed with CloseQuery; other calls on it meanwhile fail with "connection
+/// busy: query stream open". SetQueryTimeout bounds each wait for the server
+/// and CancelQuery aborts the stream; either closes the connection, as for
+/// ExecuteSql. Returns 0 on failure; call GetLastErrorMessage for the reason.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn BeginQuery(input_sql: *const c_char) -> u64 {
+    let sql = match unsafe { c_str_arg(input_sql, "SQL string") } {
+        Ok(s) => s.to_string(),
+        Err(e) => {
+            free_error_string(e);
+            return 0;
+        }
+    };
+    let client_storage = match DB_CLIENT.get() {
+        Some(cs) => Arc::clone(cs),
+        None => {
+            set_last_error("ERROR: Database not connected. Call ConnectDb first.");
+            return 0;
+        }
+    };
+    let handle = NEXT_HANDLE.fetch_add(1, Ordering::Relaxed);
+    let mut client = {
+        let mut slot = lock_client(&client_storage);
+        let Some(client) = slot.take() else {
+            set_last_error(&format!("ERROR: {}", empty_slot_error()));
+            return 0;
+        };
+        GLOBAL_STREAM.store(handle, Ordering::Relaxed);
+        client
+    };
+
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER_ROWS);
+    lock_or_recover(get_cursors()).insert(handle, rx);
+    trace_at(TRACE_STATEMENTS, &format!("EXEC (stream {}): {}", handle, sql.trim()));
+    let timeout_secs = QUERY_TIMEOUT_SECS.load(Ordering::Relaxed);
+    let cancelled = get_cancel_notify().notified();
+
+    get_runtime().spawn(async move {
+        let streamed = catch_driver_panic(stream_rows(&mut client, &sql, &tx, timeout_secs));
+        let result = tokio::select! {
+            result = streamed => result,
+            _ = cancelled => {
+                trace_at(TRACE_ERRORS, &format!("Query stream {} cancelled", handle));
+                Err(QUERY_CANCELLED.to_string())
+            }
+        };
+        // Hand the connection back before the end of the stream is signalled,
+        // so the caller can use it as soon as FetchRows reports done. Not if
+        // the stream was abandoned mid-response, or DisconnectDb/ConnectDb ran
+        // in the meantime.
+        {
+            let mut client = Some(client);
+            discard_if_abandoned(&mut client, &result);
+            let mut slot = lock_client(&client_storage);
+            let connected = lock_or_recover(get_conn_key()).is_some();
+            if GLOBAL_STREAM
+                .compare_exchange(handle, 0, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+                && connected
+                && slot.is_none()
+            {
+                *slot = client;
+            }
+        }
+        let end = result.map(|()| None).map_err(|e| (e, PENDING_SQL_ERROR.with(Cell::take)));
+        let _ = tx.send(end).await;
+    });
+    record_success();
+    handle
+}
+
+/// Send the rows of the first result set to a cursor's channel. The stream is
+/// read to the end even after the cursor is closed, so the connection is left
+/// ready for the next statement. `timeout_secs` bounds each wait for the
+/// server, not the time the caller takes to fetch the rows.
+async fn stream_rows(
+    client: &mut TibClient,
+    sql: &str,
+    tx: &mpsc::Sender<StreamItem>,
+    timeout_secs: u32,
+) -> Result<(), String> {
+    let mut stream = server_wait(timeout_secs, async {
+        client
+            .simple_query(sql)
+            .await
+            .map_err(|e| sql_error("Query execution failed", e))
+    })
+    .await?;
+    let mut first_result = None;
+    let mut receiver_open = true;
+    while let Some(item) = server_wait(timeout_secs, async {
+        stream
+            .try_next()
+            .await
+            .map_err(|e| sql_error("Failed to fetch results", e))
+    })
+    .await?
+    {
+        if let QueryItem::Row(row) = item
+            && receiver_open
+            && *first_result.get_or_insert(row.result_index()) == row.result_index()
+        {
+            receiver_open = tx.send(Ok(Some(row))).await.is_ok();
+        }
+    }
+    Ok(())
+}
+
+/// Wait for the server for at most `secs` seconds (0 = no limit)
+async fn server_wait<T>(secs: u32, wait: impl Future<Output = Result<T, String>>) -> Result<T, String> {
+    if secs == 0 {
+        return wait.await;
+    }
+    match tokio::time::timeout(Duration::from_secs(secs as u64), wait).await {
+        Ok(result) => result,
+        Err(_) => {
+            trace_at(TRACE_ERRORS, &format!("Query timed out after {}s", secs));
+            Err(QUERY_TIMED_OUT.to_string())
+        }
+    }
+}
+
+/// FetchRows returns up to `max_rows` further rows of a BeginQuery stream as
+/// `{"rows":[{...}],"done":false}`. Once `done` is true the stream is finished
+/// and the handle is released. Returns an error string for an unknown handle
+/// or if the query failed. The caller is responsible for freeing the returned
+/// string with FreeCString.
+#[unsafe(no_mangle)]
+pub extern "C" fn FetchRows(handle: u64, max_rows: u32) -> *const c_char {
+    // Taken out of the map so other cursors are not blocked while waiting
+    let Some(mut rx) = lock_or_recover(get_cursors()).remove(&handle) else {
+        return create_error_string(&format!("ERROR: Unknown query handle {}", handle));
+    };
+    let limit = max_rows.max(1) as usize;
+    let mut rows = Vec::with_capacity(limit.min(STREAM_BUFFER_ROWS));
+    let mut done = false;
+    let mut failure = None;
+    get_runtime().block_on(async {
+        while rows.len() < limit {
+            match rx.recv().await {
+                Some(Ok(Some(row))) => rows.push(row),
+                Some(Ok(None)) => {
+                    done = true;
+                    break;
+                }
+                Some(Err(e)) => {
+                    failure = Some(e);
+                    break;
+                }
+                None => {
+                    failure = Some(("query stream ended unexpectedly".to_string(), None));
+                    break;
+                }
+            }
+        }
+    });
+
+    if let Some((msg, sql_info)) = failure {
+        trace_at(TRACE_ERRORS, &format!("Query stream {} failed", handle));
+        PENDING_SQL_ERROR.with(|pending| pending.set(sql_info));
+        return create_error_string(&format!("ERROR: {}", msg));
+    }
+    if done {
+        trace(&format!("Query stream {} finished", handle));
+    } else {
+        lock_or_recover(get_cursors()).insert(handle, rx);
+    }
+    let chunk = serde_json::json!({ "rows": rows_to_maps(&rows), "done": done });
+    sql_result_to_c_string(to_json_string(&chunk).map(Some))
+}
+
+/// CloseQuery abandons a BeginQuery stream before it has been read to the
+/// end. The remaining rows are discarded in the background, after which the
+/// global connection is usable again. Unknown or finished handles are ignored.
+#[unsafe(no_mangle)]
+pub extern "C" fn CloseQuery(handle: u64) {
+    if lock_or_recover(get_cursors()).remove(&handle).is_some() {
+        trace(&format!("Closed query stream {}", handle));
+    }
+}
+
+/// GetLastErrorMessage returns the most recent error produced on the calling
+/// thread (e.g. why ConnectDbHandle returned 0), or null if there is none.
+/// The caller is responsible for freeing the returned string with FreeCString.
+#[unsafe(no_mangle)]
+pub extern "C" fn GetLastErrorMessage() -> *const c_char {
+    LAST_ERROR.with(|last| match last.borrow().as_deref() {
+        Some(msg) => CString::new(msg).map_or(std::ptr::null(), hand_out_c_string),
+        None => std::ptr::null(),
+    })
+}
+
+/// GetLastErrorCode returns the SQL Server error number of the most recent call
+/// on the calling thread (e.g. 1205 for a deadlock victim, 2627 for a primary key
+/// violation), 0 if that call succeeded, or -1 if it failed without a server
+/// error (connection failure, query timeout, cancellation, invalid arguments).
+#[unsafe(no_mangle)]
+pub extern "C" fn GetLastErrorCode() -> i32 {
+    LAST_ERROR_CODE.with(Cell::get)
+}
+
+/// GetLastResultLength returns the length in bytes, excluding the terminating
+/// NUL, of the most recent string returned on the calling thread (a result or
+/// an error; UTF-16 strings from ExecuteSqlW count 2 bytes per code unit), or
+/// 0 if the most recent call succeeded with a null return.
+/// Lets callers copy the exact bytes without scanning for the terminator.
+#[unsafe(no_mangle)]
+pub extern "C" fn GetLastResultLength() -> usize {
+    LAST_RESULT_LENGTH.with(Cell::get)
+}
+
+/// FreeCString frees the memory for a C string allocated by Rust.
+/// This MUST be called by the client code for any returned strings.
+/// Pointers that were not handed out by this library, or were already freed,
+/// are ignored with a trace warning instead of corrupting the heap.
+///
+/// # Safety
+/// This function is unsafe because it reconstructs a CString from a raw pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn FreeCString(s: *mut c_char) {
+    if !s.is_null() && !unsafe { reclaim_c_string(s) } {
+        trace_at(
+            TRACE_ERRORS,
+            &format!("WARNING: FreeCString ignored unknown or already freed pointer {:p}", s),
+        );
+    }
+}
+
+// Addresses of strings handed to the caller and not yet freed, so FreeCString
+// can reject double frees and foreign pointers
+static OUTSTANDING_STRINGS: Lazy<Mutex<HashSet<usize>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// Transfer ownership of a string to the caller, who frees it with FreeCString
+fn hand_out_c_string(c_string: CString) -> *const c_char {
+    LAST_RESULT_LENGTH.with(|len| len.set(c_string.as_bytes().len()));
+    let ptr = c_string.into_raw();
+    lock_or_recover(&OUTSTANDING_STRINGS).insert(ptr as usize);
+    ptr
+}
+
+// Free a string produced by hand_out_c_string. Returns false, without
+// touching the memory, if the pointer is not outstanding.
+unsafe fn reclaim_c_string(ptr: *mut c_char) -> bool {
+    if !lock_or_recover(&OUTSTANDING_STRINGS).remove(&(ptr as usize)) {
+        return false;
+    }
+    drop(unsafe { CString::from_raw(ptr) });
+    true
+}
+
+/// ExecuteSqlW is ExecuteSql for UTF-16 hosts (e.g. .NET with
+/// `CharSet.Unicode`): it takes a NUL-terminated UTF-16 SQL string and returns
+/// the same JSON or error text as a NUL-terminated UTF-16 string (null for
+/// statements without rows). The caller is responsible for freeing the
+/// returned string with FreeWString, not FreeCString.
+///
+/// # Safety
+/// This function is unsafe because it dereferences a raw pointer from C.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ExecuteSqlW(input_sql: *const u16) -> *const u16 {
+    if input_sql.is_null() {
+        return hand_out_wide_string(&error_text("ERROR: SQL input is null"));
+    }
+    // SAFETY: the caller passes a NUL-terminated UTF-16 string
+    let units = unsafe {
+        let len = (0..).take_while(|&i| *input_sql.add(i) != 0).count();
+        std::slice::from_raw_parts(input_sql, len)
+    };
+    let Ok(sql) = String::from_utf16(units) else {
+        return hand_out_wide_string(&error_text("ERROR: Invalid UTF-16 in SQL string"));
+    };
+
+    match execute_global(&sql) {
+        Ok(Some(json)) => {
+            record_success();
+            hand_out_wide_string(&json)
+        }
+        Ok(None) => {
+            record_success();
+            std::ptr::null()
+        }
+        Err(e) => hand_out_wide_string(&error_text(&format!("ERROR: {}", e))),
+    }
+}
+
+/// FreeWString frees a UTF-16 string returned by ExecuteSqlW. Pointers that
+/// were not handed out by this library, or were already freed, are ignored
+/// with a trace warning.
+///
+/// # Safety
+/// This function is unsafe because it reconstructs a boxed slice from a raw pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn FreeWString(s: *mut u16) {
+    if s.is_null() {
+        return;
+    }
+    let Some(len) = lock_or_recover(&OUTSTANDING_WIDE_STRINGS).remove(&(s as usize)) else {
+        trace_at(
+            TRACE_ERRORS,
+            &format!("WARNING: FreeWString ignored unknown or already freed pointer {:p}", s),
+        );
+        return;
+    };
+    drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(s, len)) });
+}
+
+// UTF-16 strings handed to the caller and not yet freed, with their length in
+// code units including the terminating NUL (needed to rebuild the allocation)
+static OUTSTANDING_WIDE_STRINGS: Lazy<Mutex<HashMap<usize, usize>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Transfer ownership of a UTF-16 copy of `text` to the caller, who frees it
+// with FreeWString. Interior NULs are replaced so the terminator is the only one.
+fn hand_out_wide_string(text: &str) -> *const u16 {
+    let wide: Box<[u16]> = text
+        .encode_utf16()
+        .map(|unit| if unit == 0 { u16::from(b' ') } else { unit })
+        .chain(std::iter::once(0))
+        .collect();
+    let len = wide.len();
+    LAST_RESULT_LENGTH.with(|last| last.set((len - 1) * 2));
+    let ptr = Box::into_raw(wide) as *mut u16;
+    lock_or_recover(&OUTSTANDING_WIDE_STRINGS).insert(ptr as usize, len);
+    ptr
+}
+
+// Helper function to borrow a UTF-8 string argument passed from C.
+// On failure the ready-to-return C error string is handed back.
+unsafe fn c_str_arg<'a>(ptr: *const c_char, what: &str) -> Result<&'a str, *const c_char> {
+    if ptr.is_null() {
+        return Err(create_error_string(&format!("ERROR: {} is null", what)));
+    }
+    unsafe { CStr::from_ptr(ptr) }
+        .to_str()
+        .map_err(|_| create_error_string(&format!("ERROR: Invalid UTF-8 in {}", what)))
+}
+
+// Remember an error message for GetLastErrorMessage. Returns the server error
+// details captured while producing it, if any.
+fn set_last_error(msg: &str) -> Option<SqlErrorInfo> {
+    trace_at(TRACE_ERRORS, msg);
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg.to_string()));
+    let sql = PENDING_SQL_ERROR.with(Cell::take);
+    let code = sql.map_or(-1, |info| info.number as i32);
+    LAST_ERROR_CODE.with(|last| last.set(code));
+    sql
+}
+
+// Reset the error code after a successful call (GetLastErrorCode returns 0)
+fn record_success() {
+    LAST_RESULT_LENGTH.with(|len| len.set(0));
+    PENDING_SQL_ERROR.with(|pending| pending.set(None));
+    LAST_ERROR_CODE.with(|last| last.set(0));
+}
+
+// Format a tiberius error with context. Server errors also have their number,
+// severity and state captured so the error string built from them can carry it.
+fn sql_error(context: &str, e: tiberius::error::Error) -> String {
+    if let tiberius::error::Error::Server(token) = &e {
+        let info = SqlErrorInfo {
+            number: token.code(),
+            severity: token.class(),
+            state: token.state(),
+        };
+        PENDING_SQL_ERROR.with(|pending| pending.set(Some(info)));
+    }
+    if matches!(e, tiberius::error::Error::Io { .. }) {
+        CONNECTION_LOST.with(|lost| lost.set(true));
+    }
+    format!("{}: {}", context, e)
+}
+
+// Render an error as {"error":true,"message":...,"sqlErrorNumber":...} for
+// the JSON error format. SQL fields are null for client-side errors.
+fn error_json(msg: &str, sql: Option<SqlErrorInfo>) -> String {
+    serde_json::json!({
+        "error": true,
+        "message": msg.strip_prefix("ERROR: ").unwrap_or(msg),
+        "sqlErrorNumber": sql.map(|s| s.number),
+        "severity": sql.map(|s| s.severity),
+        "state": sql.map(|s| s.state),
+    })
+    .to_string()
+}
+
+// Release an error string produced by create_error_string that is not handed
+// to the caller (used by exports that report failure via GetLastErrorMessage).
+fn free_error_string(ptr: *const c_char) {
+    if !ptr.is_null() {
+        unsafe {
+            reclaim_c_string(ptr as *mut c_char);
+        }
+    }
+}
+
+// Helper function to create error strings
+fn create_error_string(msg: &str) -> *const c_char {
+    match CString::new(error_text(msg)) {
+        Ok(c_string) => hand_out_c_string(c_string),
+        Err(_) => std::ptr::null(),
+    }
+}
+
+// Record an error for GetLastErrorMessage/GetLastErrorCode and return the
+// text handed to the caller: the message, or its JSON form (SetErrorFormat)
+fn error_text(msg: &str) -> String {
+    let sql = set_last_error(msg);
+    if ERRORS_AS_JSON.load(Ordering::Relaxed) {
+        error_json(msg, sql)
+    } else {
+        msg.to_string()
+    }
+}
+
+/// Session isolation level applied once at connect time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IsolationLevel {
+    ReadUncommitted,
+    ReadCommitted,
+    RepeatableRead,
+    Snapshot,
+    Serializable,
+}
+
+impl IsolationLevel {
+    fn parse(value: &str) -> Result<Self, String> {
+        let normalized = value.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+        match normalized.as_str() {
+            "read uncommitted" | "readuncommitted" => Ok(Self::ReadUncommitted),
+            "read committed" | "readcommitted" => Ok(Self::ReadCommitted),
+            "repeatable read" | "repeatableread" => Ok(Self::RepeatableRead),
+            "snapshot" => Ok(Self::Snapshot),
+            "serializable" => Ok(Self::Serializable),
+            _ => Err(format!("Unknown isolation level '{}'", value)),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::ReadUncommitted => "READ UNCOMMITTED",
+            Self::ReadCommitted => "READ COMMITTED",
+            Self::RepeatableRead => "REPEATABLE READ",
+            Self::Snapshot => "SNAPSHOT",
+            Self::Serializable => "SERIALIZABLE",
+        }
+    }
+
+    fn set_statement(self) -> &'static str {
+        match self {
+            Self::ReadUncommitted => "SET TRANSACTION ISOLATION LEVEL READ UNCOMMITTED",
+            Self::ReadCommitted => "SET TRANSACTION ISOLATION LEVEL READ COMMITTED",
+            Self::RepeatableRead => "SET TRANSACTION ISOLATION LEVEL REPEATABLE READ",
+            Self::Snapshot => "SET TRANSACTION ISOLATION LEVEL SNAPSHOT",
+            Self::Serializable => "SET TRANSACTION ISOLATION LEVEL SERIALIZABLE",
+        }
+    }
+}
+
+/// How the connect-time SET TRANSACTION ISOLATION LEVEL is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IsolationSetup {
+    /// Issue the SET and fail the connect if it fails (default)
+    Required,
+    /// Issue the SET but only trace a warning if it fails
+    Optional,
+    /// Do not issue the SET; the session keeps the server default
+    Skip,
+}
+
+impl IsolationSetup {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_lowercase().as_str() {
+            "required" => Ok(Self::Required),
+            "optional" => Ok(Self::Optional),
+            "skip" => Ok(Self::Skip),
+            _ => Err(format!(
+                "Unknown isolation setup '{}' (expected required, optional or skip)",
+                value
+            )),
+        }
+    }
+}
+
+/// Everything parsed from a connection string: the tiberius Config plus the
+/// SQLThinkRS-specific session settings.
+struct ConnOptions {
+    config: Config,
+    isolation_level: IsolationLevel,
+    // Levels tried in order instead of isolation_level; empty when not set
+    isolation_fallback: Vec<IsolationLevel>,
+    isolation_setup: IsolationSetup,
+    // Reuse idle connections and return this one to the pool on disconnect
+    pooling: bool,
+    // Bound on TCP connect + TDS login; None waits indefinitely
+    connect_timeout: Option<Duration>,
+    // Disable Nagle's algorithm on the socket
+    tcp_nodelay: bool,
+    // Idle time and probe interval for TCP keepalive; None leaves it off
+    tcp_keepalive: Option<Duration>,
+    // Database named in the connection string, restored on pool checkout
+    database: Option<String>,
+}
+
+impl ConnOptions {
+    /// Whether SELECTs are wrapped in BEGIN/COMMIT TRANSACTION. Only snapshot
+    /// and serializable sessions need it; under the other levels (or when the
+    /// isolation SET is skipped) the wrapper is pure overhead and breaks
+    /// statements that cannot run inside a user transaction. The level a
+    /// fallback chain settles on is only known per connection, so the wrapper
+    /// is kept whenever the chain could pick snapshot or serializable.
+    fn wrap_selects(&self) -> bool {
+        self.isolation_setup != IsolationSetup::Skip
+            && self
+                .isolation_candidates()
+                .iter()
+                .any(|level| matches!(level, IsolationLevel::Snapshot | IsolationLevel::Serializable))
+    }
+
+    /// Isolation levels to try at connect time, in order of preference
+    fn isolation_candidates(&self) -> Vec<IsolationLevel> {
+        if self.isolation_fallback.is_empty() {
+            vec![self.isolation_level]
+        } else {
+            self.isolation_fallback.clone()
+        }
+    }
+}
+
+// Split a connection string into (lowercased key, value) pairs; runs of
+// whitespace inside a key collapse to one space.
+// Values may be wrapped in braces to contain ';' or '=' (SQL Server
+// convention), with '}}' standing for a literal '}': password={p=ss;wo}}rd}
+fn split_connection_string(conn_str: &str) -> Result<Vec<(String, String)>, String> {
+    let mut pairs = Vec::new();
+    let mut rest = conn_str;
+
+    while !rest.is_empty() {
+        let Some(eq) = rest.find('=') else {
+            // Trailing text without '=' is ignored, as before
+            break;
+        };
+        let (raw_key, after_key) = rest.split_at(eq);
+        // A key may not span a ';' - skip malformed fragments like "foo;"
+        if let Some(semi) = raw_key.rfind(';') {
+            rest = &rest[semi + 1..];
+            continue;
+        }
+        let key = raw_key.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+        let after_eq = after_key[1..].trim_start();
+
+        let value;
+        if let Some(braced) = after_eq.strip_prefix('{') {
+            let mut literal = String::new();
+            let mut chars = braced.char_indices();
+            let mut end = None;
+            while let Some((i, c)) = chars.next() {
+                if c == '}' {
+                    if braced[i + 1..].starts_with('}') {
+                        literal.push('}');
+                        chars.next();
+                    } else {
+                        end = Some(i + 1);
+                        break;
+                    }
+                } else {
+                    literal.push(c);
+                }
+            }
+            let end = end.ok_or_else(|| format!("Unterminated '{{' in value for '{}'", key))?;
+            let tail = braced[end..].trim_start();
+            rest = match tail.strip_prefix(';') {
+                Some(next) => next,
+                None if tail.is_empty() => tail,
+                None => return Err(format!("Unexpected text after braced value for '{}'", key)),
+            };
+            value = literal;
+        } else {
+            let (raw_value, next) = match after_eq.find(';') {
+                Some(semi) => (&after_eq[..semi], &after_eq[semi + 1..]),
+                None => (after_eq, ""),
+            };
+            value = raw_value.trim().to_string();
+            rest = next;
+        }
+
+        if !key.is_empty() {
+            pairs.push((key, value));
+        }
+    }
+
+    Ok(pairs)
+}
+
+// Split a server value of the form [tcp:]host[\instance][,port] into its parts.
+// "." and "(local)" are shorthands for the local machine.
+fn parse_server_value(value: &str) -> Result<(String, Option<u16>, Option<String>), String> {
+    // TCP is the only protocol, so its "tcp:" prefix is accepted and dropped
+    let address = value.trim();
+    let address = match address.get(..4) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("tcp:") => &address[4..],
+        _ => address,
+    };
+    let (host_part, port) = match address.split_once(',') {
+        Some((host, port)) => {
+            let port = port
+                .trim()
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid port in server value '{}'", value))?;
+            (host.trim(), Some(port))
+        }
+        None => (address, None),
+    };
+    let (host, instance) = match host_part.split_once('\\') {
+        Some((host, instance)) if !instance.trim().is_empty() => {
+            (host.trim(), Some(instance.trim().to_string()))
+        }
+        Some((host, _)) => (host.trim(), None),
+        None => (host_part, None),
+    };
+    let host = match host {
+        "." | "(local)" => "localhost",
+        other => other,
+    };
+    Ok((host.to_string(), port, instance))
+}
+
+// Map an `encrypt` connection string value to a TLS encryption level.
+// tiberius has no TDS 8 strict mode, so `strict` requires TLS the same way
+// as `true`; `false` still encrypts the login packet, like other drivers.
+fn parse_encryption_level(value: &str) -> Result<EncryptionLevel, String> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "yes" | "mandatory" | "strict" => Ok(EncryptionLevel::Required),
+        "false" | "no" | "optional" => Ok(EncryptionLevel::Off),
+        _ => Err(format!(
+            "Unknown encrypt value '{}' (expected true, yes, mandatory, strict, false, no \
+             or optional)",
+            value
+        )),
+    }
+}
+
+// Parse connection string into tiberius Config plus session options
+fn parse_connection_string(conn_str: &str) -> Result<ConnOptions, String> {
+    let mut config = Config::new();
+    // Sessions show up under this name in sys.dm_exec_sessions unless overridden
+    config.application_name(DEFAULT_APPLICATION_NAME);
+    // Snapshot stays the default for backward compatibility
+    let mut isolation_level = IsolationLevel::Snapshot;
+    let mut isolation_fallback = Vec::new();
+    let mut isolation_setup = IsolationSetup::Required;
+    let mut user: Option<String> = None;
+    let mut password: Option<String> = None;
+    let mut integrated_security = false;
+    let mut access_token: Option<String> = None;
+    let mut pooling = true;
+    let mut server_port: Option<u16> = None;
+    let mut port_override: Option<u16> = None;
+    let mut instance_name: Option<String> = None;
+    let mut connect_timeout = Some(Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS));
+    let mut tcp_nodelay = true;
+    let mut database: Option<String> = None;
+    let mut tcp_keepalive = false;
+    let mut keepalive_interval = Duration::from_secs(DEFAULT_KEEPALIVE_INTERVAL_SECS);
+    let mut unknown_keys: Vec<String> = Vec::new();
+
+    for (key, value) in split_connection_string(conn_str)? {
+        let value = value.as_str();
+        match key.as_str() {
+            "server" | "host" | "data source" | "address" | "addr" | "network address" => {
+                let (host, port, instance) = parse_server_value(value)?;
+                config.host(host);
+                server_port = port;
+                instance_name = instance;
+            }
+            "port" => {
+                if let Ok(port) = value.parse::<u16>() {
+                    port_override = Some(port);
+                }
+            }
+            "user id" | "uid" | "user" => user = Some(value.to_string()),
+            "password" | "pwd" => {
+                password = Some(value.to_string());
+            }
+            "database" | "initial catalog" => {
+                config.database(value);
+                database = Some(value.to_string());
+            }
+            "application name" | "app" => config.application_name(value),
+            "trust server certificate" | "trustservercertificate" => {
+                if value.eq_ignore_ascii_case("true") || value == "1" {
+                    config.trust_cert();
+                }
+            }
+            "isolation level" => isolation_level = IsolationLevel::parse(value)?,
+            "isolation fallback" => {
+                isolation_fallback = value
+                    .split(',')
+                    .map(IsolationLevel::parse)
+                    .collect::<Result<Vec<_>, _>>()?;
+            }
+            "integrated security" | "trusted_connection" | "trusted connection" => {
+                integrated_security = match value.to_lowercase().as_str() {
+                    "true" | "yes" | "sspi" | "1" => true,
+                    "false" | "no" | "0" => false,
+                    _ => {
+                        return Err(format!(
+                            "Invalid integrated security value '{}' (expected true, yes, sspi, \
+                             false or no)",
+                            value
+                        ))
+                    }
+                };
+            }
+            "access token" | "accesstoken" => access_token = Some(value.to_string()),
+            "pooling" => pooling = !matches!(value.to_lowercase().as_str(), "false" | "no" | "0"),
+            "isolation setup" => isolation_setup = IsolationSetup::parse(value)?,
+            "skip isolation setup" => {
+                if value.eq_ignore_ascii_case("true") || value == "1" {
+                    isolation_setup = IsolationSetup::Skip;
+                }
+            }
+            "connect timeout" | "connection timeout" => {
+                let secs = value
+                    .parse::<u64>()
+                    .map_err(|_| format!("Invalid connect timeout '{}' (expected seconds)", value))?;
+                // 0 means wait indefinitely, as in ADO.NET
+                connect_timeout = (secs > 0).then(|| Duration::from_secs(secs));
+            }
+            "encrypt" => config.encryption(parse_encryption_level(value)?),
+            "tcp nodelay" | "nodelay" => {
+                tcp_nodelay = !matches!(value.to_lowercase().as_str(), "false" | "no" | "0");
+            }
+            "tcp keepalive" | "keepalive" => {
+                tcp_keepalive = matches!(value.to_lowercase().as_str(), "true" | "yes" | "1");
+            }
+            "keepalive interval" => {
+                // Accept "30" or "30s"
+                let secs = value
+                    .strip_suffix(['s', 'S'])
+                    .unwrap_or(value)
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|&secs| secs > 0)
+                    .ok_or_else(|| format!("Invalid keepalive interval '{}' (expected seconds)", value))?;
+                keepalive_interval = Duration::from_secs(secs);
+            }
+            "application intent" | "applicationintent" => {
+                match value.to_lowercase().replace(' ', "").as_str() {
+                    "readonly" => config.readonly(true),
+                    "readwrite" => config.readonly(false),
+                    _ => {
+                        return Err(format!(
+                            "Invalid application intent '{}' (expected ReadOnly or ReadWrite)",
+                            value
+                        ))
+                    }
+                }
+            }
+            "multipleactiveresultsets" | "multiple active result sets" => {
+                // tiberius has no MARS (SMP session multiplexing), so a
+                // connection always carries one active request. Every result
+                // set of a batch is still readable through ExecuteSqlMulti.
+                if matches!(value.to_lowercase().as_str(), "true" | "yes" | "1") {
+                    trace_at(
+                        TRACE_ERRORS,
+                        "WARNING: MultipleActiveResultSets is not supported; use ExecuteSqlMulti \
+                         for multiple result sets and ConnectDbHandle for concurrent statements",
+                    );
+                }
+            }
+            key if IGNORED_CONN_KEYS.contains(&key) => {
+                trace(&format!("Ignoring connection string key '{}' (not applicable)", key));
+            }
+            "packet size" => {
+                let size = value
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|size| (MIN_PACKET_SIZE..=MAX_PACKET_SIZE).contains(size))
+                    .ok_or_else(|| {
+                        format!(
+                            "Invalid packet size '{}' (expected {}-{} bytes)",
+                            value, MIN_PACKET_SIZE, MAX_PACKET_SIZE
+                        )
+                    })?;
+                // tiberius always requests 4096-byte packets in its login
+                // record and offers no way to change that
+                if size != TDS_PACKET_SIZE {
+                    trace_at(
+                        TRACE_ERRORS,
+                        &format!(
+                            "WARNING: packet size={} is not supported; using {} bytes",
+                            size, TDS_PACKET_SIZE
+                        ),
+                    );
+                }
+            }
+            _ => unknown_keys.push(key),
+        }
+    }
+
+    if !unknown_keys.is_empty() {
+        if STRICT_CONN_STR.load(Ordering::Relaxed) {
+            return Err(format!(
+                "Unrecognized connection string keys: {}",
+                unknown_keys.join(", ")
+            ));
+        }
+        for key in &unknown_keys {
+            trace_at(
+                TRACE_ERRORS,
+                &format!("WARNING: ignoring unrecognized connection string key '{}'", key),
+            );
+        }
+    }
+
+    // An explicit port (separate key first, then "host,port") wins over the
+    // instance name, which would otherwise be resolved via SQL Browser
+    match (port_override.or(server_port), instance_name) {
+        (Some(port), _) => config.port(port),
+        (None, Some(instance)) => config.instance_name(instance),
+        (None, None) => {}
+    }
+
+    if let Some(token) = access_token {
+        // Azure AD bearer token (e.g. from a managed identity); user id and
+        // password are ignored. The token is never traced.
+        if integrated_security {
+            return Err("access token cannot be combined with integrated security".to_string());
+        }
+        config.authentication(tiberius::AuthMethod::aad_token(token));
+    } else if integrated_security {
+        // The Windows identity of the calling process is used; user id and
+        // password are ignored, as in ADO.NET
+        #[cfg(windows)]
+        config.authentication(tiberius::AuthMethod::Integrated);
+        #[cfg(not(windows))]
+        return Err("Integrated security is only supported on Windows".to_string());
+    } else if let Some(user) = user {
+        config.authentication(tiberius::AuthMethod::sql_server(
+            user,
+            password.unwrap_or_default(),
+        ));
+    }
+
+    Ok(ConnOptions {
+        config,
+        isolation_level,
+        isolation_fallback,
+        isolation_setup,
+        pooling,
+        connect_timeout,
+        tcp_nodelay,
+        tcp_keepalive: tcp_keepalive.then_some(keepalive_interval),
+        database,
+    })
+}
+
+// Matches a PRIMARY KEY constraint, however it is spaced or cased
+static PRIMARY_KEY_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)\bPRIMARY\s+KEY\b").unwrap());
+
+/// Return a copy of `sql` with comments, string literals and quoted identifiers
+/// blanked out with spaces. Byte offsets are preserved, so positions found in
+/// the result can be used to slice the original.
+fn mask_comments_and_literals(sql: &str) -> String {
+    let bytes = sql.as_bytes();
+    let mut masked = bytes.to_vec();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let end = match bytes[i] {
+            b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                bytes[i..].iter().position(|&b| b == b'\n').map_or(bytes.len(), |p| i + p)
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                // T-SQL block comments nest
+                let mut depth = 0;
+                let mut j = i;
+                while j < bytes.len() {
+                    if bytes[j..].starts_with(b"/*") {
+                        depth += 1;
+                        j += 2;
+                    } else if bytes[j..].starts_with(b"*/") {
+                        depth -= 1;
+                        j += 2;
+                        if depth == 0 {
+                            break;
+                        }
+                    } else {
+                        j += 1;
+                    }
+                }
+                j
+            }
+            quote @ (b'\'' | b'"' | b'[') => {
+                let close = if quote == b'[' { b']' } else { quote };
+                let mut j = i + 1;
+                loop {
+                    match bytes.get(j) {
+                        None => break j,
+                        // A doubled closing character is an escape
+                        Some(&b) if b == close && bytes.get(j + 1) == Some(&close) => j += 2,
+                        Some(&b) if b == close => break j + 1,
+                        Some(_) => j += 1,
+                    }
+                }
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        masked[start..end].fill(b' ');
+        i = end;
+    }
+    // Only whole characters were replaced by ASCII spaces, so this is valid UTF-8
+    String::from_utf8(masked).unwrap_or_default()
+}
+
+static CREATE_TABLE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)^\s*CREATE\s+TABLE\b").unwrap());
+
+// Any CREATE TABLE in a batch, to detect more than one
+static CREATE_TABLE_ANYWHERE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)\bCREATE\s+TABLE\b").unwrap());
+
+/// Byte offset of the '(' opening the column list of a CREATE TABLE, i.e. the
+/// first token after the (possibly qualified or quoted) table name. Returns
+/// None for forms without a column list such as `CREATE TABLE t AS SELECT ...`.
+/// `code` is `sql` passed through mask_comments_and_literals.
+fn column_list_paren(sql: &str, code: &str) -> Option<usize> {
+    let bytes = sql.as_bytes();
+    let code_bytes = code.as_bytes();
+    // Whitespace, or a comment (masked to spaces but not a quoted identifier)
+    let skip_blank = |mut i: usize| {
+        while i < bytes.len()
+            && (bytes[i].is_ascii_whitespace()
+                || (code_bytes[i] == b' ' && bytes[i] != b'[' && bytes[i] != b'"'))
+        {
+            i += 1;
+        }
+        i
+    };
+
+    let mut i = CREATE_TABLE_RE.find(code)?.end();
+    loop {
+        i = skip_blank(i);
+        // One name part: [bracketed], "quoted" or a bare identifier
+        match *bytes.get(i)? {
+            quote @ (b'[' | b'"') => {
+                let close = if quote == b'[' { b']' } else { b'"' };
+                i += 1;
+                loop {
+                    match *bytes.get(i)? {
+                        b if b == close && bytes.get(i + 1) == Some(&close) => i += 2,
+                        b if b == close => break,
+                        _ => i += 1,
+                    }
+                }
+                i += 1;
+            }
+            _ => {
+                let start = i;
+                while i < bytes.len()
+                    && (bytes[i].is_ascii_alphanumeric() || b"_#@$".contains(&bytes[i]) || !bytes[i].is_ascii())
+                {
+                    i += 1;
+                }
+                // An empty part is only valid in the db..table form
+                if i == start && bytes.get(i) != Some(&b'.') {
+                    return None;
+                }
+            }
+        }
+        i = skip_blank(i);
+        if bytes.get(i) == Some(&b'.') {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    (bytes.get(i) == Some(&b'(')).then_some(i)
+}
+
+// Names tried, in order, for the injected primary key column; if the table
+// already has columns with all of them, no key is injected
+const PRIMARY_KEY_COLUMN_NAMES: [&str; 2] = ["ID", "thinksqlrs_id"];
+
+/// Names of the columns (and constraints) declared in a CREATE TABLE column
+/// list, unquoted: the first identifier of each top-level comma-separated item.
+/// `open` is the offset of the list's '(' and `code` is the masked `sql`.
+fn column_list_names(sql: &str, code: &str, open: usize) -> Vec<String> {
+    let bytes = sql.as_bytes();
+    let code_bytes = code.as_bytes();
+    let mut names = Vec::new();
+    let mut item_start = Some(open + 1);
+    let mut depth = 0usize;
+    let mut i = open + 1;
+    while i < bytes.len() {
+        if let Some(start) = item_start.take() {
+            // Skip whitespace and comments (blank in the mask, but not a quote)
+            let mut j = start;
+            while j < bytes.len()
+                && (bytes[j].is_ascii_whitespace()
+                    || (code_bytes[j] == b' ' && bytes[j] != b'[' && bytes[j] != b'"'))
+            {
+                j += 1;
+            }
+            if let Some(name) = identifier_at(sql, j) {
+                names.push(name);
+            }
+        }
+        match code_bytes[i] {
+            b'(' => depth += 1,
+            b')' if depth == 0 => break,
+            b')' => depth -= 1,
+            b',' if depth == 0 => item_start = Some(i + 1),
+            _ => {}
+        }
+        i += 1;
+    }
+    names
+}
+
+/// The identifier starting at byte `i` of `sql`: `[bracketed]`, `"quoted"`
+/// (both unescaped) or bare.
+fn identifier_at(sql: &str, i: usize) -> Option<String> {
+    let rest = sql.get(i..)?;
+    let close = match rest.chars().next()? {
+        '[' => ']',
+        '"' => '"',
+        _ => {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || "_#@$".contains(c)))
+                .unwrap_or(rest.len());
+            return (end > 0).then(|| rest[..end].to_string());
+        }
+    };
+    let mut name = String::new();
+    let mut chars = rest[1..].chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == close {
+            if chars.peek() != Some(&close) {
+                return Some(name);
+            }
+            chars.next();
+        }
+        name.push(c);
+    }
+    None
+}
+
+/// Only CREATE TABLE needs transformation, and only while SetAutoPrimaryKey
+/// is on (expects a statement_head)
+fn apply_auto_primary_key(sql: &str, head: &str) -> String {
+    if starts_with_keyword(head, "CREATE") && AUTO_PRIMARY_KEY.load(Ordering::Relaxed) {
+        process_create_table(sql)
+    } else {
+        sql.to_string()
+    }
+}
+
+/// Process CREATE TABLE to inject primary key if not present
+fn process_create_table(sql: &str) -> String {
+    // Only look at actual SQL: "PRIMARY KEY" or "(" inside a comment,
+    // string literal or quoted identifier must not count
+    let code = mask_comments_and_literals(sql);
+    if PRIMARY_KEY_RE.is_match(&code) {
+        return sql.to_string();
+    }
+    // A batch creating several tables is left as written: the check above
+    // cannot tell which table a PRIMARY KEY belongs to, and rewriting only the
+    // first one would be inconsistent
+    if CREATE_TABLE_ANYWHERE_RE.find_iter(&code).count() != 1 {
+        return sql.to_string();
+    }
+
+    // Find the parenthesis that opens the column list
+    if let Some(first_paren_index) = column_list_paren(sql, &code) {
+        // Avoid a duplicate column when the table already has an ID column
+        let existing = column_list_names(sql, &code, first_paren_index);
+        let Some(id_column) = PRIMARY_KEY_COLUMN_NAMES
+            .iter()
+            .find(|name| !existing.iter().any(|c| c.eq_ignore_ascii_case(name)))
+        else {
+            return sql.to_string();
+        };
+        let primary_key_column = format!("{} INT PRIMARY KEY IDENTITY(1,1), ", id_column);
+        let mut processed_sql = String::with_capacity(sql.len() + primary_key_column.len());
+        processed_sql.push_str(&sql[..=first_paren_index]);
+        processed_sql.push_str(&primary_key_column);
+        processed_sql.push_str(&sql[first_paren_index + 1..]);
+        return processed_sql;
+    }
+
+    sql.to_string()
+}
+
+/// Process SELECT - snapshot isolation is now set at connection time,
+/// so this just returns the SQL as-is (no extra round-trip needed).
+#[allow(dead_code)]
+fn process_select(sql: &str) -> String {
+    sql.to_string()
+}
+
+/// Execute SELECT query and return JSON results.
+/// When `wrap` is set (snapshot/serializable sessions), sends BEGIN TRANSACTION +
+/// SELECT + COMMIT TRANSACTION as a **single batch** via simple_query, so the
+/// isolation level is honoured with only ONE round-trip instead of three. The
+/// BEGIN/COMMIT pair is skipped when @@TRANCOUNT shows a transaction is open.
+/// The result sets are iterated to find the one containing rows. A SELECT that
+/// matches nothing still returns `[]`.
+async fn execute_select_query(
+    client: &mut Client<tokio_util::compat::Compat<TcpStream>>,
+    sql: &str,
+    wrap: bool,
+) -> Result<Option<String>, String> {
+    let set = fetch_select_rows(client, sql, wrap).await?.unwrap_or_default();
+    rows_to_json(&set).map(Some)
+}
+
+/// Run a SELECT, optionally inside a transaction batch, and return the result
+/// set it produced, or None if the batch produced no result set at all.
+async fn fetch_select_rows(client: &mut TibClient, sql: &str, wrap: bool) -> Result<Option<ResultSet>, String> {
+    // Build a single-batch string: BEGIN TRAN; SELECT …; COMMIT TRAN. The
+    // server decides from @@TRANCOUNT: inside an open transaction (from
+    // BeginTransaction or the caller's own BEGIN TRAN) the SELECT joins it, so
+    // under snapshot isolation every read until the commit sees one snapshot
+    let batch = if wrap {
+        Cow::Owned(format!(
+            "DECLARE @__thinksqlrs_own_tran BIT = CASE WHEN @@TRANCOUNT = 0 THEN 1 ELSE 0 END;\n\
+             IF @__thinksqlrs_own_tran = 1 BEGIN TRANSACTION;\n{}\n;\n\
+             IF @__thinksqlrs_own_tran = 1 COMMIT TRANSACTION",
+            sql.trim()
+        ))
+    } else {
+        Cow::Borrowed(sql)
+    };
+    trace_at(TRACE_STATEMENTS, &format!("EXEC (batch): {}", batch));
+
+    let started = Instant::now();
+    let stream = client
+        .simple_query(batch.as_ref())
+        .await
+        .map_err(|e| sql_error("Query execution failed", e))?;
+
+    // simple_query can return multiple result sets (one per statement).
+    let result_sets = collect_result_sets(stream)
+        .await
+        .map_err(|e| sql_error("Failed to fetch results", e))?;
+    let set = select_result_set(result_sets);
+
+    trace(&format!(
+        "SELECT returned {} rows in {}ms",
+        set.as_ref().map_or(0, |set| set.rows.len()),
+        started.elapsed().as_millis()
+    ));
+    Ok(set)
+}
+
+/// One result set of a response with its column schema, which is known even
+/// when it has no rows
+#[derive(Default)]
+struct ResultSet {
+    columns: Vec<tiberius::Column>,
+    rows: Vec<Row>,
+}
+
+/// Read every result set of a response, keeping empty ones. (tiberius'
+/// into_results drops the schema and can lose empty sets.)
+async fn collect_result_sets(mut stream: tiberius::QueryStream<'_>) -> tiberius::Result<Vec<ResultSet>> {
+    let mut sets: Vec<ResultSet> = Vec::new();
+    while let Some(item) = stream.try_next().await? {
+        match item {
+            QueryItem::Metadata(meta) => sets.push(ResultSet {
+                columns: meta.columns().to_vec(),
+                rows: Vec::new(),
+            }),
+            QueryItem::Row(row) => match sets.last_mut() {
+                Some(set) => set.rows.push(row),
+                None => sets.push(ResultSet {
+                    columns: row.columns().to_vec(),
+                    rows: vec![row],
+                }),
+            },
+        }
+    }
+    Ok(sets)
+}
+
+/// The result set a statement is answered with: the first one with rows, or
+/// else the first (empty) one, so an empty SELECT is told apart from a
+/// statement that produced no result set.
+fn select_result_set(sets: Vec<ResultSet>) -> Option<ResultSet> {
+    match sets.iter().position(|set| !set.rows.is_empty()) {
+        Some(index) => sets.into_iter().nth(index),
+        None => sets.into_iter().next(),
+    }
+}
+
+/// Execute a batch and serialize every result set, in order, including empty ones.
+async fn execute_multi_query(client: &mut TibClient, sql: &str) -> Result<Option<String>, String> {
+    trace_at(TRACE_STATEMENTS, &format!("EXEC (multi): {}", sql.trim()));
+    let result_sets = client
+        .simple_query(sql)
+        .await
+        .map_err(|e| sql_error("Query execution failed", e))?
+        .into_results()
+        .await
+        .map_err(|e| sql_error("Failed to fetch results", e))?;
+
+    trace(&format!("Batch returned {} result sets", result_sets.len()));
+    let sets: Vec<Vec<serde_json::Map<String, Value>>> =
+        result_sets.iter().map(|rows| rows_to_maps(rows)).collect();
+    to_json_string(&sets).map(Some)
+}
+
+/// Serialize a result set in the shape chosen with SetResultFormat. The
+/// envelope and columnar shapes list the columns even when there are no rows.
+fn rows_to_json(set: &ResultSet) -> Result<String, String> {
+    let rows = &set.rows;
+    match RESULT_FORMAT.load(Ordering::Relaxed) {
+        RESULT_FORMAT_ENVELOPE => to_json_string(&serde_json::json!({
+            "columns": column_metadata(&set.columns),
+            "rows": rows_to_maps(rows),
+            "messages": LAST_MESSAGES.with(|messages| messages.borrow().clone()),
+        })),
+        RESULT_FORMAT_COLUMNAR => {
+            let column_names = unique_column_names(set.columns.iter().map(|c| c.name()));
+            let (_, column_types) = column_layout(rows);
+            let arrays: Vec<Vec<Value>> = rows
+                .iter()
+                .map(|row| {
+                    column_types
+                        .iter()
+                        .enumerate()
+                        .map(|(i, &column_type)| row_to_json_value(row, i, column_type))
+                        .collect()
+                })
+                .collect();
+            to_json_string(&serde_json::json!({ "columns": column_names, "rows": arrays }))
+        }
+        _ => to_json_string(&rows_to_maps(rows)),
+    }
+}
+
+/// Column names and server-declared types (tiberius names, e.g. "Int4" or
+/// "NVarchar"), from the result set's schema.
+fn column_metadata(columns: &[tiberius::Column]) -> Vec<Value> {
+    let names = unique_column_names(columns.iter().map(|c| c.name()));
+    columns
+        .iter()
+        .zip(names)
+        .map(|(c, name)| {
+            serde_json::json!({
+                "name": name,
+                "type": format!("{:?}", c.column_type()),
+            })
+        })
+        .collect()
+}
+
+/// Make repeated column names (e.g. `a.id, b.id` from a join) unique so no
+/// value is lost in the row objects: the second `id` becomes `id_1`, the
+/// third `id_2`. A suffix already used by a real column is skipped, so that
+/// column keeps its own name. Names are compared case-insensitively, since
+/// PowerShell's ConvertFrom-Json rejects keys that differ only in case.
+fn unique_column_names<'a>(names: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let names: Vec<&str> = names.collect();
+    let real: HashSet<String> = names.iter().map(|name| name.to_lowercase()).collect();
+    let mut taken: HashSet<String> = HashSet::new();
+    let mut unique = Vec::with_capacity(names.len());
+    for name in names {
+        let mut candidate = name.to_string();
+        let mut suffix = 0;
+        while !taken.insert(candidate.to_lowercase()) {
+            // Keep looking past suffixes that belong to a real column
+            loop {
+                suffix += 1;
+                candidate = format!("{}_{}", name, suffix);
+                if !real.contains(&candidate.to_lowercase()) {
+                    break;
+                }
+            }
+        }
+        unique.push(candidate);
+    }
+    unique
+}
+
+/// Convert a result set to row objects keyed by column name.
+fn rows_to_maps(rows: &[Row]) -> Vec<serde_json::Map<String, Value>> {
+    let num_rows = rows.len();
+
+    // Pre-allocate with exact capacity and cache column names and types
+    let mut results: Vec<serde_json::Map<String, Value>> = Vec::with_capacity(num_rows);
+    let (column_names, column_types) = column_layout(rows);
+
+    // Build row maps using cached column names and types
+    for row in rows {
+        let mut row_map = serde_json::Map::with_capacity(column_names.len());
+        for (i, name) in column_names.iter().enumerate() {
+            let column_type = column_types.get(i).copied().unwrap_or(ColumnType::Other);
+            row_map.insert(name.clone(), row_to_json_value(row, i, column_type));
+        }
+        results.push(row_map);
+    }
+
+    results
+}
+
+/// Column names and value types of a result set, detected once up front.
+fn column_layout(rows: &[Row]) -> (Vec<String>, Vec<ColumnType>) {
+    let column_names: Vec<String> = rows
+        .first()
+        .map(|r| unique_column_names(r.columns().iter().map(|c| c.name())))
+        .unwrap_or_default();
+
+    // Detect column types once (from the first non-null cell of each column)
+    // to avoid repeated type checks
+    let column_types: Vec<ColumnType> = (0..column_names.len())
+        .map(|i| {
+            let Some(sample) = rows.iter().find(|r| !cell_is_null(r, i)) else {
+                return ColumnType::Other; // all NULL (or no rows)
+            };
+            let column_type = detect_column_type(sample, i);
+            if matches!(column_type, ColumnType::Other) {
+                trace_at(
+                    TRACE_ERRORS,
+                    &format!(
+                        "WARNING: column '{}' has unsupported type {}",
+                        column_names[i],
+                        declared_type_name(sample, i)
+                    ),
+                );
+            }
+            column_type
+        })
+        .collect();
+
+    (column_names, column_types)
+}
+
+/// Render a result set as RFC 4180 CSV: a header row, CRLF line endings,
+/// NULL as an empty field. Values are formatted as in the JSON output. The
+/// header comes from the result set's schema, so it is there even without rows.
+fn rows_to_csv(set: &ResultSet) -> String {
+    let column_names = unique_column_names(set.columns.iter().map(|c| c.name()));
+    let (_, column_types) = column_layout(&set.rows);
+    let mut csv = String::new();
+    let mut push_record = |fields: &mut dyn Iterator<Item = String>| {
+        for (i, field) in fields.enumerate() {
+            if i > 0 {
+                csv.push(',');
+            }
+            csv.push_str(&csv_field(&field));
+        }
+        csv.push_str("\r\n");
+    };
+
+    if !column_names.is_empty() {
+        push_record(&mut column_names.iter().cloned());
+    }
+    for row in &set.rows {
+        push_record(&mut column_types.iter().enumerate().map(|(i, &column_type)| {
+            match row_to_json_value(row, i, column_type) {
+                Value::Null => String::new(),
+                Value::String(s) => s,
+                other => other.to_string(),
+            }
+        }));
+    }
+    csv
+}
+
+/// Quote a CSV field if it contains a delimiter, quote or line break.
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\r', '\n']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+fn to_json_string<T: serde::Serialize + ?Sized>(value: &T) -> Result<String, String> {
+    // Serialize directly to bytes (more efficient than to_string for large payloads)
+    let json_bytes = if PRETTY_JSON.load(Ordering::Relaxed) {
+        serde_json::to_vec_pretty(value)
+    } else {
+        serde_json::to_vec(value)
+    }
+    .map_err(|e| format!("Failed to marshal JSON: {}", e))?;
+    String::from_utf8(json_bytes)
+        .map_err(|e| format!("Failed to convert JSON bytes to string: {}", e))
+}
+
+/// Execute an EXEC statement, returning the first result set with rows as JSON
+/// (`[]` if the procedure only produced empty ones), or None if it produced no
+/// result set. Never wrapped in a transaction, so procedures can manage their own.
+async fn execute_proc_statement(client: &mut TibClient, sql: &str) -> Result<Option<String>, String> {
+    match fetch_select_rows(client, sql, false).await? {
+        Some(set) => rows_to_json(&set).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Execute non-SELECT statement using simple_query (avoids sp_executesql overhead)
+async fn execute_non_select(
+    client: &mut Client<tokio_util::compat::Compat<TcpStream>>,
+    sql: &str,
+) -> Result<Option<String>, String> {
+    trace_at(TRACE_STATEMENTS, &format!("EXEC (non-select): {}", sql.trim()));
+    let started = Instant::now();
+    client
+        .simple_query(sql)
+        .await
+        .map_err(|e| sql_error("SQL execution failed", e))?
+        .into_results()
+        .await
+        .map_err(|e| sql_error("SQL execution failed", e))?;
+
+    trace(&format!("Non-select completed OK in {}ms", started.elapsed().as_millis()));
+    Ok(None) // Success
+}
+
+/// Convert a single cell to a JSON value using the cached column type.
+/// A genuine SQL NULL becomes `null`; a non-null value that cannot be mapped
+/// becomes the sentinel string "<unsupported:TypeName>" so it is never
+/// confused with a real NULL.
+fn row_to_json_value(row: &tiberius::Row, index: usize, column_type: ColumnType) -> Value {
+    if cell_is_null(row, index) {
+        return Value::Null;
+    }
+    let value = match column_type {
+        ColumnType::I64 => row.try_get::<i64, _>(index).ok().flatten().map(|v| Value::Number(v.into())),
+        ColumnType::I32 => row.try_get::<i32, _>(index).ok().flatten().map(|v| Value::Number(v.into())),
+        ColumnType::I16 => row.try_get::<i16, _>(index).ok().flatten().map(|v| Value::Number(v.into())),
+        ColumnType::U8 => row.try_get::<u8, _>(index).ok().flatten().map(|v| Value::Number(v.into())),
+        // (MAX) text arrives as PLP chunks; if the borrowed reader refuses it,
+        // take the decoded string straight from the cell.
+        ColumnType::Str => row
+            .try_get::<&str, _>(index)
+            .ok()
+            .flatten()
+            .or_else(|| cell_text(row, index))
+            .map(|v| Value::String(v.to_string())),
+        ColumnType::F64 => row.try_get::<f64, _>(index).ok().flatten().map(float_to_json),
+        // Widen REAL via its shortest decimal form so 3.14f32 serializes as 3.14,
+        // not 3.140000104904175.
+        ColumnType::F32 => row
+            .try_get::<f32, _>(index)
+            .ok()
+            .flatten()
+            .and_then(|v| v.to_string().parse::<f64>().ok())
+            .map(float_to_json),
+        ColumnType::Bool => row.try_get::<bool, _>(index).ok().flatten().map(Value::Bool),
+        ColumnType::DateTime => row
+            .try_get::<NaiveDateTime, _>(index)
+            .ok()
+            .flatten()
+            .and_then(|v| datetime_to_json(v, || format_datetime(v, fractional_scale(row, index)))),
+        ColumnType::Date => row
+            .try_get::<NaiveDate, _>(index)
+            .ok()
+            .flatten()
+            .and_then(|v| datetime_to_json(v.into(), || v.format("%Y-%m-%d").to_string())),
+        ColumnType::Time => row
+            .try_get::<NaiveTime, _>(index)
+            .ok()
+            .flatten()
+            .map(|v| Value::String(format_time(v, fractional_scale(row, index)))),
+        ColumnType::Decimal => row
+            .try_get::<Numeric, _>(index)
+            .ok()
+            .flatten()
+            .and_then(decimal_to_json),
+        // tiberius already swaps the mixed-endian wire layout, so the
+        // hyphenated form matches what SSMS displays (lowercased).
+        ColumnType::Guid => row
+            .try_get::<Uuid, _>(index)
+            .ok()
+            .flatten()
+            .map(|v| Value::String(v.hyphenated().to_string())),
+        ColumnType::Binary => row
+            .try_get::<&[u8], _>(index)
+            .ok()
+            .flatten()
+            .map(|v| Value::String(format_binary(v))),
+        ColumnType::Other => None,
+    };
+    value.unwrap_or_else(|| Value::String(format!("<unsupported:{}>", declared_type_name(row, index))))
+}
+
+/// The raw decoded text of a string cell, bypassing tiberius' FromSql readers.
+fn cell_text(row: &tiberius::Row, index: usize) -> Option<&str> {
+    match row.cells().nth(index).map(|(_, data)| data) {
+        Some(ColumnData::String(Some(text))) => Some(text.as_ref()),
+        _ => None,
+    }
+}
+
+/// Type-agnostic NULL probe: every ColumnData variant wraps an Option.
+fn cell_is_null(row: &tiberius::Row, index: usize) -> bool {
+    match row.cells().nth(index).map(|(_, data)| data) {
+        Some(ColumnData::U8(v)) => v.is_none(),
+        Some(ColumnData::I16(v)) => v.is_none(),
+        Some(ColumnData::I32(v)) => v.is_none(),
+        Some(ColumnData::I64(v)) => v.is_none(),
+        Some(ColumnData::F32(v)) => v.is_none(),
+        Some(ColumnData::F64(v)) => v.is_none(),
+        Some(ColumnData::Bit(v)) => v.is_none(),
+        Some(ColumnData::String(v)) => v.is_none(),
+        Some(ColumnData::Guid(v)) => v.is_none(),
+        Some(ColumnData::Binary(v)) => v.is_none(),
+        Some(ColumnData::Numeric(v)) => v.is_none(),
+        Some(ColumnData::Xml(v)) => v.is_none(),
+        Some(ColumnData::DateTime(v)) => v.is_none(),
+        Some(ColumnData::SmallDateTime(v)) => v.is_none(),
+        Some(ColumnData::Time(v)) => v.is_none(),
+        Some(ColumnData::Date(v)) => v.is_none(),
+        Some(ColumnData::DateTime2(v)) => v.is_none(),
+        Some(ColumnData::DateTimeOffset(v)) => v.is_none(),
+        None => true,
+    }
+}
+
+/// The server-declared type of a column, e.g. "Xml" or "NVarchar", for diagnostics.
+fn declared_type_name(row: &tiberius::Row, index: usize) -> String {
+    row.columns()
+        .get(index)
+        .map(|c| format!("{:?}", c.column_type()))
+        .unwrap_or_else(|| "Unknown".to_string())
+}
+
+/// Convert a FLOAT/REAL value to JSON. JSON has no NaN or infinities, so those
+/// become "NaN"/"Infinity"/"-Infinity" with NON_FINITE_AS_STRING, else null
+/// with a trace warning.
+fn float_to_json(value: f64) -> Value {
+    if let Some(number) = serde_json::Number::from_f64(value) {
+        return Value::Number(number);
+    }
+    let text = if value.is_nan() {
+        "NaN"
+    } else if value > 0.0 {
+        "Infinity"
+    } else {
+        "-Infinity"
+    };
+    if NON_FINITE_AS_STRING.load(Ordering::Relaxed) {
+        return Value::String(text.to_string());
+    }
+    trace_at(
+        TRACE_ERRORS,
+        &format!("WARNING: float value {} has no JSON number form; emitting null", text),
+    );
+    Value::Null
+}
+
+/// Convert a DECIMAL/NUMERIC value to JSON, honouring the DECIMAL_AS_STRING flag.
+/// Numbers keep every digit: serde_json's arbitrary_precision feature stores
+/// the decimal text as is instead of going through f64.
+fn decimal_to_json(value: Numeric) -> Option<Value> {
+    let text = format_numeric(value);
+    if DECIMAL_AS_STRING.load(Ordering::Relaxed) {
+        return Some(Value::String(text));
+    }
+    text.parse::<serde_json::Number>().ok().map(Value::Number)
+}
+
+/// Format a DECIMAL/NUMERIC value exactly from its scaled integer representation.
+/// (tiberius' own Display impl mangles negative values with a zero integer part.)
+fn format_numeric(value: Numeric) -> String {
+    let scale = value.scale() as usize;
+    let sign = if value.value() < 0 { "-" } else { "" };
+    let digits = value.value().unsigned_abs().to_string();
+    if scale == 0 {
+        return format!("{}{}", sign, digits);
+    }
+    let padded = format!("{:0>width$}", digits, width = scale + 1);
+    let (int_part, dec_part) = padded.split_at(padded.len() - scale);
+    format!("{}{}.{}", sign, int_part, dec_part)
+}
+
+/// Encode binary data as base64, or as a "0x..." hex literal when BINARY_AS_HEX is set.
+fn format_binary(bytes: &[u8]) -> String {
+    if BINARY_AS_HEX.load(Ordering::Relaxed) {
+        let mut hex = String::with_capacity(2 + bytes.len() * 2);
+        hex.push_str("0x");
+        for b in bytes {
+            hex.push_str(&format!("{:02X}", b));
+        }
+        hex
+    } else {
+        BASE64_STANDARD.encode(bytes)
+    }
+}
+
+/// Format a DATETIME/DATETIME2 value as ISO-8601 (e.g. "2024-01-15T13:45:30").
+/// Uses a fixed pattern so the output never depends on the host locale.
+fn format_datetime(value: NaiveDateTime, scale: Option<u8>) -> String {
+    format!(
+        "{}T{}",
+        value.date().format("%Y-%m-%d"),
+        format_time(value.time(), scale)
+    )
+}
+
+/// Serialize a DATETIME or DATE value according to SetDateFormat; `iso` builds
+/// the default ISO-8601 form. None if the value cannot be formatted.
+fn datetime_to_json(value: NaiveDateTime, iso: impl FnOnce() -> String) -> Option<Value> {
+    match DATE_FORMAT_MODE.load(Ordering::Relaxed) {
+        DATE_FORMAT_EPOCH_MILLIS => Some(Value::Number(value.and_utc().timestamp_millis().into())),
+        DATE_FORMAT_PATTERN => {
+            // write! reports a pattern that does not apply (e.g. a time zone
+            // specifier) as an error instead of panicking like to_string
+            use std::fmt::Write;
+            let mut text = String::new();
+            write!(text, "{}", value.format(&lock_or_recover(&DATE_FORMAT_STRFTIME))).ok()?;
+            Some(Value::String(text))
+        }
+        _ => Some(Value::String(iso())),
+    }
+}
+
+/// Format a TIME value as "13:45:30.1234567".
+/// When the column scale is known the fraction is padded to exactly that many
+/// digits (matching what SSMS shows); otherwise it is only emitted when non-zero.
+fn format_time(value: NaiveTime, scale: Option<u8>) -> String {
+    let whole = value.format("%H:%M:%S");
+    match scale {
+        Some(0) => whole.to_string(),
+        Some(scale) => {
+            let scale = scale.min(9) as u32;
+            let digits = value.nanosecond() / 10u32.pow(9 - scale);
+            format!("{}.{:0width$}", whole, digits, width = scale as usize)
+        }
+        None => value.format("%H:%M:%S%.f").to_string(),
+    }
+}
+
+/// Read the declared fractional-seconds scale of a TIME/DATETIME2/DATETIMEOFFSET
+/// cell. Legacy DATETIME/SMALLDATETIME carry no scale and return None.
+fn fractional_scale(row: &tiberius::Row, index: usize) -> Option<u8> {
+    match row.cells().nth(index).map(|(_, data)| data) {
+        Some(ColumnData::Time(Some(t))) => Some(t.scale()),
+        Some(ColumnData::DateTime2(Some(dt))) => Some(dt.time().scale()),
+        Some(ColumnData::DateTimeOffset(Some(dto))) => Some(dto.datetime2().time().scale()),
+        _ => None,
+    }
+}
+
+/// Detect column type from the server-declared type, so each column maps to
+/// exactly one reader. Only types without a fixed reader (XML, UDT,
+/// SQL_VARIANT, DATETIMEOFFSET, ...) fall back to probing.
+fn detect_column_type(row: &tiberius::Row, index: usize) -> ColumnType {
+    use tiberius::ColumnType as Sql;
+    let Some(declared) = row.columns().get(index).map(|c| c.column_type()) else {
+        return ColumnType::Other;
+    };
+    match declared {
+        Sql::Bit | Sql::Bitn => ColumnType::Bool,
+        Sql::Int1 => ColumnType::U8,
+        Sql::Int2 => ColumnType::I16,
+        Sql::Int4 => ColumnType::I32,
+        Sql::Int8 => ColumnType::I64,
+        Sql::Float4 => ColumnType::F32,
+        // MONEY/SMALLMONEY are decoded by tiberius as f64
+        Sql::Float8 | Sql::Money | Sql::Money4 => ColumnType::F64,
+        // Nullable INT/FLOAT share one wire type; the cell carries the width
+        Sql::Intn | Sql::Floatn => match row.cells().nth(index).map(|(_, data)| data) {
+            Some(ColumnData::U8(_)) => ColumnType::U8,
+            Some(ColumnData::I16(_)) => ColumnType::I16,
+            Some(ColumnData::I32(_)) => ColumnType::I32,
+            Some(ColumnData::I64(_)) => ColumnType::I64,
+            Some(ColumnData::F32(_)) => ColumnType::F32,
+            Some(ColumnData::F64(_)) => ColumnType::F64,
+            _ => probe_column_type(row, index),
+        },
+        Sql::Datetime | Sql::Datetime4 | Sql::Datetimen | Sql::Datetime2 => ColumnType::DateTime,
+        Sql::Daten => ColumnType::Date,
+        Sql::Timen => ColumnType::Time,
+        Sql::Decimaln | Sql::Numericn => ColumnType::Decimal,
+        Sql::Guid => ColumnType::Guid,
+        Sql::BigVarChar | Sql::BigChar | Sql::NVarchar | Sql::NChar | Sql::Text | Sql::NText => ColumnType::Str,
+        Sql::BigVarBin | Sql::BigBinary | Sql::Image => ColumnType::Binary,
+        _ => probe_column_type(row, index),
+    }
+}
+
+/// Speculative fallback for declared types without a fixed reader: try each
+/// Rust type in turn until one accepts the cell.
+fn probe_column_type(row: &tiberius::Row, index: usize) -> ColumnType {
+    if row.try_get::<i64, _>(index).ok().flatten().is_some() {
+        return ColumnType::I64;
+    }
+    // Narrower integer types first so SMALLINT/TINYINT resolve to their own reader
+    if row.try_get::<u8, _>(index).ok().flatten().is_some() {
+        return ColumnType::U8;
+    }
+    if row.try_get::<i16, _>(index).ok().flatten().is_some() {
+        return ColumnType::I16;
+    }
+    if row.try_get::<i32, _>(index).ok().flatten().is_some() {
+        return ColumnType::I32;
+    }
+    if row.try_get::<&str, _>(index).ok().flatten().is_some() {
         return ColumnType::Str;
     }
     if row.try_get::<f64, _>(index).ok().flatten().is_some() {
         return ColumnType::F64;
     }
+    if row.try_get::<f32, _>(index).ok().flatten().is_some() {
+        return ColumnType::F32;
+    }
     if row.try_get::<bool, _>(index).ok().flatten().is_some() {
         return ColumnType::Bool;
     }
+    if row.try_get::<NaiveDateTime, _>(index).ok().flatten().is_some() {
+        return ColumnType::DateTime;
+    }
+    if row.try_get::<Numeric, _>(index).ok().flatten().is_some() {
+        return ColumnType::Decimal;
+    }
+    if row.try_get::<Uuid, _>(index).ok().flatten().is_some() {
+        return ColumnType::Guid;
+    }
+    if row.try_get::<&[u8], _>(index).ok().flatten().is_some() {
+        return ColumnType::Binary;
+    }
+    if row.try_get::<NaiveDate, _>(index).ok().flatten().is_some() {
+        return ColumnType::Date;
+    }
+    if row.try_get::<NaiveTime, _>(index).ok().flatten().is_some() {
+        return ColumnType::Time;
+    }
     ColumnType::Other
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(conn_str: &str) -> ConnOptions {
+        parse_connection_string(conn_str).unwrap_or_else(|e| panic!("{}: {}", conn_str, e))
+    }
+
+    #[test]
+    fn isolation_level_selects_set_statement() {
+        let cases = [
+            ("read uncommitted", "SET TRANSACTION ISOLATION LEVEL READ UNCOMMITTED"),
+            ("read committed", "SET TRANSACTION ISOLATION LEVEL READ COMMITTED"),
+            ("ReadCommitted", "SET TRANSACTION ISOLATION LEVEL READ COMMITTED"),
+            ("repeatable  read", "SET TRANSACTION ISOLATION LEVEL REPEATABLE READ"),
+            ("snapshot", "SET TRANSACTION ISOLATION LEVEL SNAPSHOT"),
+            ("SERIALIZABLE", "SET TRANSACTION ISOLATION LEVEL SERIALIZABLE"),
+        ];
+        for (value, expected) in cases {
+            let options = options(&format!("server=localhost;isolation level={}", value));
+            assert_eq!(options.isolation_candidates(), vec![options.isolation_level]);
+            assert_eq!(options.isolation_level.set_statement(), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn isolation_level_defaults_to_snapshot() {
+        let options = options("server=localhost");
+        assert_eq!(options.isolation_level, IsolationLevel::Snapshot);
+    }
+
+    #[test]
+    fn isolation_level_rejects_unknown_value() {
+        let err = parse_connection_string("server=localhost;isolation level=chaos").err();
+        assert_eq!(err.as_deref(), Some("Unknown isolation level 'chaos'"));
+    }
+
+    #[test]
+    fn braced_values_keep_separators() {
+        let pairs = split_connection_string(
+            "server=localhost;user id=sa;password={p=ss;wo}}rd};database=app",
+        )
+        .unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                ("server".to_string(), "localhost".to_string()),
+                ("user id".to_string(), "sa".to_string()),
+                ("password".to_string(), "p=ss;wo}rd".to_string()),
+                ("database".to_string(), "app".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn braced_value_may_end_the_string() {
+        let pairs = split_connection_string("pwd = { a;b=c }").unwrap();
+        assert_eq!(pairs, vec![("pwd".to_string(), " a;b=c ".to_string())]);
+    }
+
+    #[test]
+    fn unterminated_brace_is_an_error() {
+        let err = split_connection_string("server=localhost;password={abc;database=app").err();
+        assert_eq!(err.as_deref(), Some("Unterminated '{' in value for 'password'"));
+        assert!(split_connection_string("password={abc}}").is_err());
+    }
+
+    #[test]
+    fn encrypt_values_map_to_encryption_levels() {
+        for value in ["true", "YES", "mandatory", " Strict "] {
+            assert_eq!(parse_encryption_level(value), Ok(EncryptionLevel::Required), "{}", value);
+        }
+        for value in ["false", "No", "optional"] {
+            assert_eq!(parse_encryption_level(value), Ok(EncryptionLevel::Off), "{}", value);
+        }
+        let config = options("server=localhost;encrypt=false").config;
+        assert!(format!("{:?}", config).contains("encryption: Off"));
+    }
+
+    #[test]
+    fn unknown_encrypt_value_lists_accepted_values() {
+        assert_eq!(
+            parse_encryption_level("maybe"),
+            Err("Unknown encrypt value 'maybe' (expected true, yes, mandatory, strict, false, \
+                 no or optional)"
+                .to_string())
+        );
+    }
+
+    #[test]
+    fn server_value_splits_host_port_and_instance() {
+        let parse = |value: &str| parse_server_value(value).unwrap();
+        assert_eq!(parse("db01,1434"), ("db01".to_string(), Some(1434), None));
+        assert_eq!(parse("tcp:db01"), ("db01".to_string(), None, None));
+        assert_eq!(parse("tcp:db01, 1500"), ("db01".to_string(), Some(1500), None));
+        assert_eq!(parse(r"db01\INST"), ("db01".to_string(), None, Some("INST".to_string())));
+        assert_eq!(
+            parse(r"db01\INST,1434"),
+            ("db01".to_string(), Some(1434), Some("INST".to_string()))
+        );
+        assert_eq!(
+            parse(r".\SQLEXPRESS"),
+            ("localhost".to_string(), None, Some("SQLEXPRESS".to_string()))
+        );
+        assert_eq!(parse("(local)"), ("localhost".to_string(), None, None));
+    }
+
+    #[test]
+    fn server_value_rejects_invalid_port() {
+        for value in ["db01,", "db01,abc", "db01,70000", "db01,-1"] {
+            assert_eq!(
+                parse_server_value(value),
+                Err(format!("Invalid port in server value '{}'", value)),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn explicit_port_wins_over_instance() {
+        assert_eq!(options(r"server=db01\INST,1434").config.get_addr(), "db01:1434");
+        assert_eq!(options("server=db01,1434;port=1500").config.get_addr(), "db01:1500");
+        let config = options(r"server=db01\INST").config;
+        assert!(format!("{:?}", config).contains(r#"instance_name: Some("INST")"#));
+    }
+
+    #[test]
+    fn create_table_gets_primary_key() {
+        assert_eq!(
+            process_create_table("CREATE TABLE dbo.t(name VARCHAR(50), qty INT)"),
+            "CREATE TABLE dbo.t(ID INT PRIMARY KEY IDENTITY(1,1), name VARCHAR(50), qty INT)"
+        );
+        assert_eq!(
+            process_create_table("CREATE TABLE [my(table)] (name NVARCHAR(10))"),
+            "CREATE TABLE [my(table)] (ID INT PRIMARY KEY IDENTITY(1,1), name NVARCHAR(10))"
+        );
+        assert_eq!(
+            process_create_table("CREATE TABLE [db].[dbo].\"t\" (a INT)"),
+            "CREATE TABLE [db].[dbo].\"t\" (ID INT PRIMARY KEY IDENTITY(1,1), a INT)"
+        );
+    }
+
+    #[test]
+    fn create_table_without_column_list_is_untouched() {
+        for sql in [
+            "CREATE TABLE t AS SELECT 1 AS a",
+            "CREATE TABLE t AS (SELECT 1 AS a)",
+            "CREATE TABLE t /* (a INT) */ AS SELECT 1 AS a",
+        ] {
+            assert_eq!(process_create_table(sql), sql);
+        }
+    }
+
+    #[test]
+    fn create_table_with_primary_key_is_untouched() {
+        for sql in [
+            "CREATE TABLE t (code INT PRIMARY KEY, name NVARCHAR(10))",
+            "CREATE TABLE t (a INT, b INT, CONSTRAINT pk_t PRIMARY  KEY (a, b))",
+            "CREATE TABLE t (a INT NOT NULL, CONSTRAINT pk_t primary key clustered (a))",
+        ] {
+            assert_eq!(process_create_table(sql), sql);
+        }
+    }
+
+    #[test]
+    fn primary_key_in_comment_or_literal_does_not_count() {
+        let sql = "CREATE TABLE t (a INT /* PRIMARY KEY ( */, b CHAR(3) DEFAULT 'x)(')";
+        assert_eq!(
+            process_create_table(sql),
+            "CREATE TABLE t (ID INT PRIMARY KEY IDENTITY(1,1), a INT /* PRIMARY KEY ( */, \
+             b CHAR(3) DEFAULT 'x)(')"
+        );
+        let sql = "CREATE TABLE t ( -- primary key\n a VARCHAR(9) DEFAULT 'primary key')";
+        assert_eq!(
+            process_create_table(sql),
+            "CREATE TABLE t (ID INT PRIMARY KEY IDENTITY(1,1),  -- primary key\n \
+             a VARCHAR(9) DEFAULT 'primary key')"
+        );
+    }
+
+    #[test]
+    fn auto_primary_key_can_be_disabled() {
+        let sql = "CREATE TABLE t (a INT)";
+        SetAutoPrimaryKey(false);
+        let untouched = apply_auto_primary_key(sql, &statement_head(sql));
+        SetAutoPrimaryKey(true);
+        assert_eq!(untouched, sql);
+    }
+
+    #[test]
+    fn comment_prefixed_create_table_matches_bare_form() {
+        let bare = "CREATE TABLE t (a INT)";
+        let expected = "CREATE TABLE t (ID INT PRIMARY KEY IDENTITY(1,1), a INT)";
+        assert_eq!(process_create_table(bare), expected);
+        assert_eq!(
+            process_create_table(&format!("/* ddl */\n  -- note\n{}", bare)),
+            format!("/* ddl */\n  -- note\n{}", expected)
+        );
+        assert!(starts_with_keyword(&statement_head("/* ddl */ CREATE TABLE t (a INT)"), "CREATE"));
+    }
+
+    #[test]
+    fn batch_with_several_create_tables_is_untouched() {
+        let sql = "CREATE TABLE a (x INT)\nCREATE TABLE b (y INT PRIMARY KEY)";
+        assert_eq!(process_create_table(sql), sql);
+    }
+
+    #[test]
+    fn existing_id_column_is_not_duplicated() {
+        assert_eq!(
+            process_create_table("CREATE TABLE t (id INT, name NVARCHAR(10))"),
+            "CREATE TABLE t (thinksqlrs_id INT PRIMARY KEY IDENTITY(1,1), id INT, \
+             name NVARCHAR(10))"
+        );
+        assert_eq!(
+            process_create_table("CREATE TABLE t ([Id] INT)"),
+            "CREATE TABLE t (thinksqlrs_id INT PRIMARY KEY IDENTITY(1,1), [Id] INT)"
+        );
+        let both = "CREATE TABLE t (ID INT, THINKSQLRS_ID INT)";
+        assert_eq!(process_create_table(both), both);
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        assert_eq!(
+            redact_secrets("server=db;User Id=sa;Password=hunter2;database=app"),
+            "server=db;User Id=sa;Password=***;database=app"
+        );
+        assert_eq!(redact_secrets("uid=sa; PWD = s3cret ;app=x"), "uid=sa; PWD = *** ;app=x");
+        assert_eq!(
+            redact_secrets("password={p=ss;wo}}rd};database=app"),
+            "password=***;database=app"
+        );
+        assert_eq!(redact_secrets("Access Token=eyJ0eXAi.abc"), "Access Token=***");
+        assert_eq!(
+            redact_secrets("CREATE LOGIN bob WITH PASSWORD = N'it''s; secret'"),
+            "CREATE LOGIN bob WITH PASSWORD = ***"
+        );
+        assert_eq!(
+            redact_connection_string("server=db;pwd={a;b};app={x;y}"),
+            "server=db;pwd=***;app={x;y}"
+        );
+    }
+
+    static TRACED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    // Held by tests that change the trace level and callback
+    static TRACE_SETTINGS: Mutex<()> = Mutex::new(());
+
+    extern "C" fn collect_trace(msg: *const c_char) {
+        let msg = unsafe { CStr::from_ptr(msg) }.to_string_lossy().into_owned();
+        TRACED.lock().unwrap().push(msg);
+    }
+
+    #[test]
+    fn traced_connection_string_is_redacted() {
+        let _settings = lock_or_recover(&TRACE_SETTINGS);
+        SetTraceCallback(Some(collect_trace));
+        SetTraceLevel(TRACE_ERRORS);
+        trace_at(TRACE_ERRORS, "Connect failed: server=db;Password={a;b}};c};pwd=x1");
+        SetTraceLevel(TRACE_OFF);
+        SetTraceCallback(None);
+        let traced = TRACED.lock().unwrap();
+        assert!(traced.iter().any(|msg| msg == "Connect failed: server=db;Password=***;pwd=***"));
+        assert!(!traced.iter().any(|msg| msg.contains("x1") || msg.contains("a;b")));
+    }
+
+    #[test]
+    fn statement_kind_skips_leading_comments() {
+        let kind = |sql: &str| StatementKind::of(&statement_head(sql));
+        assert_eq!(statement_head("  /* note */\n-- more\n select 1 "), "SELECT 1");
+        assert_eq!(kind("/* rows */ SELECT 1"), StatementKind::Select);
+        assert_eq!(kind("-- rows\nSELECT 1"), StatementKind::Select);
+        assert_eq!(kind("WITH c AS (SELECT 1 AS a) SELECT a FROM c"), StatementKind::Select);
+        assert_eq!(kind("(SELECT 1) UNION (SELECT 2)"), StatementKind::Select);
+        assert_eq!(kind("DECLARE @x INT = 1; SELECT @x"), StatementKind::Select);
+        assert_eq!(kind("SELECTED_PROC"), StatementKind::Other);
+    }
+
+    #[test]
+    fn statement_kind_recognizes_exec_and_output() {
+        let kind = |sql: &str| StatementKind::of(&statement_head(sql));
+        assert_eq!(kind("EXEC dbo.usp_rows"), StatementKind::Exec);
+        assert_eq!(kind("/* proc */ execute dbo.usp_rows @a = 1"), StatementKind::Exec);
+        assert_eq!(
+            kind("INSERT INTO t (a) OUTPUT inserted.id VALUES (1)"),
+            StatementKind::Exec
+        );
+        assert_eq!(kind("DELETE FROM t OUTPUT deleted.* WHERE a = 1"), StatementKind::Exec);
+        assert_eq!(
+            kind("INSERT INTO t (a) OUTPUT inserted.id INTO @ids VALUES (1)"),
+            StatementKind::Other
+        );
+        assert_eq!(kind("INSERT INTO t (a) VALUES ('OUTPUT')"), StatementKind::Other);
+        assert_eq!(kind("UPDATE t SET a = 1"), StatementKind::Other);
+    }
+
+    #[test]
+    fn statement_separators_outside_code_are_allowed() {
+        let separated = |sql: &str| has_statement_separator(&mask_comments_and_literals(sql));
+        assert!(!separated("SELECT 'a;b' AS x"));
+        assert!(!separated("SELECT 1 -- ; DROP TABLE x"));
+        assert!(!separated("SELECT 1 /* ; */ AS [a;b]"));
+        assert!(!separated("SELECT 1;"));
+        assert!(!separated("SELECT 1 ; \n ;; "));
+        assert!(separated("SELECT 1; DROP TABLE x"));
+        assert!(separated("SELECT ';'; DROP TABLE x;"));
+    }
+
+    #[test]
+    fn strict_mode_rejects_second_statement() {
+        SetStrictSingleStatement(true);
+        let results = [
+            check_single_statement("SELECT 1; DROP TABLE x"),
+            check_single_statement("SELECT 'a;b';"),
+        ];
+        SetStrictSingleStatement(false);
+        assert_eq!(
+            results[0],
+            Err("Multiple statements are not allowed in strict single-statement mode".to_string())
+        );
+        assert_eq!(results[1], Ok(()));
+    }
+
+    #[test]
+    fn sql_auth_uses_user_and_password() {
+        let config = options("server=db;user id=sa;password=secret").config;
+        assert!(format!("{:?}", config).contains(r#"auth: SqlServer(SqlServerAuth { user: "sa""#));
+        let config = options("server=db;uid=sa;pwd=secret;integrated security=false").config;
+        assert!(format!("{:?}", config).contains("auth: SqlServer("));
+        let config = options("server=db;user id=sa;access token=eyJ0").config;
+        assert!(format!("{:?}", config).contains(r#"auth: AADToken("eyJ0")"#));
+    }
+
+    #[test]
+    fn integrated_auth_ignores_user_and_password() {
+        for value in ["true", "yes", "SSPI"] {
+            let conn_str = format!("server=db;user id=sa;password=x;trusted_connection={}", value);
+            let result = parse_connection_string(&conn_str);
+            #[cfg(windows)]
+            assert!(format!("{:?}", result.unwrap().config).contains("auth: Integrated"));
+            #[cfg(not(windows))]
+            assert_eq!(
+                result.err().as_deref(),
+                Some("Integrated security is only supported on Windows")
+            );
+        }
+    }
+
+    #[test]
+    fn integrated_auth_rejects_unknown_value() {
+        let err = parse_connection_string("server=db;integrated security=kerberos").err();
+        assert_eq!(
+            err.as_deref(),
+            Some(
+                "Invalid integrated security value 'kerberos' (expected true, yes, sspi, false \
+                 or no)"
+            )
+        );
+        let err = parse_connection_string("server=db;integrated security=true;access token=t");
+        assert_eq!(
+            err.err().as_deref(),
+            Some("access token cannot be combined with integrated security")
+        );
+    }
+
+    #[test]
+    fn application_name_defaults_and_overrides() {
+        let config = options("server=db").config;
+        assert!(format!("{:?}", config)
+            .contains(&format!("application_name: Some({:?})", DEFAULT_APPLICATION_NAME)));
+        for conn_str in ["server=db;application name=billing", "server=db;App=billing"] {
+            let config = options(conn_str).config;
+            assert!(format!("{:?}", config).contains(r#"application_name: Some("billing")"#));
+        }
+    }
+
+    #[test]
+    fn strict_conn_str_rejects_unknown_keys() {
+        let conn_str = "server=db;databse=app;Pooling=false;colour=blue";
+        SetStrictConnStr(true);
+        let strict = parse_connection_string(conn_str).err();
+        // Keys with no equivalent here are recognised, so strict mode accepts them
+        let ignored: Vec<_> = IGNORED_CONN_KEYS
+            .iter()
+            .chain(&["packet size"])
+            .map(|key| parse_connection_string(&format!("server=db;{}=4096", key)).err())
+            .collect();
+        SetStrictConnStr(false);
+        assert!(ignored.iter().all(Option::is_none), "{:?}", ignored);
+        assert_eq!(
+            strict.as_deref(),
+            Some("Unrecognized connection string keys: databse, colour")
+        );
+        assert!(parse_connection_string(conn_str).is_ok());
+    }
+
+    #[test]
+    fn named_parameter_used_twice_binds_once() {
+        let (names, params) = parse_named_sql_params(r#"{"@id": 7}"#).unwrap();
+        assert_eq!(names, vec!["id".to_string()]);
+        assert_eq!(params.len(), 1);
+        assert_eq!(
+            bind_named_params("SELECT * FROM t WHERE a=@id OR b=@ID", &names).unwrap(),
+            "SELECT * FROM t WHERE a=@P1 OR b=@P1"
+        );
+    }
+
+    #[test]
+    fn named_parameters_skip_literals_comments_and_system_functions() {
+        let (names, _) = parse_named_sql_params(r#"{"id": 1, "@Name": "x"}"#).unwrap();
+        let sql = "SELECT '@id' AS a, [@id] AS b, @@ROWCOUNT AS c, @other AS d -- @id\n\
+                   WHERE x = @name AND y = @id2 /* @name */";
+        let position = |name: &str| names.iter().position(|n| n == name).unwrap() + 1;
+        assert_eq!(
+            bind_named_params(sql, &names).unwrap(),
+            format!(
+                "SELECT '@id' AS a, [@id] AS b, @@ROWCOUNT AS c, @other AS d -- @id\n\
+                 WHERE x = @P{} AND y = @id2 /* @name */",
+                position("name")
+            )
+        );
+    }
+
+    #[test]
+    fn named_parameter_errors_use_the_callers_name() {
+        let err = parse_named_sql_params(r#"{"@id": [1, 2]}"#).err();
+        assert_eq!(
+            err.as_deref(),
+            Some("Parameter @id must be a string, number, bool or null")
+        );
+        let err = parse_named_sql_params(r#"{"@id": 1, "ID": 2}"#).err();
+        assert_eq!(err.as_deref(), Some("Duplicate parameter name: ID"));
+        let err = parse_named_sql_params(r#"{"@P1": 1}"#).err();
+        assert_eq!(err.as_deref(), Some("Invalid parameter name: @P1"));
+        let err = parse_named_sql_params("[1]").err();
+        assert_eq!(err.as_deref(), Some("Parameter JSON must be an object"));
+        let err = bind_named_params("SELECT @P1, @id", &["id".to_string()]).err();
+        assert_eq!(err.as_deref(), Some("@P1 cannot be used together with named parameters"));
+    }
+
+    #[test]
+    fn paged_batch_adds_offset_fetch_and_count() {
+        assert_eq!(
+            build_paged_batch("SELECT id, name FROM t WHERE a > 1 ORDER BY id;").unwrap(),
+            "SELECT id, name FROM t WHERE a > 1 ORDER BY id OFFSET @P1 ROWS FETCH NEXT @P2 ROWS \
+             ONLY;\nSELECT COUNT_BIG(*) FROM (SELECT 1 AS [__row] FROM t WHERE a > 1) AS [__paged];"
+        );
+        let cte = "WITH c AS (SELECT id FROM t ORDER BY id OFFSET 0 ROWS) \
+                   SELECT id FROM c ORDER BY id DESC";
+        assert_eq!(
+            build_paged_batch(cte).unwrap(),
+            "WITH c AS (SELECT id FROM t ORDER BY id OFFSET 0 ROWS) SELECT id FROM c ORDER BY id \
+             DESC OFFSET @P1 ROWS FETCH NEXT @P2 ROWS ONLY;\nWITH c AS (SELECT id FROM t ORDER \
+             BY id OFFSET 0 ROWS) SELECT COUNT_BIG(*) FROM (SELECT 1 AS [__row] FROM c) AS \
+             [__paged];"
+        );
+    }
+
+    #[test]
+    fn paged_count_ignores_the_select_list() {
+        let count = |sql: &str| {
+            let batch = build_paged_batch(sql).unwrap();
+            batch.split_once('\n').unwrap().1.to_string()
+        };
+        // Unnamed aggregates
+        assert_eq!(
+            count(
+                "SELECT dept, COUNT(*), MAX(pay) FROM emp GROUP BY dept HAVING COUNT(*) > 1 \
+                 ORDER BY dept"
+            ),
+            "SELECT COUNT_BIG(*) FROM (SELECT 1 AS [__row] FROM emp GROUP BY dept HAVING COUNT(*) \
+             > 1) AS [__paged];"
+        );
+        // Duplicate column names from a join
+        assert_eq!(
+            count("SELECT a.id, b.id FROM a JOIN b ON b.a_id = a.id ORDER BY a.id"),
+            "SELECT COUNT_BIG(*) FROM (SELECT 1 AS [__row] FROM a JOIN b ON b.a_id = a.id) AS \
+             [__paged];"
+        );
+        assert_eq!(
+            count("SELECT (SELECT MAX(x) FROM u), 2 ORDER BY 1"),
+            "SELECT COUNT_BIG(*) FROM (SELECT 1 AS [__row]) AS [__paged];"
+        );
+        // The select list decides which rows DISTINCT and UNION keep
+        assert_eq!(
+            count("SELECT DISTINCT dept FROM emp ORDER BY dept"),
+            "SELECT COUNT_BIG(*) FROM (SELECT DISTINCT dept FROM emp) AS [__paged];"
+        );
+        assert_eq!(
+            count("SELECT id FROM a UNION SELECT id FROM b ORDER BY id"),
+            "SELECT COUNT_BIG(*) FROM (SELECT id FROM a UNION SELECT id FROM b) AS [__paged];"
+        );
+    }
+
+    #[test]
+    fn paged_batch_requires_top_level_order_by() {
+        let missing =
+            Err("ExecutePaged requires the query to end in an ORDER BY clause".to_string());
+        assert_eq!(build_paged_batch("SELECT id FROM t"), missing);
+        assert_eq!(
+            build_paged_batch("SELECT id FROM (SELECT TOP 5 id FROM t ORDER BY id) AS s"),
+            missing
+        );
+        assert_eq!(build_paged_batch("SELECT 'ORDER BY id' AS a FROM t"), missing);
+        assert_eq!(build_paged_batch("SELECT id FROM t -- ORDER BY id"), missing);
+    }
+
+    #[test]
+    fn paged_batch_rejects_other_statements() {
+        assert_eq!(
+            build_paged_batch("DELETE FROM t"),
+            Err("ExecutePaged only runs SELECT statements".to_string())
+        );
+        assert_eq!(
+            build_paged_batch("SELECT id FROM t ORDER BY id; SELECT 1"),
+            Err("ExecutePaged takes a single SELECT statement".to_string())
+        );
+        assert_eq!(
+            build_paged_batch("SELECT id FROM t ORDER BY id OFFSET 5 ROWS"),
+            Err("ExecutePaged does not support OFFSET after ORDER BY".to_string())
+        );
+    }
+
+    #[test]
+    fn duplicate_column_names_get_free_suffixes() {
+        let unique = |names: &[&str]| unique_column_names(names.iter().copied());
+        assert_eq!(unique(&["id", "name", "ID", "Id"]), ["id", "name", "ID_1", "Id_2"]);
+        assert_eq!(unique(&["id", "id", "id_1"]), ["id", "id_2", "id_1"]);
+        assert_eq!(unique(&["id_1", "id", "id"]), ["id_1", "id", "id_2"]);
+        assert_eq!(unique(&["", ""]), ["", "_1"]);
+    }
+
+    #[test]
+    fn date_pattern_must_apply_to_column_values() {
+        for format in ["%Y-%m-%d %H:%M:%S", "%d/%m/%Y", "%s%.3f", "literal"] {
+            assert_eq!(check_date_pattern(format), Ok(()), "{}", format);
+        }
+        for format in ["%z", "%Y %Z", "%:z", "%Q", "%", ""] {
+            assert_eq!(
+                check_date_pattern(format),
+                Err(format!("Invalid date format '{}'", format)),
+                "{}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn empty_result_set_still_has_csv_header() {
+        let set = ResultSet {
+            columns: vec![
+                tiberius::Column::new("id".to_string(), tiberius::ColumnType::Int4),
+                tiberius::Column::new("note, text".to_string(), tiberius::ColumnType::NVarchar),
+                tiberius::Column::new("ID".to_string(), tiberius::ColumnType::Int4),
+            ],
+            rows: Vec::new(),
+        };
+        assert_eq!(rows_to_csv(&set), "id,\"note, text\",ID_1\r\n");
+        assert_eq!(rows_to_csv(&ResultSet::default()), "");
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("Smith, John"), "\"Smith, John\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("cr\r"), "\"cr\r\"");
+        assert!(matches!(csv_field("no quoting needed"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn only_read_only_selects_are_replayed() {
+        assert!(is_read_only_select("SELECT * FROM t"));
+        assert!(is_read_only_select("-- note\nSELECT 'insert into' AS x"));
+        assert!(is_read_only_select("WITH c AS (SELECT 1 AS n) SELECT n FROM c"));
+        assert!(!is_read_only_select("SELECT * INTO #copy FROM t"));
+        assert!(!is_read_only_select("INSERT INTO t VALUES (1)"));
+        assert!(!is_read_only_select("UPDATE t SET a = 1"));
+        assert!(!is_read_only_select("EXEC dbo.p"));
+        assert!(!is_read_only_select("WITH c AS (SELECT 1 AS n) DELETE FROM t"));
+    }
+
+    #[test]
+    fn only_the_tiberius_info_event_is_captured() {
+        let result = run_statement(async {
+            tracing::info!("not a server message");
+            Ok::<_, String>(())
+        });
+        assert!(result.is_ok());
+        assert!(LAST_MESSAGES.with(|messages| messages.borrow().is_empty()));
+    }
+
+    /// Set THINKSQLRS_TEST_CONNECTION to a connection string and run with
+    /// `cargo test -- --ignored` to check against a live server.
+    #[test]
+    #[ignore = "needs a SQL Server (THINKSQLRS_TEST_CONNECTION)"]
+    fn print_output_is_captured() {
+        let conn_str = std::env::var("THINKSQLRS_TEST_CONNECTION")
+            .expect("THINKSQLRS_TEST_CONNECTION is not set");
+        let conn_str = CString::new(conn_str).unwrap();
+        let error = unsafe { ConnectDb(conn_str.as_ptr()) };
+        assert!(error.is_null(), "ConnectDb failed");
+
+        let sql = CString::new("PRINT 'hello'").unwrap();
+        let result = unsafe { ExecuteSql(sql.as_ptr()) };
+        assert!(result.is_null(), "PRINT returned a result");
+        let messages = GetLastMessages();
+        let json = unsafe { CStr::from_ptr(messages) }.to_str().unwrap().to_string();
+        unsafe { FreeCString(messages as *mut c_char) };
+        assert_eq!(json, r#"["hello"]"#);
+        DisconnectDb();
+    }
+
+    #[test]
+    fn go_batches_split_outside_comments_and_literals() {
+        let split = |script: &str| split_go_batches(script).unwrap();
+        let batch = |sql: &str, count| (sql.to_string(), count);
+        assert_eq!(
+            split("SELECT 1\nGO\nSELECT 2\n"),
+            vec![batch("SELECT 1", 1), batch("SELECT 2", 1)]
+        );
+        assert_eq!(
+            split("SELECT 1\r\n  go  \r\nSELECT 2"),
+            vec![batch("SELECT 1", 1), batch("SELECT 2", 1)]
+        );
+        assert_eq!(split("PRINT 'a\nGO\nb'"), vec![batch("PRINT 'a\nGO\nb'", 1)]);
+        assert_eq!(split("/* one\nGO\n*/ SELECT 1"), vec![batch("/* one\nGO\n*/ SELECT 1", 1)]);
+        assert_eq!(split("SELECT 1\nGO -- next\n"), vec![batch("SELECT 1", 1)]);
+        assert_eq!(split("GO\n\nGO\n"), Vec::new());
+        assert_eq!(split("GOTO done\ndone:"), vec![batch("GOTO done\ndone:", 1)]);
+    }
+
+    #[test]
+    fn go_with_a_count_repeats_the_batch() {
+        assert_eq!(
+            split_go_batches("INSERT t VALUES (1)\nGO 3\nSELECT 1").unwrap(),
+            vec![("INSERT t VALUES (1)".to_string(), 3), ("SELECT 1".to_string(), 1)]
+        );
+        assert!(split_go_batches("SELECT 1\nGO 0").is_err());
+        assert!(split_go_batches("SELECT 1\nGO x").is_err());
+        assert!(split_go_batches("SELECT 1\nGO 2 3").is_err());
+    }
+
+    #[test]
+    fn empty_slot_reports_an_open_stream() {
+        assert_eq!(empty_slot_error(), "Database not connected. Call ConnectDb first.");
+        GLOBAL_STREAM.store(u64::MAX, Ordering::Relaxed);
+        assert_eq!(empty_slot_error(), CONNECTION_BUSY);
+        GLOBAL_STREAM.store(0, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn decimals_keep_every_digit() {
+        let json = |value, scale| decimal_to_json(Numeric::new_with_scale(value, scale)).unwrap();
+        assert_eq!(json(999_999_999_999_999_999_999, 4).to_string(), "99999999999999999.9999");
+        assert_eq!(json(-5, 2).to_string(), "-0.05");
+        assert_eq!(json(1_000, 0).to_string(), "1000");
+        assert_eq!(
+            to_json_string(&serde_json::json!({ "d": json(12_345_678_901_234_567, 2) })).unwrap(),
+            r#"{"d":123456789012345.67}"#
+        );
+    }
+
+    fn take_c_string(ptr: *const c_char) -> String {
+        assert!(!ptr.is_null());
+        let text = unsafe { CStr::from_ptr(ptr) }.to_string_lossy().into_owned();
+        unsafe { FreeCString(ptr as *mut c_char) };
+        text
+    }
+
+    #[test]
+    fn fetch_rows_reports_a_producer_that_died() {
+        let handle = NEXT_HANDLE.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel::<StreamItem>(1);
+        lock_or_recover(get_cursors()).insert(handle, rx);
+        drop(tx);
+        let chunk = take_c_string(FetchRows(handle, 10));
+        assert_eq!(chunk, "ERROR: query stream ended unexpectedly");
+        assert!(lock_or_recover(get_cursors()).get(&handle).is_none());
+    }
+
+    #[test]
+    fn fetch_rows_reports_a_clean_end_as_done() {
+        let handle = NEXT_HANDLE.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel::<StreamItem>(1);
+        lock_or_recover(get_cursors()).insert(handle, rx);
+        tx.try_send(Ok(None)).unwrap();
+        let chunk = take_c_string(FetchRows(handle, 10));
+        assert_eq!(chunk, r#"{"done":true,"rows":[]}"#);
+    }
+
+    #[test]
+    fn driver_panic_becomes_an_error() {
+        fn decode() -> Result<(), String> {
+            panic!("not yet implemented")
+        }
+        let result = get_runtime().block_on(catch_driver_panic(async { decode() }));
+        assert_eq!(result, Err(UNDECODABLE_RESPONSE.to_string()));
+    }
+
+    #[test]
+    fn abandoning_errors_are_recognised() {
+        assert!(is_abandoning_error(QUERY_TIMED_OUT));
+        assert!(is_abandoning_error(QUERY_CANCELLED));
+        assert!(is_abandoning_error(UNDECODABLE_RESPONSE));
+        assert!(!is_abandoning_error("Batch failed: Invalid object name 'x'."));
+    }
+
+    /// Minimal TDS endpoint for one connection: accepts the PRELOGIN (no
+    /// encryption) and LOGIN7, then answers every batch with `reply` tokens.
+    async fn fake_tds_server(listener: tokio::net::TcpListener, reply: Vec<u8>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        async fn read_message(socket: &mut TcpStream) -> Option<Vec<u8>> {
+            let mut payload = Vec::new();
+            loop {
+                let mut header = [0u8; 8];
+                socket.read_exact(&mut header).await.ok()?;
+                let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+                let mut body = vec![0; len - header.len()];
+                socket.read_exact(&mut body).await.ok()?;
+                payload.extend(body);
+                if header[1] & 0x01 != 0 {
+                    return Some(payload);
+                }
+            }
+        }
+        async fn write_message(socket: &mut TcpStream, payload: &[u8]) {
+            let mut packet = vec![0x04, 0x01];
+            packet.extend(((payload.len() + 8) as u16).to_be_bytes());
+            packet.extend([0, 0, 1, 0]);
+            packet.extend(payload);
+            socket.write_all(&packet).await.unwrap();
+        }
+
+        let (mut socket, _) = listener.accept().await.unwrap();
+        read_message(&mut socket).await.unwrap();
+        // VERSION and ENCRYPTION = NOT_SUP
+        let prelogin = [0x00, 0, 11, 0, 6, 0x01, 0, 17, 0, 1, 0xff, 16, 0, 0, 0, 0, 0, 2];
+        write_message(&mut socket, &prelogin).await;
+        read_message(&mut socket).await.unwrap();
+        let mut login_ack = vec![0x01];
+        login_ack.extend(0x7400_0004u32.to_be_bytes());
+        login_ack.extend(b_varchar("Microsoft SQL Server"));
+        login_ack.extend(0x1000_0000u32.to_le_bytes());
+        let mut login = token(0xAD, &login_ack);
+        login.extend(done_token());
+        write_message(&mut socket, &login).await;
+        while read_message(&mut socket).await.is_some() {
+            write_message(&mut socket, &reply).await;
+        }
+    }
+
+    fn utf16(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    fn b_varchar(text: &str) -> Vec<u8> {
+        let mut bytes = vec![text.encode_utf16().count() as u8];
+        bytes.extend(utf16(text));
+        bytes
+    }
+
+    fn token(kind: u8, body: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![kind];
+        bytes.extend((body.len() as u16).to_le_bytes());
+        bytes.extend(body);
+        bytes
+    }
+
+    fn info_token(message: &str) -> Vec<u8> {
+        let mut body = vec![0, 0, 0, 0, 1, 0];
+        body.extend((message.encode_utf16().count() as u16).to_le_bytes());
+        body.extend(utf16(message));
+        body.extend(b_varchar("fake"));
+        body.extend(b_varchar(""));
+        body.extend(1u32.to_le_bytes());
+        token(0xAB, &body)
+    }
+
+    fn done_token() -> Vec<u8> {
+        let mut bytes = vec![0xFD, 0, 0, 0, 0];
+        bytes.extend(0u64.to_le_bytes());
+        bytes
+    }
+
+    /// Client logged in to a fake_tds_server answering every batch with `reply`
+    fn connect_to_fake_server(reply: Vec<u8>) -> TibClient {
+        let runtime = get_runtime();
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        runtime.spawn(fake_tds_server(listener, reply));
+
+        let mut config = Config::new();
+        config.host("127.0.0.1");
+        config.port(port);
+        config.encryption(EncryptionLevel::NotSupported);
+        config.authentication(tiberius::AuthMethod::sql_server("sa", "x"));
+        runtime
+            .block_on(async {
+                let tcp = TcpStream::connect(("127.0.0.1", port)).await?;
+                Client::connect(config, tcp.compat_write()).await
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn print_messages_are_captured_from_the_tds_stream() {
+        // USE tempdb; PRINT 'hello'; BEGIN TRAN
+        let mut reply = vec![0x01];
+        reply.extend(b_varchar("tempdb"));
+        reply.extend(b_varchar("master"));
+        let mut reply = token(0xE3, &reply);
+        reply.extend(info_token("hello"));
+        reply.extend(token(0xE3, &[0x08, 8, 1, 0, 0, 0, 0, 0, 0, 0, 0]));
+        reply.extend(done_token());
+        let mut client = connect_to_fake_server(reply);
+        run_statement(async {
+            let stream = client.simple_query("PRINT 'hello'").await.map_err(|e| e.to_string())?;
+            stream.into_results().await.map_err(|e| e.to_string())
+        })
+        .unwrap();
+        let messages = LAST_MESSAGES.with(|messages| messages.borrow().clone());
+        assert_eq!(messages, vec!["hello".to_string()]);
+    }
+
+    #[test]
+    fn tiberius_notices_are_not_server_messages() {
+        for notice in [
+            "Database change from 'master' to 'tempdb'",
+            "Begin transaction",
+            "Ignored env change: `Language`",
+            "Microsoft SQL Server version 268435456",
+        ] {
+            assert!(TIBERIUS_NOTICE_RE.is_match(notice), "{}", notice);
+        }
+        assert!(!TIBERIUS_NOTICE_RE.is_match("hello"));
+        assert!(!TIBERIUS_NOTICE_RE.is_match("Begin transaction\nstep 2"));
+    }
+
+    type AsyncResult = (Option<String>, Option<String>, Option<String>);
+    static ASYNC_RESULTS: Mutex<Vec<AsyncResult>> = Mutex::new(Vec::new());
+
+    extern "C" fn collect_async_result(
+        result: *const c_char,
+        error: *const c_char,
+        messages: *const c_char,
+    ) {
+        let text = |ptr: *const c_char| {
+            (!ptr.is_null()).then(|| unsafe { CStr::from_ptr(ptr) }.to_string_lossy().into_owned())
+        };
+        ASYNC_RESULTS.lock().unwrap().push((text(result), text(error), text(messages)));
+    }
+
+    #[test]
+    fn async_execution_runs_on_the_blocking_pool() {
+        // Statements drive the runtime with block_on, which must work there
+        let ran = get_runtime().spawn_blocking(|| run_statement(async { Ok::<_, String>(7) }));
+        assert_eq!(get_runtime().block_on(ran).unwrap(), Ok(7));
+
+        let sql = CString::new("SELECT 1").unwrap();
+        let queued = unsafe { ExecuteSqlAsync(sql.as_ptr(), Some(collect_async_result)) };
+        assert!(queued.is_null());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while ASYNC_RESULTS.lock().unwrap().is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let (result, error, messages) =
+            ASYNC_RESULTS.lock().unwrap().pop().expect("callback not called");
+        assert_eq!(result, None);
+        assert!(error.unwrap().contains("Database not connected"));
+        assert_eq!(messages.as_deref(), Some("[]"));
+    }
+
+    #[test]
+    fn prepared_cache_evicts_the_least_recently_used() {
+        let mut cache = PreparedCache { capacity: 2, ..Default::default() };
+        assert!(cache.insert("a".to_string(), 1).is_empty());
+        assert!(cache.insert("b".to_string(), 2).is_empty());
+        assert_eq!(cache.lookup("a"), Some(1));
+        assert_eq!(cache.insert("c".to_string(), 3), vec![2]);
+        assert_eq!(cache.lookup("b"), None);
+        assert_eq!(cache.take_handles(), vec![1, 3]);
+        assert_eq!((cache.hits, cache.prepares), (1, 3));
+    }
+
+    #[test]
+    fn prepared_declarations_match_the_driver() {
+        let params = [
+            SqlParam::Null,
+            SqlParam::Bool(true),
+            SqlParam::Int32(1),
+            SqlParam::Int(2),
+            SqlParam::Float(0.5),
+            SqlParam::Str("x".repeat(4000)),
+            SqlParam::Str("x".repeat(4001)),
+        ];
+        assert_eq!(
+            param_declarations(&params),
+            "@P1 nvarchar(4000), @P2 bit, @P3 int, @P4 bigint, @P5 float(53), \
+             @P6 nvarchar(4000), @P7 nvarchar(max)"
+        );
+        assert_eq!(param_declarations(&[]), "");
+    }
+
+    #[test]
+    fn repeated_parameterized_queries_prepare_once() {
+        // Every batch returns one INT row: the handle for sp_prepare, a
+        // result row for sp_execute
+        let mut reply = vec![0x81, 1, 0, 0, 0, 0, 0, 0x01, 0x00, 0x26, 4];
+        reply.extend(b_varchar(""));
+        reply.extend([0xD1, 4, 7, 0, 0, 0]);
+        reply.extend(done_token());
+        let mut client = connect_to_fake_server(reply);
+        let cache = Mutex::new(PreparedCache { capacity: 8, ..Default::default() });
+
+        let sql = "SELECT id FROM t WHERE id = @P1";
+        let kind = StatementKind::Select;
+        for id in 0..5 {
+            let params = [SqlParam::Int(id)];
+            let result =
+                run_statement(execute_params_query(&mut client, sql, &params, kind, Some(&cache)));
+            assert_eq!(result.unwrap().as_deref(), Some(r#"[{"":7}]"#));
+        }
+        let params = [SqlParam::Str("1".to_string())];
+        run_statement(execute_params_query(&mut client, sql, &params, kind, Some(&cache)))
+            .unwrap();
+
+        let cache = cache.into_inner().unwrap();
+        assert_eq!((cache.prepares, cache.hits), (2, 4));
+        assert_eq!(cache.entries.len(), 2);
+    }
+
+    #[test]
+    fn session_reset_restores_connection_defaults() {
+        for statement in [
+            "IF @@TRANCOUNT > 0 ROLLBACK TRANSACTION;",
+            "SET IMPLICIT_TRANSACTIONS OFF;",
+            "SET XACT_ABORT OFF;",
+            "SET NOCOUNT OFF;",
+            "SET ROWCOUNT 0;",
+            "SET TEXTSIZE 2147483647;",
+            "SET ANSI_NULLS ON;",
+            "SET ANSI_NULL_DFLT_ON ON;",
+            "SET ANSI_PADDING ON;",
+            "SET ANSI_WARNINGS ON;",
+            "SET CONCAT_NULL_YIELDS_NULL ON;",
+            "SET QUOTED_IDENTIFIER ON;",
+            "SET ARITHABORT OFF;",
+            "SET NUMERIC_ROUNDABORT OFF;",
+            "SET CONTEXT_INFO 0x;",
+            "SET LANGUAGE @__language;",
+            "SET DATEFORMAT @__dateformat;",
+            "SET DATEFIRST @__datefirst;",
+        ] {
+            assert!(RESET_SESSION_SQL.contains(statement), "{}", statement);
+        }
+    }
+
+    #[test]
+    fn tvp_rows_are_sent_as_one_json_parameter() {
+        let columns = vec![
+            ("Id".to_string(), "int".to_string()),
+            ("Note's \"x\"".to_string(), "nvarchar(50)".to_string()),
+        ];
+        let rows: Vec<serde_json::Map<String, Value>> =
+            serde_json::from_str(r#"[{"id":1},{"ID":2,"note's \"x\"":"b"}]"#).unwrap();
+        let (sql, json) = build_tvp_insert("dbo.IntList", &columns, &rows, 2).unwrap();
+        assert_eq!(
+            sql,
+            "DECLARE @__tvp dbo.IntList; INSERT INTO @__tvp ([Id], [Note's \"x\"]) SELECT [Id], \
+             [Note's \"x\"] FROM OPENJSON(@P3) WITH ([Id] int '$.\"Id\"', [Note's \"x\"] \
+             nvarchar(50) '$.\"Note''s \\\"x\\\"\"'); "
+        );
+        assert_eq!(json, r#"[{"Id":1},{"Id":2,"Note's \"x\"":"b"}]"#);
+
+        let (sql, json) = build_tvp_insert("dbo.IntList", &columns, &[], 0).unwrap();
+        assert_eq!((sql.as_str(), json.as_str()), ("DECLARE @__tvp dbo.IntList; ", "[]"));
+    }
+
+    #[test]
+    fn tvp_rows_must_match_the_table_type() {
+        let columns = vec![("Id".to_string(), "int".to_string())];
+        let rows = |json: &str| serde_json::from_str::<Vec<serde_json::Map<String, Value>>>(json);
+        assert_eq!(
+            build_tvp_insert("dbo.IntList", &columns, &rows(r#"[{"Id":1},{"Ids":2}]"#).unwrap(), 0),
+            Err("Row 2 has 'Ids', which is not a column of dbo.IntList".to_string())
+        );
+        assert_eq!(
+            build_tvp_insert("dbo.IntList", &columns, &rows(r#"[{"Id":[1]}]"#).unwrap(), 0),
+            Err("Row 1 column 'Id' must be a string, number, bool or null".to_string())
+        );
+    }
+
+    #[test]
+    fn free_c_string_ignores_double_frees_and_foreign_pointers() {
+        let ptr = hand_out_c_string(CString::new("handed out").unwrap()) as *mut c_char;
+        assert!(unsafe { reclaim_c_string(ptr) });
+        // A second free of the same pointer is rejected without touching it
+        assert!(!unsafe { reclaim_c_string(ptr) });
+        unsafe { FreeCString(ptr) };
+
+        // Memory this library never handed out is left alone
+        let foreign = CString::new("caller's own").unwrap();
+        let foreign_ptr = foreign.as_ptr() as *mut c_char;
+        assert!(!unsafe { reclaim_c_string(foreign_ptr) });
+        unsafe { FreeCString(foreign_ptr) };
+        assert_eq!(foreign.to_str(), Ok("caller's own"));
+
+        unsafe { FreeCString(std::ptr::null_mut()) };
+    }
+
+    #[test]
+    fn packet_size_and_ado_net_only_keys_are_accepted() {
+        for size in ["512", "4096", "8000", "32767"] {
+            options(&format!("server=db;Packet Size={}", size));
+        }
+        for size in ["511", "32768", "big"] {
+            let err = parse_connection_string(&format!("server=db;packet size={}", size)).err();
+            assert_eq!(
+                err,
+                Some(format!("Invalid packet size '{}' (expected 512-32767 bytes)", size))
+            );
+        }
+    }
+
+    #[test]
+    fn application_intent_sets_read_only_routing() {
+        let readonly =
+            |conn_str: &str| format!("{:?}", options(conn_str).config).contains("readonly: true");
+        assert!(!readonly("server=db"));
+        for key in ["ApplicationIntent", "Application Intent"] {
+            assert!(readonly(&format!("server=db;{}=ReadOnly", key)), "{}", key);
+            assert!(readonly(&format!("server=db;{}=read only", key)), "{}", key);
+            assert!(!readonly(&format!("server=db;{}=ReadWrite", key)), "{}", key);
+            let err = parse_connection_string(&format!("server=db;{}=primary", key)).err();
+            assert_eq!(
+                err.as_deref(),
+                Some("Invalid application intent 'primary' (expected ReadOnly or ReadWrite)")
+            );
+        }
+    }
+
+    #[test]
+    fn server_aliases_set_the_host() {
+        for key in ["Server", "Host", "Data Source", "Addr", "Address", "Network Address"] {
+            let config = options(&format!("{}=db07,1500;user id=sa", key)).config;
+            assert_eq!(config.get_addr(), "db07:1500", "{}", key);
+        }
+        assert_eq!(options("data source=tcp:db07").config.get_addr(), "db07:1433");
+    }
+
+    #[test]
+    fn multiple_active_result_sets_is_accepted_and_ignored() {
+        let plain = format!("{:?}", options("server=db;database=app").config);
+        for value in ["True", "true", "yes", "False"] {
+            for key in ["MultipleActiveResultSets", "Multiple Active Result Sets"] {
+                let conn_str = format!("server=db;{}={};database=app", key, value);
+                assert_eq!(format!("{:?}", options(&conn_str).config), plain, "{}", conn_str);
+            }
+        }
+    }
+
+    #[test]
+    fn keepalive_keys_set_the_probe_interval() {
+        let keepalive = |conn_str: &str| options(conn_str).tcp_keepalive;
+        let default = Some(Duration::from_secs(DEFAULT_KEEPALIVE_INTERVAL_SECS));
+        assert_eq!(keepalive("server=db"), None);
+        assert_eq!(keepalive("server=db;tcp keepalive=true"), default);
+        let secs = |secs| Some(Duration::from_secs(secs));
+        assert_eq!(keepalive("server=db;KeepAlive=yes;keepalive interval=10"), secs(10));
+        assert_eq!(keepalive("server=db;keepalive=1;keepalive interval=45s"), secs(45));
+        // An interval alone does not turn keepalive on
+        assert_eq!(keepalive("server=db;keepalive interval=10;tcp keepalive=false"), None);
+        for interval in ["soon", "0", "-5", "10m"] {
+            let conn_str = format!("server=db;tcp keepalive=true;keepalive interval={}", interval);
+            assert_eq!(
+                parse_connection_string(&conn_str).err(),
+                Some(format!("Invalid keepalive interval '{}' (expected seconds)", interval))
+            );
+        }
+    }
+
+    #[test]
+    fn non_finite_floats_become_null_or_strings() {
+        assert_eq!(float_to_json(1.5), serde_json::json!(1.5));
+        for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert_eq!(float_to_json(value), Value::Null, "{}", value);
+        }
+        SetNonFiniteAsString(true);
+        let strings = [f64::NAN, f64::INFINITY, f64::NEG_INFINITY].map(float_to_json);
+        let finite = float_to_json(-2.25);
+        SetNonFiniteAsString(false);
+        assert_eq!(strings, [
+            Value::String("NaN".to_string()),
+            Value::String("Infinity".to_string()),
+            Value::String("-Infinity".to_string()),
+        ]);
+        assert_eq!(finite, serde_json::json!(-2.25));
+    }
+
+    #[test]
+    fn json_errors_carry_the_server_error_details() {
+        let info = SqlErrorInfo { number: 208, severity: 16, state: 1 };
+        PENDING_SQL_ERROR.with(|pending| pending.set(Some(info)));
+        let msg = "ERROR: Invalid object name 'nope'.";
+        let sql = set_last_error(msg);
+        assert_eq!(LAST_ERROR_CODE.with(Cell::get), 208);
+        let parsed: Value = serde_json::from_str(&error_json(msg, sql)).unwrap();
+        assert_eq!(
+            parsed,
+            serde_json::json!({
+                "error": true,
+                "message": "Invalid object name 'nope'.",
+                "sqlErrorNumber": 208,
+                "severity": 16,
+                "state": 1,
+            })
+        );
+
+        // Client-side failures have no server details
+        let msg = "ERROR: Database not connected.";
+        let sql = set_last_error(msg);
+        assert_eq!(LAST_ERROR_CODE.with(Cell::get), -1);
+        let parsed: Value = serde_json::from_str(&error_json(msg, sql)).unwrap();
+        assert_eq!(
+            parsed,
+            serde_json::json!({
+                "error": true,
+                "message": "Database not connected.",
+                "sqlErrorNumber": null,
+                "severity": null,
+                "state": null,
+            })
+        );
+    }
+
+    #[test]
+    fn typed_params_decode_each_tag() {
+        let typed = |tag: u8, text: &str| {
+            let value = CString::new(text).unwrap();
+            unsafe { typed_sql_param(1, tag, value.as_ptr()) }
+        };
+        let decoded = |tag: u8, text: &str| format!("{:?}", typed(tag, text).unwrap().to_sql());
+        let column = |data: ColumnData<'_>| format!("{:?}", data);
+        assert_eq!(decoded(PARAM_TYPE_NULL, "42"), column(ColumnData::String(None)));
+        assert_eq!(decoded(PARAM_TYPE_INT, " 42 "), column(ColumnData::I32(Some(42))));
+        let big = ColumnData::I64(Some(-9_000_000_000));
+        assert_eq!(decoded(PARAM_TYPE_BIGINT, "-9000000000"), column(big));
+        assert_eq!(decoded(PARAM_TYPE_DOUBLE, "3.5"), column(ColumnData::F64(Some(3.5))));
+        assert_eq!(decoded(PARAM_TYPE_BOOL, "TRUE"), column(ColumnData::Bit(Some(true))));
+        assert_eq!(decoded(PARAM_TYPE_BOOL, "0"), column(ColumnData::Bit(Some(false))));
+        let text = ColumnData::String(Some(" x ".into()));
+        assert_eq!(decoded(PARAM_TYPE_STRING, " x "), column(text));
+        let null = unsafe { typed_sql_param(0, PARAM_TYPE_INT, std::ptr::null()) }.unwrap();
+        assert_eq!(format!("{:?}", null.to_sql()), column(ColumnData::String(None)));
+
+        assert_eq!(
+            typed(PARAM_TYPE_INT, "9000000000").err().as_deref(),
+            Some("Parameter @P2 is not a valid INT: '9000000000'")
+        );
+        assert_eq!(
+            typed(PARAM_TYPE_BOOL, "yes").err().as_deref(),
+            Some("Parameter @P2 is not a valid BIT: 'yes'")
+        );
+        assert_eq!(typed(9, "1").err().as_deref(), Some("Unknown type tag 9 for parameter @P2"));
+    }
+
+    #[test]
+    fn trace_level_filters_by_verbosity() {
+        let _settings = lock_or_recover(&TRACE_SETTINGS);
+        let mut levels = Vec::new();
+        for level in [TRACE_OFF, TRACE_ERRORS, TRACE_STATEMENTS, TRACE_VERBOSE, 4, u32::MAX] {
+            SetTraceLevel(level);
+            levels.push(TRACE_LEVEL.load(Ordering::Relaxed));
+        }
+        assert_eq!(levels, vec![0, 1, 2, 3, 3, 3]);
+
+        SetTraceCallback(Some(collect_trace));
+        SetTraceLevel(TRACE_STATEMENTS);
+        trace_at(TRACE_ERRORS, "level test: error");
+        trace_at(TRACE_STATEMENTS, "level test: SELECT 1");
+        trace("level test: 1 rows in 0ms");
+        SetTraceLevel(TRACE_OFF);
+        trace_at(TRACE_ERRORS, "level test: after off");
+        SetTraceCallback(None);
+        let traced: Vec<String> = TRACED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|msg| msg.starts_with("level test:"))
+            .cloned()
+            .collect();
+        assert_eq!(traced, vec!["level test: error", "level test: SELECT 1"]);
+    }
+
+    #[test]
+    fn pooling_false_bypasses_the_pool() {
+        assert!(options("server=db").pooling);
+        for value in ["false", "False", "no", "0"] {
+            assert!(!options(&format!("server=db;Pooling={}", value)).pooling, "{}", value);
+        }
+
+        let runtime = get_runtime();
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let conn_str = format!(
+            "server=127.0.0.1,{};user id=sa;password=x;pooling=false;isolation setup=skip",
+            listener.local_addr().unwrap().port()
+        );
+        runtime.spawn(fake_tds_server(listener, done_token()));
+        // An idle client that a pooled checkout for this string would hand out
+        let idle = PooledClient {
+            client: connect_to_fake_server(done_token()),
+            idle_since: Instant::now(),
+            reset: true,
+        };
+        lock_or_recover(get_pool()).insert(conn_str.clone(), vec![idle]);
+
+        let mut unpooled = options(&conn_str);
+        // The fake server does not speak TLS, which encrypt=false still uses for the login
+        unpooled.config.encryption(EncryptionLevel::NotSupported);
+        let checkout = checkout_connection(&conn_str, unpooled);
+        let bucket = lock_or_recover(get_pool()).remove(&conn_str);
+        assert!(checkout.is_ok(), "{:?}", checkout.err());
+        assert_eq!(bucket.map(|idle| idle.len()), Some(1));
+    }
+}
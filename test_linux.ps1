@@ -50,6 +50,9 @@ public class SqlThinkRS {
     [DllImport("$SoFullPath", CallingConvention = CallingConvention.Cdecl)]
     public static extern void DisableTrace();
 
+    [DllImport("$SoFullPath", CallingConvention = CallingConvention.Cdecl)]
+    public static extern void SetDecimalAsString([MarshalAs(UnmanagedType.I1)] bool enabled);
+
     public static string PtrToStringAndFree(IntPtr ptr) {
         if (ptr == IntPtr.Zero) {
             return null;
@@ -87,7 +90,7 @@ function Invoke-SqlThinkRS {
 
 try {
     # Step 1: Connect to database
-    Write-Host "[1/10] Connecting to SQL Server..." -ForegroundColor Yellow
+    Write-Host "[1/11] Connecting to SQL Server..." -ForegroundColor Yellow
     $connString = "server=$ServerName;user id=$UserId;password=$Password;database=$Database;trust server certificate=true"
     
     $errorPtr = [SqlThinkRS]::ConnectDb($connString)
@@ -100,7 +103,7 @@ try {
     Write-Host ""
 
     # Step 2: Create table (should auto-inject primary key)
-    Write-Host "[2/10] Creating test table..." -ForegroundColor Yellow
+    Write-Host "[2/11] Creating test table..." -ForegroundColor Yellow
 
     # Clean up any leftover table from a previous failed run
     Invoke-SqlThinkRS -Sql "IF OBJECT_ID('TestRustTable','U') IS NOT NULL DROP TABLE TestRustTable"
@@ -118,7 +121,7 @@ CREATE TABLE TestRustTable (
     Write-Host ""
 
     # Step 3: Insert test data
-    Write-Host "[3/10] Inserting test data..." -ForegroundColor Yellow
+    Write-Host "[3/11] Inserting test data..." -ForegroundColor Yellow
     $insertSql1 = "INSERT INTO TestRustTable (Name, Age, Active) VALUES ('Alice', 30, 1)"
     $insertSql2 = "INSERT INTO TestRustTable (Name, Age, Active) VALUES ('Bob', 25, 0)"
     $insertSql3 = "INSERT INTO TestRustTable (Name, Age, Active) VALUES ('Charlie', 35, 1)"
@@ -130,7 +133,7 @@ CREATE TABLE TestRustTable (
     Write-Host ""
 
     # Step 4: Select data (should auto-add SNAPSHOT isolation)
-    Write-Host "[4/10] Querying data..." -ForegroundColor Yellow
+    Write-Host "[4/11] Querying data..." -ForegroundColor Yellow
     $selectSql = "SELECT * FROM TestRustTable"
     
     $jsonResult = Invoke-SqlThinkRS -Sql $selectSql
@@ -143,7 +146,7 @@ CREATE TABLE TestRustTable (
     Write-Host ""
 
     # Step 5: Test filtered query
-    Write-Host "[5/10] Testing filtered query..." -ForegroundColor Yellow
+    Write-Host "[5/11] Testing filtered query..." -ForegroundColor Yellow
     $filterSql = "SELECT Name, Age FROM TestRustTable WHERE Active = 1"
     
     $jsonResult = Invoke-SqlThinkRS -Sql $filterSql
@@ -154,7 +157,7 @@ CREATE TABLE TestRustTable (
     Write-Host ""
 
     # Step 6: Test DECLARE with SELECT
-    Write-Host "[6/10] Testing DECLARE with SELECT..." -ForegroundColor Yellow
+    Write-Host "[6/11] Testing DECLARE with SELECT..." -ForegroundColor Yellow
     $declareSql = @"
 DECLARE @MinAge INT = 28;
 SELECT Name, Age FROM TestRustTable WHERE Age >= @MinAge ORDER BY Age
@@ -168,7 +171,7 @@ SELECT Name, Age FROM TestRustTable WHERE Age >= @MinAge ORDER BY Age
     Write-Host ""
 
     # Step 7: Test DECLARE with multiple variables and computation
-    Write-Host "[7/10] Testing DECLARE with multiple variables..." -ForegroundColor Yellow
+    Write-Host "[7/11] Testing DECLARE with multiple variables..." -ForegroundColor Yellow
     $declareMultiSql = @"
 DECLARE @NameFilter NVARCHAR(50) = 'Alice';
 DECLARE @AgeBonus INT = 10;
@@ -183,7 +186,7 @@ SELECT Name, Age, Age + @AgeBonus AS AgeWithBonus FROM TestRustTable WHERE Name
     Write-Host ""
 
     # Step 8: Test WITH (Common Table Expression)
-    Write-Host "[8/10] Testing WITH (CTE) query..." -ForegroundColor Yellow
+    Write-Host "[8/11] Testing WITH (CTE) query..." -ForegroundColor Yellow
     $cteSql = @"
 WITH ActiveUsers AS (
     SELECT Name, Age FROM TestRustTable WHERE Active = 1
@@ -198,16 +201,30 @@ SELECT Name, Age FROM ActiveUsers ORDER BY Name
     $cteData | Format-Table -AutoSize
     Write-Host ""
 
-    # Step 9: Update data
-    Write-Host "[9/10] Updating data..." -ForegroundColor Yellow
+    # Step 9: DECIMAL(38,x) round trip (exact strings, as ConvertFrom-Json reads numbers as [double])
+    Write-Host "[9/11] Testing DECIMAL(38,10) precision..." -ForegroundColor Yellow
+    [SqlThinkRS]::SetDecimalAsString($true)
+    $expected = "1234567890123456789012345678.9012345678"
+    $decimalSql = "SELECT CAST('$expected' AS DECIMAL(38,10)) AS Amount"
+
+    $jsonResult = Invoke-SqlThinkRS -Sql $decimalSql
+    $decimalData = $jsonResult | ConvertFrom-Json
+    if ($decimalData[0].Amount -ne $expected) {
+        throw "DECIMAL(38,10) came back as $($decimalData[0].Amount), expected $expected"
+    }
+    Write-Host "   [OK] $expected round-tripped exactly" -ForegroundColor Green
+    Write-Host ""
+
+    # Step 10: Update data
+    Write-Host "[10/11] Updating data..." -ForegroundColor Yellow
     $updateSql = "UPDATE TestRustTable SET Age = 31 WHERE Name = 'Alice'"
     
     Invoke-SqlThinkRS -Sql $updateSql
     Write-Host "   [OK] Updated successfully" -ForegroundColor Green
     Write-Host ""
 
-    # Step 10: Cleanup - Drop table
-    Write-Host "[10/10] Cleaning up..." -ForegroundColor Yellow
+    # Step 11: Cleanup - Drop table
+    Write-Host "[11/11] Cleaning up..." -ForegroundColor Yellow
     $dropSql = "DROP TABLE TestRustTable"
     
     Invoke-SqlThinkRS -Sql $dropSql